@@ -5,3 +5,4 @@ docker build --file Dockerfile.alpine-custom-rust -t elastio:alpine-custom-rust
 docker build --file Dockerfile.alpine-official-rust -t elastio:alpine-official-rust .
 docker build --file Dockerfile.debian-rust -t elastio:debian-rust .
 docker build --file Dockerfile.debian-static-libs -t elastio:debian-static-libs .
+docker build --file Dockerfile.debian-aarch64-musl -t elastio:debian-aarch64-musl .
@@ -0,0 +1,8 @@
+fn main() {
+    println!("cargo:rerun-if-changed=src/greeting.cpp");
+
+    cc::Build::new()
+        .cpp(true)
+        .file("src/greeting.cpp")
+        .compile("greeting");
+}
@@ -0,0 +1,7 @@
+#include <cstdio>
+#include <string>
+
+extern "C" void print_greeting(const char* name) {
+    std::string greeting = std::string("Hello, ") + name + " from C++!";
+    std::puts(greeting.c_str());
+}
@@ -0,0 +1,14 @@
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+extern "C" {
+    fn print_greeting(name: *const c_char);
+}
+
+fn main() {
+    let name = CString::new("world").unwrap();
+
+    unsafe {
+        print_greeting(name.as_ptr());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::exec::ExecOutput;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// What a test crate's binary is expected to do when it runs, from `[package.metadata.test-crate.run]`.
+///
+/// Without these the run step only checks that `cargo run` exits with 0.  The regexes are matched against the output
+/// of `cargo run`, so stderr also has cargo's own `Finished` and `Running` lines
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RunAssertions {
+    /// Args passed to the binary
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Text fed to the binary's stdin
+    #[serde(default)]
+    pub stdin: Option<String>,
+
+    /// The exit code the binary must exit with.  If this is set, a different exit code means the binary misbehaves
+    /// rather than failed to run
+    #[serde(default)]
+    pub exit_code: Option<i64>,
+
+    /// Regexes which must each match somewhere in stdout
+    #[serde(default)]
+    pub stdout_matches: Vec<String>,
+
+    /// Regexes which must not match anywhere in stdout
+    #[serde(default)]
+    pub stdout_excludes: Vec<String>,
+
+    /// Regexes which must each match somewhere in stderr
+    #[serde(default)]
+    pub stderr_matches: Vec<String>,
+
+    /// Regexes which must not match anywhere in stderr
+    #[serde(default)]
+    pub stderr_excludes: Vec<String>,
+}
+
+impl RunAssertions {
+    /// Make sure all of the regexes are valid, so a typo is reported when the crate is loaded rather than when it
+    /// runs
+    pub fn validate(&self) -> Result<()> {
+        for pattern in self
+            .stdout_matches
+            .iter()
+            .chain(&self.stdout_excludes)
+            .chain(&self.stderr_matches)
+            .chain(&self.stderr_excludes)
+        {
+            compile(pattern)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the output of the run step against these assertions, returning a description of each one that failed
+    pub fn check(&self, output: &ExecOutput) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        if let Some(exit_code) = self.exit_code {
+            if output.exit_code != exit_code {
+                problems.push(format!(
+                    "Exited with code {} instead of {}",
+                    output.exit_code, exit_code
+                ));
+            }
+        }
+
+        let streams = [
+            (
+                "stdout",
+                &output.stdout,
+                &self.stdout_matches,
+                &self.stdout_excludes,
+            ),
+            (
+                "stderr",
+                &output.stderr,
+                &self.stderr_matches,
+                &self.stderr_excludes,
+            ),
+        ];
+        for (stream, text, matches, excludes) in streams {
+            for pattern in matches {
+                if !compile(pattern)?.is_match(text) {
+                    problems.push(format!("{} doesn't match `{}`", stream, pattern));
+                }
+            }
+            for pattern in excludes {
+                if compile(pattern)?.is_match(text) {
+                    problems.push(format!("{} matches `{}`", stream, pattern));
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).wrap_err_with(|| eyre!("Invalid regex `{}`", pattern))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn each_failed_assertion_is_a_problem() {
+        let assertions = RunAssertions {
+            exit_code: Some(0),
+            stdout_matches: vec![r"Found block device: /dev/\w+".to_string()],
+            stdout_excludes: vec!["panicked".to_string()],
+            stderr_excludes: vec!["(?i)error".to_string()],
+            ..Default::default()
+        };
+        let output = ExecOutput {
+            exit_code: 0,
+            stdout: "Hello, world!\n".to_string(),
+            stderr: "Running `target/debug/with-block-utils`\nERROR loading certs\n".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                r"stdout doesn't match `Found block device: /dev/\w+`".to_string(),
+                "stderr matches `(?i)error`".to_string(),
+            ],
+            assertions.check(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        let assertions = RunAssertions {
+            stderr_matches: vec!["(unclosed".to_string()],
+            ..Default::default()
+        };
+
+        assert!(assertions.validate().is_err());
+    }
+}
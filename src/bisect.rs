@@ -0,0 +1,772 @@
+use crate::environments::Platform;
+use crate::link_errors::LinkError;
+use crate::progress::{CellId, CellState, Verdict};
+use crate::tests::{self, RunContext, TestCrate, TestResult};
+use async_trait::async_trait;
+use cargo_metadata::{DependencyKind, Metadata, MetadataCommand, Package, PackageId};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// A dependency of a reduced crate, pinned to the version and features it resolved to in the original crate
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Dep {
+    /// The name the dependency is known by in code, which is also its key in `[dependencies]`
+    pub name: String,
+
+    /// The inline table for the dependency in `[dependencies]`
+    spec: String,
+}
+
+impl Dep {
+    fn new(name: &str, package: &Package, features: &[String]) -> Self {
+        // The resolved features include `default` if it was enabled, so they're the complete list
+        let mut spec = format!(
+            "package = {}, default-features = false, features = {}",
+            tests::toml_string(&package.name),
+            tests::toml_array(features)
+        );
+
+        match &package.source {
+            // The directories of path dependencies are mounted at the same path in a container
+            None => {
+                let dir = package.manifest_path.parent().unwrap();
+                spec.push_str(&format!(", path = {}", tests::toml_string(dir.as_str())));
+            }
+            Some(source) if source.repr.starts_with("git+") => {
+                // Like `git+https://github.com/org/repo?branch=main#<commit>`
+                let repo = &source.repr["git+".len()..];
+                let (url, commit) = repo.split_once('#').unwrap_or((repo, ""));
+                let url = url.split('?').next().unwrap();
+                spec.push_str(&format!(
+                    ", git = {}, rev = {}",
+                    tests::toml_string(url),
+                    tests::toml_string(commit)
+                ));
+            }
+            Some(_) => spec.push_str(&format!(", version = \"={}\"", package.version)),
+        }
+
+        Self {
+            name: name.to_string(),
+            spec,
+        }
+    }
+}
+
+/// How a reduced crate went wrong, along with the evidence for it, so that a different problem which happens to go
+/// wrong in the same step isn't mistaken for the one being bisected
+#[derive(Clone, Debug, PartialEq)]
+struct Symptom {
+    /// How it went wrong, like "isn't static"
+    description: String,
+
+    /// What it went wrong with: the sonames a non-static binary needs, the root causes of a link failure, or the
+    /// errors in the log of a step that failed
+    evidence: BTreeSet<String>,
+}
+
+impl Symptom {
+    /// The symptom of a test result, or `None` if the binary is static.  `crate_name` is left out of the evidence,
+    /// since every reduced crate has its own name
+    fn of(result: &TestResult, crate_name: &str) -> Option<Self> {
+        let (description, evidence) = match result {
+            TestResult::NonStaticBinary { deps } => (
+                "isn't static".to_string(),
+                deps.iter().map(|dep| dep.soname.clone()).collect(),
+            ),
+            TestResult::LinkFailed { errors, .. } => {
+                ("fails to link".to_string(), link_evidence(errors))
+            }
+            TestResult::Failed { step, log, .. } => (
+                format!("fails in the {} step", step),
+                log_errors(log, crate_name),
+            ),
+            TestResult::TimedOut { step, .. } => {
+                (format!("times out in the {} step", step), BTreeSet::new())
+            }
+            TestResult::MissingRequirements { missing } => (
+                "can't be built".to_string(),
+                missing.iter().cloned().collect(),
+            ),
+            TestResult::StaticBinary
+            | TestResult::StaticWithCaveats { .. }
+            | TestResult::Misbehaves { .. } => return None,
+        };
+
+        Some(Self {
+            description,
+            evidence,
+        })
+    }
+
+    /// Whether this is the same problem as `original`.  Fewer dependencies can take evidence away, like a shared
+    /// library that only one of them needed, so this has to go wrong the same way with at least all of the original's
+    /// evidence
+    fn matches(&self, original: &Symptom) -> bool {
+        self.description == original.description && self.evidence.is_superset(&original.evidence)
+    }
+}
+
+impl fmt::Display for Symptom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN: usize = 5;
+
+        f.write_str(&self.description)?;
+        if !self.evidence.is_empty() {
+            let shown = self
+                .evidence
+                .iter()
+                .take(SHOWN)
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            write!(f, " ({}", shown.join("; "))?;
+            if self.evidence.len() > SHOWN {
+                write!(f, "; and {} more", self.evidence.len() - SHOWN)?;
+            }
+            f.write_str(")")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Each root cause of a link failure on its own, so a reduced crate with only some of the original's culprits has only
+/// some of the evidence.  Which archive defines an undefined symbol isn't part of it, since that's looked up afterwards
+fn link_evidence(errors: &[LinkError]) -> BTreeSet<String> {
+    errors
+        .iter()
+        .flat_map(|error| match error {
+            LinkError::UndefinedSymbols { symbols, .. } => symbols
+                .iter()
+                .map(|symbol| format!("undefined symbol `{}`", symbol))
+                .collect(),
+            LinkError::MissingLibrary { lib } => vec![format!("cannot find -l{}", lib)],
+            LinkError::NeedsPic {
+                relocation, symbol, ..
+            } => vec![format!("{} relocation against `{}`", relocation, symbol)],
+            LinkError::MultipleDefinition { symbol, .. } => {
+                vec![format!("multiple definition of `{}`", symbol)]
+            }
+        })
+        .collect()
+}
+
+/// The errors in a step's log, like "error: failed to run custom build command for `openssl-sys v0.9.80`".  How many
+/// errors there were and anything about the crate itself is left out, since those change from one reduced crate to the
+/// next
+fn log_errors(log: &Path, crate_name: &str) -> BTreeSet<String> {
+    let log = std::fs::read_to_string(log).unwrap_or_default();
+
+    log.lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("error:") || line.starts_with("error["))
+        .filter(|line| !line.contains(crate_name) && !line.starts_with("error: aborting due to"))
+        .map(|line| match line.find(" due to ") {
+            Some(end) => line[..end].to_string(),
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+/// Something that can tell whether a set of dependencies reproduces the problem being bisected
+#[async_trait]
+pub(crate) trait Reproducer {
+    async fn reproduces(&mut self, deps: &[Dep]) -> Result<bool>;
+}
+
+/// What bisecting a crate found
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Bisection {
+    /// How a crate with all of the original crate's direct dependencies went wrong, if it did
+    pub symptom: Option<String>,
+
+    /// The smallest set of direct dependencies which still goes wrong the same way
+    pub minimal: Vec<String>,
+
+    /// The `-sys` crates under the minimal set which go wrong the same way on their own, as `name vX.Y.Z`
+    pub sys_crates: Vec<String>,
+}
+
+/// Find the direct dependencies of the crate at `crate_path` that make its binary go wrong in `platform`, by building
+/// reduced crates which depend on subsets of them.
+///
+/// Each reduced crate is a test crate like any other, and goes through the same pipeline.  A reduced crate only
+/// references its dependencies with `extern crate`, so it's good for finding what breaks the build or brings in a
+/// shared library through a `-sys` crate's link flags; a library that's only linked because of code that uses it may
+/// be dropped by the linker
+pub(crate) async fn bisect(
+    ctx: &RunContext<'_>,
+    platform: Platform<'_>,
+    crate_path: &Path,
+) -> Result<Bisection> {
+    let metadata = MetadataCommand::new()
+        .manifest_path(crate_path.join("Cargo.toml"))
+        .other_options(tests::filter_platform())
+        .exec()
+        .wrap_err_with(|| eyre!("Error getting crate metadata for {}", crate_path.display()))?;
+    let root = metadata
+        .root_package()
+        .ok_or_else(|| eyre!("{} is not a crate", crate_path.display()))?;
+
+    let work_dir = ctx.cache_dir.join("bisect").join(&root.name);
+    if work_dir.exists() {
+        std::fs::remove_dir_all(&work_dir)?;
+    }
+    std::fs::create_dir_all(&work_dir)?;
+
+    let lock_file = metadata
+        .workspace_root
+        .join("Cargo.lock")
+        .into_std_path_buf();
+    let mut bisector = Bisector {
+        ctx,
+        platform,
+        work_dir,
+        crate_name: root.name.clone(),
+        env_vars: tests::package_env_vars(root, platform.env.name())?,
+        path_dep_dirs: path_dep_dirs(&metadata, &root.id),
+        lock_file: Some(lock_file).filter(|path| path.exists()),
+        symptom: None,
+        tries: 0,
+    };
+
+    let deps = direct_deps(&metadata, &root.id);
+    let symptom = match bisector.symptom(&deps).await? {
+        Some(symptom) => symptom,
+        None => {
+            return Ok(Bisection {
+                symptom: None,
+                minimal: Vec::new(),
+                sys_crates: Vec::new(),
+            })
+        }
+    };
+    info!(%symptom, "Bisecting the direct dependencies");
+
+    let description = symptom.to_string();
+    bisector.symptom = Some(symptom);
+    let minimal = minimize(&mut bisector, deps).await?;
+
+    // A `-sys` crate which goes wrong on its own is the most likely culprit
+    let mut sys_crates = Vec::new();
+    for (package, features) in sys_crates_under(&metadata, &root.id, &minimal) {
+        let lib_name = package
+            .targets
+            .iter()
+            .find(|target| target.kind.iter().any(|kind| kind.contains("lib")))
+            .map(|target| target.name.replace('-', "_"));
+        if let Some(lib_name) = lib_name {
+            let dep = Dep::new(&lib_name, package, &features);
+            if bisector.reproduces(&[dep]).await? {
+                sys_crates.push(format!("{} v{}", package.name, package.version));
+            }
+        }
+    }
+
+    Ok(Bisection {
+        symptom: Some(description),
+        minimal: minimal.into_iter().map(|dep| dep.name).collect(),
+        sys_crates,
+    })
+}
+
+/// Reduce `deps` to a set which still reproduces the problem, and from which no dependency can be removed without it
+/// going away.
+///
+/// First the set is halved for as long as either half reproduces the problem on its own, which is quick when there's
+/// a single culprit.  Then each remaining dependency is dropped in turn, and stays dropped if the problem remains
+pub(crate) async fn minimize(
+    reproducer: &mut dyn Reproducer,
+    mut deps: Vec<Dep>,
+) -> Result<Vec<Dep>> {
+    while deps.len() > 1 {
+        let second = deps.split_off(deps.len() / 2);
+        if reproducer.reproduces(&deps).await? {
+            continue;
+        }
+        if reproducer.reproduces(&second).await? {
+            deps = second;
+            continue;
+        }
+
+        deps.extend(second);
+        break;
+    }
+
+    let mut index = 0;
+    while deps.len() > 1 && index < deps.len() {
+        let mut without = deps.clone();
+        without.remove(index);
+        if reproducer.reproduces(&without).await? {
+            deps = without;
+        } else {
+            index += 1;
+        }
+    }
+
+    Ok(deps)
+}
+
+/// Builds reduced crates in the platform, with the pipeline every test goes through
+struct Bisector<'a> {
+    ctx: &'a RunContext<'a>,
+    platform: Platform<'a>,
+
+    /// Where the reduced crates are generated
+    work_dir: PathBuf,
+
+    /// The name of the original crate
+    crate_name: String,
+
+    /// The env vars from the original crate's `[package.metadata.test-crate]`, if it has any
+    env_vars: Vec<String>,
+
+    /// The directories of the original crate's path dependencies, which the reduced crates refer to on the host
+    path_dep_dirs: Vec<PathBuf>,
+
+    /// The original crate's lock file, so the reduced crates get the same versions of everything
+    lock_file: Option<PathBuf>,
+
+    /// The symptom being bisected, once the original set of dependencies has shown it
+    symptom: Option<Symptom>,
+
+    tries: usize,
+}
+
+impl<'a> Bisector<'a> {
+    /// Build and analyze a reduced crate with `deps`, and describe how it went wrong, if it did
+    async fn symptom(&mut self, deps: &[Dep]) -> Result<Option<Symptom>> {
+        self.tries += 1;
+        let name = format!("{}-bisect-{}", self.crate_name, self.tries);
+        let path = self.write_crate(&name, deps)?;
+
+        let mut test = TestCrate::load(path)?;
+        test.mount_host_dirs(self.path_dep_dirs.clone());
+        let cell = CellId::new(test.name(), &self.platform.name());
+        let names = deps.iter().map(|dep| dep.name.as_str()).collect::<Vec<_>>();
+        info!(test = test.name(), deps = ?names, "Trying a reduced crate");
+
+        self.ctx.progress.state(&cell, CellState::Queued);
+        let result = test.run_test(self.ctx, self.platform).await;
+        self.ctx.log.finish_cell(&cell, &result);
+        self.ctx
+            .progress
+            .state(&cell, CellState::Done(Verdict::of(&result)));
+
+        Ok(Symptom::of(&result?, &name))
+    }
+
+    /// Generate a crate named `name` which depends on `deps` and nothing else
+    fn write_crate(&self, name: &str, deps: &[Dep]) -> Result<PathBuf> {
+        let path = self.work_dir.join(name);
+        std::fs::create_dir_all(path.join("src"))?;
+
+        let mut manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+             [package.metadata.test-crate]\nenv = {}\n\n\
+             # Not part of any workspace the work dir might be in\n[workspace]\n\n\
+             [dependencies]\n",
+            name,
+            tests::toml_array(&self.env_vars)
+        );
+        let mut main = String::new();
+        for dep in deps {
+            manifest.push_str(&format!("{} = {{ {} }}\n", dep.name, dep.spec));
+            main.push_str(&format!("extern crate {} as _;\n", dep.name));
+        }
+        main.push_str("\nfn main() {}\n");
+
+        std::fs::write(path.join("Cargo.toml"), manifest)?;
+        std::fs::write(path.join("src/main.rs"), main)?;
+        if let Some(lock_file) = &self.lock_file {
+            std::fs::copy(lock_file, path.join("Cargo.lock"))?;
+        }
+
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl<'a> Reproducer for Bisector<'a> {
+    async fn reproduces(&mut self, deps: &[Dep]) -> Result<bool> {
+        let symptom = self.symptom(deps).await?;
+
+        Ok(match (symptom, &self.symptom) {
+            (Some(symptom), Some(original)) => symptom.matches(original),
+            _ => false,
+        })
+    }
+}
+
+/// The directories of every path dependency in the graph, besides the root package.  A path dependency in the original
+/// crate's workspace can refer to the workspace's manifest or to other members by relative path, so the whole workspace
+/// is included instead
+fn path_dep_dirs(metadata: &Metadata, root: &PackageId) -> Vec<PathBuf> {
+    metadata
+        .packages
+        .iter()
+        .filter(|package| package.source.is_none() && &package.id != root)
+        .map(|package| {
+            if package.manifest_path.starts_with(&metadata.workspace_root) {
+                metadata.workspace_root.clone().into_std_path_buf()
+            } else {
+                package
+                    .manifest_path
+                    .parent()
+                    .unwrap()
+                    .as_std_path()
+                    .to_owned()
+            }
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// The normal dependencies of a package, with the features they resolved to
+fn direct_deps(metadata: &Metadata, id: &PackageId) -> Vec<Dep> {
+    resolved_deps(metadata, id)
+        .into_iter()
+        .map(|(name, package, features)| Dep::new(&name, package, &features))
+        .collect()
+}
+
+/// Each normal dependency of a package in the resolved graph, as the name it's known by, the package, and its
+/// features
+fn resolved_deps<'a>(
+    metadata: &'a Metadata,
+    id: &PackageId,
+) -> Vec<(String, &'a Package, Vec<String>)> {
+    let node = metadata
+        .resolve
+        .iter()
+        .flat_map(|resolve| &resolve.nodes)
+        .find(|node| &node.id == id);
+
+    node.into_iter()
+        .flat_map(|node| &node.deps)
+        .filter(|dep| {
+            dep.dep_kinds.is_empty()
+                || dep
+                    .dep_kinds
+                    .iter()
+                    .any(|info| info.kind == DependencyKind::Normal)
+        })
+        .filter_map(|dep| {
+            let package = metadata.packages.iter().find(|p| p.id == dep.pkg)?;
+            let features = metadata
+                .resolve
+                .iter()
+                .flat_map(|resolve| &resolve.nodes)
+                .find(|node| node.id == dep.pkg)
+                .map(|node| node.features.clone())
+                .unwrap_or_default();
+
+            Some((dep.name.clone(), package, features))
+        })
+        .collect()
+}
+
+/// The `-sys` crates, and anything else which links a native library, in the graph under the dependencies of the
+/// root package that are in `deps`
+fn sys_crates_under<'a>(
+    metadata: &'a Metadata,
+    root: &PackageId,
+    deps: &[Dep],
+) -> Vec<(&'a Package, Vec<String>)> {
+    let mut seen = BTreeSet::new();
+    let mut queue = resolved_deps(metadata, root)
+        .into_iter()
+        .filter(|(name, _, _)| deps.iter().any(|dep| &dep.name == name))
+        .collect::<VecDeque<_>>();
+
+    let mut sys_crates = Vec::new();
+    while let Some((_, package, features)) = queue.pop_front() {
+        if !seen.insert(&package.id) {
+            continue;
+        }
+        if package.links.is_some() || package.name.ends_with("-sys") {
+            sys_crates.push((package, features));
+        }
+
+        queue.extend(resolved_deps(metadata, &package.id));
+    }
+
+    sys_crates
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_deps::DynamicDep;
+    use crate::environments::TargetKind;
+    use crate::fake_docker::FakeDaemon;
+    use crate::progress::{Cancel, RecordingProgress};
+    use crate::runlog::RunLog;
+    use crate::timeouts::{Step, Timeouts};
+    use std::collections::BTreeMap;
+
+    /// Reproduces the problem whenever all of the `culprits` are in the set, and remembers every set it was asked
+    /// about
+    struct FakeReproducer {
+        culprits: Vec<&'static str>,
+        tried: Vec<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Reproducer for FakeReproducer {
+        async fn reproduces(&mut self, deps: &[Dep]) -> Result<bool> {
+            let names = deps.iter().map(|dep| dep.name.clone()).collect::<Vec<_>>();
+            let reproduces = self
+                .culprits
+                .iter()
+                .all(|culprit| names.iter().any(|name| name == culprit));
+            self.tried.push(names);
+
+            Ok(reproduces)
+        }
+    }
+
+    fn deps(names: &[&str]) -> Vec<Dep> {
+        names
+            .iter()
+            .map(|name| Dep {
+                name: name.to_string(),
+                spec: String::new(),
+            })
+            .collect()
+    }
+
+    async fn minimal(culprits: Vec<&'static str>) -> (Vec<String>, usize) {
+        let mut reproducer = FakeReproducer {
+            culprits,
+            tried: Vec::new(),
+        };
+        let minimal = minimize(
+            &mut reproducer,
+            deps(&["anyhow", "openssl", "serde", "tokio", "udev", "zstd"]),
+        )
+        .await
+        .unwrap();
+
+        (
+            minimal.into_iter().map(|dep| dep.name).collect(),
+            reproducer.tried.len(),
+        )
+    }
+
+    #[tokio::test]
+    async fn single_culprit_is_found_by_halving() {
+        let (minimal, tries) = minimal(vec!["udev"]).await;
+
+        assert_eq!(vec!["udev"], minimal);
+        assert!(tries <= 5, "took {} tries", tries);
+    }
+
+    #[tokio::test]
+    async fn culprits_in_both_halves_are_all_kept() {
+        let (minimal, _) = minimal(vec!["openssl", "zstd"]).await;
+
+        assert_eq!(vec!["openssl", "zstd"], minimal);
+    }
+
+    /// The symptom of a build that failed with `output` in its log
+    fn build_failure(output: &str) -> Symptom {
+        let log = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(log.path(), output).unwrap();
+        let result = TestResult::Failed {
+            step: Step::Build,
+            message: "`cargo build` terminated with exit code 101".to_string(),
+            log: log.path().to_owned(),
+        };
+
+        Symptom::of(&result, "our-product-bisect-1").unwrap()
+    }
+
+    #[test]
+    fn unrelated_build_failure_is_not_a_reproduction() {
+        let original = build_failure(
+            "   Compiling openssl-sys v0.9.80
+             error: failed to run custom build command for `openssl-sys v0.9.80`
+             error: could not compile `our-product-bisect-1` due to previous error
+",
+        );
+        let unrelated = build_failure(
+            "   Compiling tokio v1.20.0
+             error[E0433]: failed to resolve: could not find `net` in `tokio`
+             error: could not compile `tokio` due to 2 previous errors
+",
+        );
+        let same = build_failure(
+            "error: failed to run custom build command for `openssl-sys v0.9.80`
+             error: aborting due to previous error
+",
+        );
+
+        assert_eq!(
+            "fails in the build step (error: failed to run custom build command for `openssl-sys v0.9.80`)",
+            original.to_string()
+        );
+        assert!(!unrelated.matches(&original));
+        assert!(same.matches(&original));
+    }
+
+    #[test]
+    fn non_static_binaries_must_need_the_same_libraries() {
+        let non_static = |sonames: &[&str]| {
+            let deps = sonames
+                .iter()
+                .map(|soname| DynamicDep {
+                    soname: soname.to_string(),
+                    ..Default::default()
+                })
+                .collect();
+            Symptom::of(&TestResult::NonStaticBinary { deps }, "bisect").unwrap()
+        };
+        let original = non_static(&["libc.so", "libudev.so.1"]);
+
+        assert!(non_static(&["libc.so", "libudev.so.1"]).matches(&original));
+        assert!(!non_static(&["libc.so", "libz.so.1"]).matches(&original));
+        assert!(!non_static(&["libc.so"]).matches(&original));
+    }
+
+    #[test]
+    fn link_failures_must_have_the_same_root_causes() {
+        let link_failed = |errors: Vec<LinkError>| {
+            let result = TestResult::LinkFailed {
+                errors,
+                log: PathBuf::new(),
+            };
+            Symptom::of(&result, "bisect").unwrap()
+        };
+        let missing = |lib: &str| LinkError::MissingLibrary {
+            lib: lib.to_string(),
+        };
+        let original = link_failed(vec![
+            missing("udev"),
+            LinkError::UndefinedSymbols {
+                archive: Some("/usr/lib/libz.a".to_string()),
+                symbols: vec!["deflate".to_string()],
+            },
+        ]);
+
+        assert!(link_failed(vec![
+            LinkError::UndefinedSymbols {
+                archive: None,
+                symbols: vec!["deflate".to_string(), "inflate".to_string()],
+            },
+            missing("udev"),
+        ])
+        .matches(&original));
+        assert!(!link_failed(vec![missing("ssl")]).matches(&original));
+        assert!(!link_failed(vec![missing("udev")]).matches(&original));
+    }
+
+    #[test]
+    fn deps_are_pinned_to_their_resolved_version() {
+        let metadata = MetadataCommand::new()
+            .manifest_path(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("crates/with-libsqlite/Cargo.toml"),
+            )
+            .other_options(tests::filter_platform())
+            .exec()
+            .unwrap();
+        let root = metadata.root_package().unwrap();
+
+        let deps = direct_deps(&metadata, &root.id);
+        let rusqlite = metadata
+            .packages
+            .iter()
+            .find(|package| package.name == "rusqlite")
+            .unwrap();
+
+        assert_eq!(
+            vec!["rusqlite"],
+            deps.iter().map(|dep| &dep.name).collect::<Vec<_>>()
+        );
+        assert!(deps[0]
+            .spec
+            .ends_with(&format!(", version = \"={}\"", rusqlite.version)));
+        assert_eq!(
+            vec!["libsqlite3-sys"],
+            sys_crates_under(&metadata, &root.id, &deps)
+                .into_iter()
+                .map(|(package, _)| package.name.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn path_deps_are_mounted_in_the_container() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = temp_dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            "our-product/Cargo.toml",
+            "[package]\nname = \"our-product\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+             [dependencies]\nsome-lib = { path = \"../some-lib\" }\n",
+        );
+        write("our-product/src/main.rs", "fn main() {}\n");
+        write(
+            "some-lib/Cargo.toml",
+            "[package]\nname = \"some-lib\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+        );
+        write("some-lib/src/lib.rs", "");
+        let lib_dir = temp_dir.path().join("some-lib");
+
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec(
+                &["find"],
+                0,
+                "target/x86_64-unknown-linux-musl/debug/our-product-bisect-1\n",
+            )
+            .on_exec(&["stat"], 0, "1234567\n")
+            .on_exec(
+                &["readelf"],
+                0,
+                "ELF Header:\n  Machine:                           Advanced Micro Devices X86-64\n",
+            );
+        let cache_dir = temp_dir.path().join("cache");
+        let log = RunLog::create(&temp_dir.path().join("logs")).unwrap();
+        let progress = RecordingProgress::default();
+        let ctx = RunContext {
+            daemon: Some(&daemon),
+            cache_dir: &cache_dir,
+            timeouts: &Timeouts::default(),
+            progress: &progress,
+            log: &log,
+            capabilities: &BTreeMap::new(),
+            cancel: &Cancel::default(),
+        };
+        let platform = Platform::find("debian-rust", TargetKind::Musl).unwrap();
+
+        let bisection = bisect(&ctx, platform, &temp_dir.path().join("our-product"))
+            .await
+            .unwrap();
+
+        assert_eq!(None, bisection.symptom);
+        let manifest = std::fs::read_to_string(
+            cache_dir.join("bisect/our-product/our-product-bisect-1/Cargo.toml"),
+        )
+        .unwrap();
+        assert!(manifest.contains(&format!(
+            "some_lib = {{ package = \"some-lib\", default-features = false, features = [], path = \"{}\" }}",
+            lib_dir.display()
+        )));
+        let calls = daemon.calls();
+        assert!(calls.launched[0]
+            .1
+            .volumes
+            .contains(&format!("{0}:{0}:ro", lib_dir.display())));
+    }
+}
@@ -1,19 +1,195 @@
+use crate::exec::{ExecOutput, ExecRequest, OutputCollector, OutputSink};
+use async_trait::async_trait;
 use color_eyre::{
-    eyre::Context,
     eyre::{eyre, WrapErr},
-    Report, Result,
+    Result,
 };
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use shiplift::{
-    builder::{ImageFilter, ImageListOptions},
-    rep::{ContainerCreateInfo, ExecDetails, Image},
-    tty::TtyChunk,
-    Container, ContainerOptions, Docker, Exec, ExecContainerOptions,
+    builder::ImageListOptions, rep::Image, tty::TtyChunk, Container, ContainerOptions, Docker,
+    Exec, ExecContainerOptions,
 };
-use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use tracing::*;
 
-pub(crate) async fn connect_docker() -> Result<Docker> {
+/// Everything needed to create a container for a test
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ContainerSpec {
+    /// Env vars to set in the container, each in the form `NAME=VALUE`
+    pub env: Vec<String>,
+
+    /// Volumes to mount in the container, each in the usual docker `host_path:container_path` format
+    pub volumes: Vec<String>,
+
+    /// The working directory in the container
+    pub working_dir: String,
+
+    /// Capabilities to add to the container, like `SYS_ADMIN`
+    pub capabilities: Vec<String>,
+
+    /// Run the container in privileged mode, with access to all of the host's devices
+    pub privileged: bool,
+}
+
+/// Access to the host beyond what a default, unprivileged container has.
+///
+/// Some test crates enumerate devices, and in a default container with a masked `/sys` and no `/run/udev` what
+/// they find doesn't reflect a real host
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ContainerAccess {
+    /// Host paths to mount in the container.  A plain path like `/sys` is mounted read-only at the same path;
+    /// anything with a `:` is used as is, in the usual docker `host_path:container_path[:mode]` format
+    #[serde(default)]
+    pub mounts: Vec<String>,
+
+    /// Capabilities to add to the container, like `SYS_ADMIN`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+
+    /// Run the container in privileged mode
+    #[serde(default)]
+    pub privileged: bool,
+}
+
+impl ContainerAccess {
+    /// The mounts as docker volumes
+    pub fn volumes(&self) -> Vec<String> {
+        self.mounts
+            .iter()
+            .map(|mount| {
+                if mount.contains(':') {
+                    mount.clone()
+                } else {
+                    format!("{}:{}:ro", mount, mount)
+                }
+            })
+            .collect()
+    }
+
+    /// A description of the extra access for reports, or `None` if this is just a default container
+    pub fn describe(&self) -> Option<String> {
+        let mut access = Vec::new();
+        if self.privileged {
+            access.push("privileged".to_string());
+        }
+        if !self.capabilities.is_empty() {
+            access.push(format!("capabilities {}", self.capabilities.join(", ")));
+        }
+        if !self.mounts.is_empty() {
+            access.push(format!("mounts {}", self.volumes().join(", ")));
+        }
+
+        if access.is_empty() {
+            None
+        } else {
+            Some(access.join("; "))
+        }
+    }
+}
+
+/// The operations the tests need from a container daemon.
+///
+/// In normal operation this is the local Docker daemon (see [`DockerDaemon`]), but keeping all container
+/// interaction behind this trait means the logic which drives the containers can be tested without Docker.
+///
+/// Containers are referred to by their ID, as returned by `launch_container`
+#[async_trait]
+pub(crate) trait ContainerDaemon: Send + Sync {
+    /// Find the ID of the image with the repo tag `elastio:$label`
+    async fn find_image_by_label(&self, label: &str) -> Result<String>;
+
+    /// Create and start a new container from an image, returning the container ID
+    async fn launch_container(&self, image_id: &str, spec: &ContainerSpec) -> Result<String>;
+
+    /// Run a command in a running container, passing its output to `output` as it runs.  If the request's timeout
+    /// expires, the whole container is killed
+    async fn exec(
+        &self,
+        container_id: &str,
+        request: &ExecRequest,
+        output: &dyn OutputSink,
+    ) -> Result<ExecOutput>;
+
+    /// Stop a running container
+    async fn stop_container(&self, container_id: &str) -> Result<()>;
+
+    /// Delete a stopped container
+    async fn delete_container(&self, container_id: &str) -> Result<()>;
+}
+
+/// The real [`ContainerDaemon`] implementation, which talks to the local Docker daemon
+pub(crate) struct DockerDaemon {
+    docker: Docker,
+}
+
+#[async_trait]
+impl ContainerDaemon for DockerDaemon {
+    async fn find_image_by_label(&self, label: &str) -> Result<String> {
+        get_image_by_label(&self.docker, label)
+            .await
+            .map(|image| image.id)
+    }
+
+    async fn launch_container(&self, image_id: &str, spec: &ContainerSpec) -> Result<String> {
+        let vols = spec.volumes.iter().map(|v| v.as_str()).collect();
+        let options = ContainerOptions::builder(image_id)
+            .tty(true)
+            .env(&spec.env)
+            .volumes(vols)
+            .auto_remove(false)
+            .working_dir(&spec.working_dir)
+            .capabilities(spec.capabilities.iter().map(|c| c.as_str()).collect())
+            .privileged(spec.privileged)
+            // .user("1000")
+            .build();
+        let container_info = self
+            .docker
+            .containers()
+            .create(&options)
+            .await
+            .wrap_err_with(|| eyre!("Error creating docker container from image {}", image_id))?;
+
+        debug!(container_id = %container_info.id, "Started container");
+
+        self.docker
+            .containers()
+            .get(&container_info.id)
+            .start()
+            .await
+            .wrap_err_with(|| eyre!("Error starting docker container from image {}", image_id))?;
+
+        Ok(container_info.id)
+    }
+
+    async fn exec(
+        &self,
+        container_id: &str,
+        request: &ExecRequest,
+        output: &dyn OutputSink,
+    ) -> Result<ExecOutput> {
+        let container = self.docker.containers().get(container_id);
+
+        exec_in_container(&self.docker, &container, request, output).await
+    }
+
+    async fn stop_container(&self, container_id: &str) -> Result<()> {
+        Ok(self
+            .docker
+            .containers()
+            .get(container_id)
+            .stop(None)
+            .await?)
+    }
+
+    async fn delete_container(&self, container_id: &str) -> Result<()> {
+        Ok(self.docker.containers().get(container_id).delete().await?)
+    }
+}
+
+pub(crate) async fn connect_docker() -> Result<DockerDaemon> {
     let docker = shiplift::Docker::new();
 
     // Make sure it's working
@@ -21,7 +197,7 @@ pub(crate) async fn connect_docker() -> Result<Docker> {
 
     debug!(?version, "Connected to Docker daemon");
 
-    Ok(docker)
+    Ok(DockerDaemon { docker })
 }
 
 pub(crate) async fn get_image_by_label(docker: &Docker, label: &str) -> Result<Image> {
@@ -29,16 +205,10 @@ pub(crate) async fn get_image_by_label(docker: &Docker, label: &str) -> Result<I
 
     let images = docker.images().list(&options).await?;
 
-    // Find an image that has a repo tag `elastio:$label`
-    let repo_tag = format!("elastio:{}", label);
-
-    if let Some(image) = images.into_iter().find(|image| {
-        image
-            .repo_tags
-            .as_ref()
-            .map(|tags| tags.iter().any(|tag| tag == &repo_tag))
-            .unwrap_or(false)
-    }) {
+    if let Some(image) = images
+        .into_iter()
+        .find(|image| has_label(image.repo_tags.as_deref(), label))
+    {
         debug!(label, image_id = %image.id, "Found image by label");
         Ok(image)
     } else {
@@ -46,61 +216,116 @@ pub(crate) async fn get_image_by_label(docker: &Docker, label: &str) -> Result<I
     }
 }
 
+/// Test if an image with the given repo tags is the image for a label; that is, if it has a repo tag `elastio:$label`
+fn has_label(repo_tags: Option<&[String]>, label: &str) -> bool {
+    let repo_tag = format!("elastio:{}", label);
+
+    repo_tags
+        .map(|tags| tags.iter().any(|tag| tag == &repo_tag))
+        .unwrap_or(false)
+}
+
+/// The script which `exec_in_container` uses to apply the stdin of a request.
+///
+/// It's run as `bash -c SCRIPT bash STDIN_PATH CMD...`, so the command's arguments are passed through as positional
+/// parameters and are never interpreted by the shell
+const EXEC_TRAMPOLINE: &str = r#"exec "${@:2}" < "$1""#;
+
+/// The full command line to exec for a request, wrapped in [`EXEC_TRAMPOLINE`]
+fn trampoline_cmd<'a>(request: &'a ExecRequest, stdin_path: &'a str) -> Vec<&'a str> {
+    let mut cmd = vec!["bash", "-c", EXEC_TRAMPOLINE, "bash", stdin_path];
+    cmd.extend(request.cmd.iter().map(|c| c.as_str()));
+
+    cmd
+}
+
+/// Used to give each stdin file copied into a container a unique name
+static STDIN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 /// Helper to run a command in a container.
 ///
-/// This command is always run with `bash -c` to ensure env vars are set up as expected.
+/// The stdout/stderr for this command are passed live to `sink`, and also captured separately and combined in the
+/// returned output.
 ///
-/// The stdout/stderr for this command are echoed live to this process' stdout/stderr for now,
-/// and also combined into a single string which is returned along with the results of the exec operation
-pub(crate) async fn exec_in_container<'docker, C, S>(
+/// If the request's timeout expires the container is killed, since that's the only way to be sure the command is dead,
+/// and the output so far is returned marked as timed out
+pub(crate) async fn exec_in_container<'docker>(
     docker: &'docker Docker,
     container: &Container<'docker>,
-    cmd: C,
-) -> Result<(ExecDetails, String)>
-where
-    C: AsRef<[S]>,
-    S: AsRef<str>,
-{
-    // Make the command a single argument to `bash -c`
-    let args = cmd
-        .as_ref()
-        .iter()
-        .map(|c| format!("\"{}\"", c.as_ref()))
-        .collect::<Vec<_>>()
-        .join(" ");
-
-    debug!(command = %args,
+    request: &ExecRequest,
+    sink: &dyn OutputSink,
+) -> Result<ExecOutput> {
+    debug!(command = %request.display_cmd(),
         "Running command in container");
 
+    // Docker's exec API can't feed stdin to the process, so stdin is copied into the container as a file and redirected
+    let stdin_path = match &request.stdin {
+        Some(stdin) => {
+            let path = format!(
+                "/tmp/exec-stdin-{}",
+                STDIN_COUNTER.fetch_add(1, Ordering::SeqCst)
+            );
+            container
+                .copy_file_into(&path, stdin)
+                .await
+                .wrap_err_with(|| "Error copying stdin into container")?;
+            path
+        }
+        None => "/dev/null".to_string(),
+    };
+
     let exec_options = ExecContainerOptions::builder()
-        .cmd(vec!["bash", "-c", &args])
+        .cmd(trampoline_cmd(request, &stdin_path))
         .attach_stderr(true)
         .attach_stdout(true)
         .build();
-    let exec = Exec::create(&docker, container.id(), &exec_options)
+    let started = Instant::now();
+    let exec = Exec::create(docker, container.id(), &exec_options)
         .await
         .wrap_err_with(|| "Error executing command in container")?;
     exec.inspect().await?;
 
-    // For simplicity, we'll bypass the logging framework for the test, and write the output verbatim to our
-    // stdout/stderr streams
     let mut stream = exec.start();
-    let mut stdout = io::stdout();
-    let mut stderr = io::stderr();
-    let mut output = Vec::new();
-    while let Some(item) = stream.next().await {
+    let mut output = OutputCollector::new(sink);
+    let deadline = request
+        .timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    loop {
+        let item = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(item) => item,
+                Err(_) => {
+                    // There's no way to kill just the exec'd process via the Docker API, so kill the whole container
+                    warn!(command = %request.display_cmd(),
+                        container_id = container.id(),
+                        "Command timed out; killing the container");
+                    if let Err(e) = container.kill(None).await {
+                        error!(
+                            container_id = container.id(),
+                            "Error killing container after timeout: {}", e
+                        );
+                    }
+
+                    return Ok(output.timed_out(started.elapsed()));
+                }
+            },
+            None => stream.next().await,
+        };
+        let item = match item {
+            Some(item) => item,
+            None => break,
+        };
+
         match item {
             Err(e) => {
                 error!("Docker exec error: {}", e);
                 return Err(e.into());
             }
             Ok(TtyChunk::StdOut(chunk)) => {
-                stdout.write_all(&chunk)?;
-                output.write_all(&chunk)?;
+                output.stdout(&chunk);
             }
             Ok(TtyChunk::StdErr(chunk)) => {
-                stderr.write_all(&chunk)?;
-                output.write_all(&chunk)?;
+                output.stderr(&chunk);
             }
             Ok(TtyChunk::StdIn(_)) => {
                 unreachable!()
@@ -117,5 +342,76 @@ where
         ));
     }
 
-    Ok((results, String::from_utf8_lossy(&output).to_string()))
+    let exit_code = results
+        .exit_code
+        .expect("Non-running process must have exit code");
+
+    Ok(output.finish(exit_code as i64, started.elapsed()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_label_matches_elastio_repo_tag() {
+        let tags = vec![
+            "elastio:debian-rust".to_string(),
+            "debian-static-libs:latest".to_string(),
+        ];
+
+        assert!(has_label(Some(&tags), "debian-rust"));
+        assert!(!has_label(Some(&tags), "debian-static-libs"));
+        assert!(!has_label(Some(&tags), "debian"));
+        assert!(!has_label(None, "debian-rust"));
+    }
+
+    #[test]
+    fn plain_mounts_are_read_only() {
+        let access = ContainerAccess {
+            mounts: vec!["/sys".to_string(), "/dev:/dev:rw".to_string()],
+            capabilities: vec!["SYS_ADMIN".to_string()],
+            privileged: false,
+        };
+
+        assert_eq!(
+            vec!["/sys:/sys:ro".to_string(), "/dev:/dev:rw".to_string()],
+            access.volumes()
+        );
+        assert_eq!(
+            Some("capabilities SYS_ADMIN; mounts /sys:/sys:ro, /dev:/dev:rw".to_string()),
+            access.describe()
+        );
+        assert_eq!(None, ContainerAccess::default().describe());
+    }
+
+    #[test]
+    fn trampoline_passes_args_verbatim() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let stdin = temp_dir.path().join("stdin");
+        std::fs::write(&stdin, "from stdin").unwrap();
+        let stdin = stdin.display().to_string();
+
+        let request = ExecRequest::new(vec![
+            "bash",
+            "-c",
+            r#"printf '%s|' "$@"; cat"#,
+            "bash",
+            "a \"quoted\" arg",
+            "$HOME",
+            "`id`",
+        ]);
+        let cmd = trampoline_cmd(&request, &stdin);
+
+        let output = std::process::Command::new(cmd[0])
+            .args(&cmd[1..])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            "a \"quoted\" arg|$HOME|`id`|from stdin",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
 }
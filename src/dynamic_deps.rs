@@ -0,0 +1,418 @@
+use crate::environments::{Environment, Target};
+use crate::libs::LibScan;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+/// The script that resolves each of its args after the first, a soname or the path of the dynamic loader, to the file
+/// in the environment, the distro package that owns it, and a static archive of the same library if there is one.
+///
+/// The first arg is the dirs to look in, separated by spaces, which depend on the target; see [`Target::lib_dirs`]
+const RESOLVE_SCRIPT: &str = r#"lib_dirs=$1
+shift
+owner() {
+    if command -v dpkg >/dev/null; then
+        # Packages list the file a symlink like libz.so.1 points to, by its exact path.  With a merged /usr the
+        # resolved path is under /usr while the package still lists it under /lib, so try it in the original dir too
+        real=$(readlink -f "$1")
+        for path in "$real" "${1%/*}/${real##*/}"; do
+            package=$(dpkg -S "$path" 2>/dev/null | head -n 1 | cut -d: -f1)
+            if [ -n "$package" ]; then echo "$package"; return; fi
+        done
+    elif command -v apk >/dev/null; then
+        apk info --who-owns "$1" 2>/dev/null | sed -n 's/.* is owned by //p'
+    fi
+}
+find_lib() {
+    for dir in $lib_dirs; do
+        if [ -e "$dir/$1" ]; then echo "$dir/$1"; return; fi
+    done
+}
+for dep in "$@"; do
+    echo "== $dep"
+    case "$dep" in
+        /*) path=$dep ;;
+        *) path=$(find_lib "$dep") ;;
+    esac
+    if [ -n "$path" ] && [ -e "$path" ]; then
+        echo "path $path"
+        echo "package $(owner "$path")"
+    fi
+    name=${dep##*/}
+    archive=$(find_lib "${name%%.so*}.a")
+    if [ -n "$archive" ]; then
+        echo "archive $archive"
+        echo "archive-package $(owner "$archive")"
+    fi
+done
+true"#;
+
+/// A shared library that a binary needs at runtime, resolved to where it comes from in the environment it was built in
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DynamicDep {
+    /// The soname the binary needs, like `libudev.so.1`, or the path of the dynamic loader it asks for
+    pub soname: String,
+
+    /// The file the soname resolves to in the environment, if it's there at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// The distro package that owns the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+
+    /// A static archive of the same library that the binary could have linked instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub static_alternative: Option<StaticAlternative>,
+}
+
+/// A static archive of a library, in the environment the binary was built in or in another one
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct StaticAlternative {
+    /// The environment with the archive
+    pub env: String,
+
+    pub path: String,
+
+    /// The distro package that owns the archive, if it's known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+}
+
+impl DynamicDep {
+    /// The command that resolves `deps`, which are [`ElfInfo::dynamic_deps`](crate::elf::ElfInfo::dynamic_deps) of a
+    /// binary built for `target`.  The deps are passed as args, so nothing in them is interpreted by the shell
+    pub fn resolve_command(target: &Target, deps: &[String]) -> Vec<String> {
+        resolve_in(&target.lib_dirs(), deps)
+    }
+
+    /// Match the output of [`DynamicDep::resolve_command`] up with `deps`, which were built in `env_name`.  A dep
+    /// that's not in the output is just its soname
+    pub fn parse(deps: &[String], env_name: &str, output: &str) -> Vec<Self> {
+        let mut resolved: Vec<DynamicDep> = deps
+            .iter()
+            .map(|soname| DynamicDep {
+                soname: soname.clone(),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut current = None;
+        for line in output.lines().map(str::trim) {
+            if let Some(soname) = line.strip_prefix("== ") {
+                current = resolved.iter().position(|dep| dep.soname == soname);
+                continue;
+            }
+
+            let dep = match current {
+                Some(index) => &mut resolved[index],
+                None => continue,
+            };
+            let (key, value) = match line.split_once(' ') {
+                Some((key, value)) if !value.trim().is_empty() => (key, value.trim().to_string()),
+                _ => continue,
+            };
+            match key {
+                "path" => dep.path = Some(value),
+                "package" => dep.package = Some(value),
+                "archive" => {
+                    dep.static_alternative = Some(StaticAlternative {
+                        env: env_name.to_string(),
+                        path: value,
+                        package: None,
+                    })
+                }
+                "archive-package" => {
+                    if let Some(alternative) = &mut dep.static_alternative {
+                        alternative.package = Some(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        resolved
+    }
+
+    /// The name the library is linked by, like `udev` for `libudev.so.1`
+    fn lib_name(&self) -> &str {
+        let file_name = self.soname.rsplit('/').next().unwrap_or(&self.soname);
+        let stem = file_name.split(".so").next().unwrap_or(file_name);
+
+        stem.strip_prefix("lib").unwrap_or(stem)
+    }
+}
+
+impl fmt::Display for DynamicDep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.soname)?;
+
+        let origin = self
+            .path
+            .iter()
+            .filter(|path| **path != self.soname)
+            .chain(&self.package)
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        if !origin.is_empty() {
+            write!(f, " ({})", origin.join(" from "))?;
+        }
+
+        match &self.static_alternative {
+            Some(alternative) => {
+                write!(f, "; {} has {}", alternative.env, alternative.path)?;
+                if let Some(package) = &alternative.package {
+                    write!(f, " from {}", package)?;
+                }
+
+                Ok(())
+            }
+            None => write!(f, "; no static archive found"),
+        }
+    }
+}
+
+/// The command that resolves `deps` by looking for them in `lib_dirs`
+fn resolve_in(lib_dirs: &[String], deps: &[String]) -> Vec<String> {
+    let mut cmd = vec![
+        "bash".to_string(),
+        "-c".to_string(),
+        RESOLVE_SCRIPT.to_string(),
+        "bash".to_string(),
+        lib_dirs.join(" "),
+    ];
+    cmd.extend(deps.iter().cloned());
+
+    cmd
+}
+
+/// For each of `deps` of a binary built for `triple` with no static archive in the environment it was built in, look
+/// for one in the scans of the other environments.
+///
+/// Only the lib dirs of an environment's targets for the same triple are looked in, in the order the linker would,
+/// so a glibc binary isn't pointed at an archive built for musl.  The first environment with one is suggested
+pub(crate) fn suggest_other_environments(
+    deps: &mut [DynamicDep],
+    triple: &str,
+    scans: &[(&Environment, LibScan)],
+) {
+    for dep in deps
+        .iter_mut()
+        .filter(|dep| dep.static_alternative.is_none())
+    {
+        let found = scans.iter().find_map(|(env, scan)| {
+            let archives = scan.archives_of(dep.lib_name());
+            env.targets()
+                .iter()
+                .filter(|target| target.triple() == triple)
+                .flat_map(|target| target.lib_dirs())
+                .find_map(|dir| {
+                    archives
+                        .iter()
+                        .find(|path| Path::new(path).parent() == Some(Path::new(&dir)))
+                })
+                .map(|path| (env.name(), path.to_string()))
+        });
+
+        if let Some((env_name, path)) = found {
+            dep.static_alternative = Some(StaticAlternative {
+                env: env_name.to_string(),
+                path,
+                package: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environments::{Platform, TargetKind};
+
+    #[test]
+    fn resolved_deps_are_parsed() {
+        let deps = vec![
+            "libudev.so.1".to_string(),
+            "libz.so.1".to_string(),
+            "/lib64/ld-linux-x86-64.so.2".to_string(),
+        ];
+        let output = "== libudev.so.1\n\
+                      path /lib/x86_64-linux-gnu/libudev.so.1\n\
+                      package libudev1\n\
+                      == libz.so.1\n\
+                      path /lib/x86_64-linux-gnu/libz.so.1\n\
+                      package zlib1g\n\
+                      archive /usr/lib/x86_64-linux-gnu/libz.a\n\
+                      archive-package zlib1g-dev\n\
+                      == /lib64/ld-linux-x86-64.so.2\n\
+                      path /lib64/ld-linux-x86-64.so.2\n\
+                      package \n";
+
+        let mut resolved = DynamicDep::parse(&deps, "debian-rust", output);
+
+        assert_eq!(
+            DynamicDep {
+                soname: "libz.so.1".to_string(),
+                path: Some("/lib/x86_64-linux-gnu/libz.so.1".to_string()),
+                package: Some("zlib1g".to_string()),
+                static_alternative: Some(StaticAlternative {
+                    env: "debian-rust".to_string(),
+                    path: "/usr/lib/x86_64-linux-gnu/libz.a".to_string(),
+                    package: Some("zlib1g-dev".to_string()),
+                }),
+            },
+            resolved[1]
+        );
+        assert_eq!(None, resolved[2].package);
+
+        let static_libs = Environment::from_name("debian-static-libs").unwrap();
+        let scan = LibScan {
+            archives: vec!["/opt/musl/lib/libudev.a".to_string()],
+            ..Default::default()
+        };
+        suggest_other_environments(
+            &mut resolved,
+            "x86_64-unknown-linux-gnu",
+            &[(static_libs, scan)],
+        );
+
+        assert_eq!(
+            vec![
+                "libudev.so.1 (/lib/x86_64-linux-gnu/libudev.so.1 from libudev1); no static archive found",
+                "libz.so.1 (/lib/x86_64-linux-gnu/libz.so.1 from zlib1g); debian-rust has \
+                 /usr/lib/x86_64-linux-gnu/libz.a from zlib1g-dev",
+                "/lib64/ld-linux-x86-64.so.2; no static archive found",
+            ],
+            resolved
+                .iter()
+                .map(|dep| dep.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn other_environments_are_only_searched_for_the_same_target() {
+        let static_libs = Environment::from_name("debian-static-libs").unwrap();
+        let scan = LibScan {
+            archives: vec![
+                "/usr/lib/x86_64-linux-gnu/libudev.a".to_string(),
+                "/opt/musl/lib/libudev.a".to_string(),
+            ],
+            ..Default::default()
+        };
+        let suggestion = |triple| {
+            let mut deps = vec![DynamicDep {
+                soname: "libudev.so.1".to_string(),
+                ..Default::default()
+            }];
+            suggest_other_environments(&mut deps, triple, &[(static_libs, scan.clone())]);
+            deps.remove(0)
+                .static_alternative
+                .map(|alternative| alternative.path)
+        };
+
+        assert_eq!(
+            Some("/usr/lib/x86_64-linux-gnu/libudev.a".to_string()),
+            suggestion("x86_64-unknown-linux-gnu")
+        );
+        assert_eq!(
+            Some("/opt/musl/lib/libudev.a".to_string()),
+            suggestion("x86_64-unknown-linux-musl")
+        );
+        assert_eq!(None, suggestion("aarch64-unknown-linux-musl"));
+    }
+
+    #[test]
+    fn resolve_script_runs() {
+        let target = Platform::find("host", TargetKind::GnuStatic)
+            .unwrap()
+            .target;
+        let cmd = DynamicDep::resolve_command(
+            target,
+            &[
+                "libc.so.6".to_string(),
+                "libnot-a-real-lib.so.1".to_string(),
+            ],
+        );
+
+        let output = std::process::Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .output()
+            .unwrap();
+
+        // Whatever the host has installed, every dep gets a section and the script succeeds
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("== libc.so.6\n"));
+        assert!(stdout.contains("== libnot-a-real-lib.so.1\n"));
+    }
+
+    #[test]
+    fn each_target_looks_in_its_own_lib_dirs() {
+        let lib_dirs = |env_name, kind| Platform::find(env_name, kind).unwrap().target.lib_dirs();
+
+        let gnu_static = lib_dirs("debian-static-libs", TargetKind::GnuStatic);
+        assert!(!gnu_static.contains(&"/opt/musl/lib".to_string()));
+        assert!(gnu_static.contains(&"/usr/lib/x86_64-linux-gnu".to_string()));
+
+        let musl = lib_dirs("debian-static-libs", TargetKind::Musl);
+        assert_eq!("/opt/musl/lib", musl[0]);
+
+        let aarch64 = lib_dirs("debian-aarch64-musl", TargetKind::Musl);
+        assert!(aarch64.iter().all(|dir| dir.contains("aarch64")));
+    }
+
+    #[test]
+    fn gnu_static_archive_is_not_found_in_the_musl_libs() {
+        // A root with a musl libz.a in `/opt/musl/lib` and glibc's in the multiarch dir
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["opt/musl/lib", "usr/lib/x86_64-linux-gnu"] {
+            std::fs::create_dir_all(root.path().join(dir)).unwrap();
+            std::fs::write(root.path().join(dir).join("libz.a"), "").unwrap();
+        }
+        std::fs::write(root.path().join("usr/lib/x86_64-linux-gnu/libz.so.1"), "").unwrap();
+
+        let resolve = |kind| {
+            let lib_dirs = Platform::find("debian-static-libs", kind)
+                .unwrap()
+                .target
+                .lib_dirs()
+                .iter()
+                .map(|dir| format!("{}{}", root.path().display(), dir))
+                .collect::<Vec<_>>();
+            let deps = vec!["libz.so.1".to_string()];
+            let cmd = resolve_in(&lib_dirs, &deps);
+            let output = std::process::Command::new(&cmd[0])
+                .args(&cmd[1..])
+                .output()
+                .unwrap();
+
+            DynamicDep::parse(
+                &deps,
+                "debian-static-libs",
+                &String::from_utf8_lossy(&output.stdout),
+            )
+            .remove(0)
+        };
+        let archive = |dep: DynamicDep| {
+            dep.static_alternative
+                .unwrap()
+                .path
+                .trim_start_matches(&root.path().display().to_string())
+                .to_string()
+        };
+
+        let gnu_static = resolve(TargetKind::GnuStatic);
+        assert_eq!(
+            Some(format!(
+                "{}/usr/lib/x86_64-linux-gnu/libz.so.1",
+                root.path().display()
+            )),
+            gnu_static.path
+        );
+        assert_eq!("/usr/lib/x86_64-linux-gnu/libz.a", archive(gnu_static));
+        assert_eq!("/opt/musl/lib/libz.a", archive(resolve(TargetKind::Musl)));
+    }
+}
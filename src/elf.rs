@@ -0,0 +1,143 @@
+use color_eyre::{eyre::eyre, Result};
+
+/// What the analyze step needs to know about a binary, parsed from the output of `readelf`.
+///
+/// `readelf` only reads the file, so unlike `ldd` it works the same way on binaries for any architecture, including
+/// ones that can only be run under emulation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ElfInfo {
+    /// The machine the binary is for, as `readelf` describes it, like `AArch64`
+    pub machine: String,
+
+    /// The dynamic loader the binary asks for, if any
+    pub interpreter: Option<String>,
+
+    /// The shared libraries the binary links to directly
+    pub needed: Vec<String>,
+}
+
+impl ElfInfo {
+    /// The `readelf` command line which produces the output that [`ElfInfo::parse`] expects
+    pub fn readelf_command(binary_path: &str) -> Vec<String> {
+        vec![
+            "readelf".to_string(),
+            "--file-header".to_string(),
+            "--program-headers".to_string(),
+            "--dynamic".to_string(),
+            "--wide".to_string(),
+            binary_path.to_string(),
+        ]
+    }
+
+    pub fn parse(readelf_output: &str) -> Result<Self> {
+        let mut info = ElfInfo::default();
+
+        for line in readelf_output.lines() {
+            let line = line.trim();
+
+            if let Some(machine) = line.strip_prefix("Machine:") {
+                info.machine = machine.trim().to_string();
+            } else if let Some(interpreter) = bracketed(line, "[Requesting program interpreter:") {
+                info.interpreter = Some(interpreter.trim().to_string());
+            } else if line.contains("(NEEDED)") {
+                if let Some(library) = bracketed(line, "Shared library: [") {
+                    info.needed.push(library.to_string());
+                }
+            }
+        }
+
+        if info.machine.is_empty() {
+            return Err(eyre!("readelf output has no ELF header"));
+        }
+
+        Ok(info)
+    }
+
+    /// A binary is static if nothing has to be loaded along with it at runtime.  A static PIE still has a dynamic
+    /// section, but it has no interpreter and needs no libraries
+    pub fn is_static(&self) -> bool {
+        self.interpreter.is_none() && self.needed.is_empty()
+    }
+
+    /// Everything the binary needs at runtime: the libraries it links to and the loader that loads them
+    pub fn dynamic_deps(&self) -> Vec<String> {
+        self.needed
+            .iter()
+            .cloned()
+            .chain(self.interpreter.iter().cloned())
+            .collect()
+    }
+}
+
+/// How `readelf` names the machine for the architecture in a target triple, if it's one we know of
+pub(crate) fn machine_for_triple(triple: &str) -> Option<&'static str> {
+    match triple.split('-').next()? {
+        "x86_64" => Some("Advanced Micro Devices X86-64"),
+        "aarch64" => Some("AArch64"),
+        _ => None,
+    }
+}
+
+/// The text after `prefix` in `line`, up to the closing `]`
+fn bracketed<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    let start = line.find(prefix)? + prefix.len();
+    let rest = &line[start..];
+
+    rest.find(']').map(|end| &rest[..end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dynamic_binary_has_interpreter_and_libs() {
+        let info = ElfInfo::parse(
+            "ELF Header:\n\
+             \x20 Type:                              DYN (Position-Independent Executable file)\n\
+             \x20 Machine:                           Advanced Micro Devices X86-64\n\
+             Program Headers:\n\
+             \x20 INTERP         0x000318 0x0000000000000318 0x0000000000000318 0x00001c 0x00001c R   0x1\n\
+             \x20     [Requesting program interpreter: /lib64/ld-linux-x86-64.so.2]\n\
+             Dynamic section at offset 0x2dc8 contains 27 entries:\n\
+             \x200x0000000000000001 (NEEDED)             Shared library: [libgcc_s.so.1]\n\
+             \x200x0000000000000001 (NEEDED)             Shared library: [libc.so.6]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("Advanced Micro Devices X86-64"),
+            machine_for_triple("x86_64-unknown-linux-gnu")
+        );
+        assert_eq!("Advanced Micro Devices X86-64", info.machine);
+        assert!(!info.is_static());
+        assert_eq!(
+            vec!["libgcc_s.so.1", "libc.so.6", "/lib64/ld-linux-x86-64.so.2"],
+            info.dynamic_deps()
+        );
+    }
+
+    #[test]
+    fn static_pie_is_static() {
+        let info = ElfInfo::parse(
+            "ELF Header:\n\
+             \x20 Type:                              DYN (Shared object file)\n\
+             \x20 Machine:                           AArch64\n\
+             Dynamic section at offset 0x5e8f0 contains 18 entries:\n\
+             \x200x000000000000001e (FLAGS)              BIND_NOW\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("AArch64"),
+            machine_for_triple("aarch64-unknown-linux-musl")
+        );
+        assert_eq!("AArch64", info.machine);
+        assert!(info.is_static());
+    }
+
+    #[test]
+    fn output_without_header_is_an_error() {
+        assert!(ElfInfo::parse("readelf: Error: Not an ELF file\n").is_err());
+    }
+}
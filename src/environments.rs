@@ -1,65 +1,92 @@
-use color_eyre::{
-    eyre::Context,
-    eyre::{eyre, WrapErr},
-    Report, Result,
-};
+use crate::docker::{ContainerAccess, ContainerDaemon, ContainerSpec};
+use crate::profiles::BuildProfile;
+use color_eyre::Result;
 use once_cell::sync::Lazy;
-use serde::Serialize;
-use shiplift::{
-    builder::{ImageFilter, ImageListOptions},
-    rep::{ContainerCreateInfo, Image},
-    Container, ContainerOptions, Docker,
-};
-use std::{
-    future::Future,
-    path::{Path, PathBuf},
-};
-use tracing::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 static ENVIRONMENTS: Lazy<Vec<Environment>> = Lazy::new(|| {
     vec![
         Environment {
             name: "alpine-custom-rust".to_string(),
-            musl_target: "x86_64-alpine-linux-musl".to_string(),
-            cargo_home: "/root/.cargo".to_string(),
+            targets: musl_targets("x86_64-alpine-linux-musl"),
+            backend: Backend::Docker {
+                cargo_home: "/root/.cargo".to_string(),
+            },
         },
         Environment {
             name: "alpine-official-rust".to_string(),
-            musl_target: "x86_64-unknown-linux-musl".to_string(),
-            cargo_home: "/usr/local/cargo".to_string(),
+            targets: musl_targets("x86_64-unknown-linux-musl"),
+            backend: Backend::Docker {
+                cargo_home: "/usr/local/cargo".to_string(),
+            },
         },
         Environment {
             name: "debian-rust".to_string(),
-            musl_target: "x86_64-unknown-linux-musl".to_string(),
-            cargo_home: "/usr/local/cargo".to_string(),
+            targets: gnu_and_musl_targets(),
+            backend: Backend::Docker {
+                cargo_home: "/usr/local/cargo".to_string(),
+            },
         },
         Environment {
             name: "debian-static-libs".to_string(),
-            musl_target: "x86_64-unknown-linux-musl".to_string(),
-            cargo_home: "/usr/local/cargo".to_string(),
+            targets: gnu_and_musl_targets(),
+            backend: Backend::Docker {
+                cargo_home: "/usr/local/cargo".to_string(),
+            },
+        },
+        Environment {
+            name: "debian-aarch64-musl".to_string(),
+            targets: aarch64_musl_targets(),
+            backend: Backend::Docker {
+                cargo_home: "/usr/local/cargo".to_string(),
+            },
+        },
+        Environment {
+            name: "host".to_string(),
+            targets: gnu_and_musl_targets(),
+            backend: Backend::Host,
         },
     ]
 });
 static ENVIRONMENT_NAMES: Lazy<Vec<&'static str>> =
     Lazy::new(|| ENVIRONMENTS.iter().map(|env| env.name.as_str()).collect());
 
-/// Describes a build environment, encapsulated in a Docker container, in which we will attempt to build a static Rust binary
+/// Describes a build environment in which we will attempt to build a static Rust binary: usually a Docker container,
+/// or the host itself for the `host` environment
 pub(crate) struct Environment {
-    /// The name of this environment for reporting purposes, and also the Docker image label
+    /// The name of this environment for reporting purposes, and for a Docker environment also the Docker image label
     /// which locates the Docker image for this environment
     name: String,
 
-    /// The name of the Rust musl target.
+    /// The targets this environment can build for.
     ///
-    /// This is typicaly `x86_64-linux-unknown-musl` but Alpine comes with a custom build of Rust that uses a different name
-    musl_target: String,
+    /// Every environment can build for musl, which is typicaly `x86_64-linux-unknown-musl` but Alpine comes with a
+    /// custom build of Rust that uses a different name, and `debian-aarch64-musl` cross-compiles to aarch64.  Only
+    /// the x86_64 environments with glibc can build for gnu
+    targets: Vec<Target>,
 
-    /// The path (within the container) where cargo holds its caches
-    cargo_home: String,
+    /// How the commands of a test are executed in this environment
+    backend: Backend,
+}
+
+/// The mechanism by which an environment runs the commands that make up a test
+pub(crate) enum Backend {
+    /// Commands run in a Docker container launched from this environment's image
+    Docker {
+        /// The path (within the container) where cargo holds its caches
+        cargo_home: String,
+    },
+
+    /// Commands run directly on this host as local processes.
+    ///
+    /// This requires the musl target and musl tools to be installed on the host, but not Docker
+    Host,
 }
 
 impl Environment {
-    pub fn from_name(name: &str) -> Option<&Environment> {
+    pub fn from_name(name: &str) -> Option<&'static Environment> {
         ENVIRONMENTS.iter().find(|env| env.name == name)
     }
 
@@ -67,61 +94,267 @@ impl Environment {
         &self.name
     }
 
-    pub fn musl_target(&self) -> &str {
-        &self.musl_target
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
     }
 
-    pub fn cargo_home(&self) -> &str {
-        &self.cargo_home
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    /// True if this environment runs its tests in a Docker container
+    pub fn uses_docker(&self) -> bool {
+        matches!(self.backend, Backend::Docker { .. })
     }
 
     /// Launch a new docker container with this environment's image, with the working directory
-    /// pre-set to `/build`
-    pub async fn launch_container<'docker, E, S, Vols, Vol>(
+    /// pre-set to `/build` and any extra access to the host the test needs, returning the ID of the container
+    pub async fn launch_container(
         &self,
-        docker: &'docker Docker,
-        envs: E,
-        volumes: Vols,
-    ) -> Result<Container<'docker>>
-    where
-        S: AsRef<str> + Serialize,
-        E: AsRef<[S]> + Serialize,
-        Vol: AsRef<str>,
-        Vols: AsRef<[Vol]>,
-    {
-        let image = self.find_docker_image(docker).await?;
+        daemon: &dyn ContainerDaemon,
+        env: Vec<String>,
+        mut volumes: Vec<String>,
+        access: &ContainerAccess,
+    ) -> Result<String> {
+        let image_id = self.find_docker_image(daemon).await?;
 
         // Create a new container running this image
-        let vols = volumes.as_ref().iter().map(|v| v.as_ref()).collect();
-        let options = ContainerOptions::builder(&image.id)
-            .tty(true)
-            .env(envs)
-            .volumes(vols)
-            .auto_remove(false)
-            .working_dir("/build")
-            // .user("1000")
-            .build();
-        let container_info = docker
-            .containers()
-            .create(&options)
-            .await
-            .wrap_err_with(|| eyre!("Error creating docker container from image {}", image.id))?;
+        volumes.extend(access.volumes());
+        let spec = ContainerSpec {
+            env,
+            volumes,
+            working_dir: "/build".to_string(),
+            capabilities: access.capabilities.clone(),
+            privileged: access.privileged,
+        };
+
+        daemon.launch_container(&image_id, &spec).await
+    }
+
+    /// Find the ID of the docker image for this environment in the container daemon
+    async fn find_docker_image(&self, daemon: &dyn ContainerDaemon) -> Result<String> {
+        daemon.find_image_by_label(&self.name).await
+    }
+}
+
+/// The kinds of target a test can be built for.  Each environment declares which of these it supports, and the
+/// target triple for each
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TargetKind {
+    /// glibc, statically linked.  This works, but glibc warns about functions like `getaddrinfo` which still need
+    /// the shared libraries of the same glibc version at runtime
+    GnuStatic,
+
+    /// musl, statically linked into a position dependent executable
+    Musl,
 
-        debug!(container_id = %container_info.id, "Started container");
+    /// musl, statically linked and position independent, so the binary still gets ASLR
+    MuslStaticPie,
+}
 
-        let container: Container<'docker> = docker.containers().get(&container_info.id);
+impl TargetKind {
+    pub const NAMES: &'static [&'static str] = &["gnu-static", "musl", "musl-static-pie"];
 
-        container
-            .start()
-            .await
-            .wrap_err_with(|| eyre!("Error starting docker container from image {}", image.id))?;
+    pub fn name(&self) -> &'static str {
+        match self {
+            TargetKind::GnuStatic => "gnu-static",
+            TargetKind::Musl => "musl",
+            TargetKind::MuslStaticPie => "musl-static-pie",
+        }
+    }
 
-        Ok(container)
+    /// Flags for rustc to build for this kind of target, besides the `+crt-static` that every target gets
+    pub fn rustflags(&self) -> &'static [&'static str] {
+        match self {
+            // Surface the linker's warnings, which is where glibc warns about functions that need shared
+            // libraries at runtime.  Toolchains older than the lint just warn that it's unknown
+            TargetKind::GnuStatic => &["-W", "linker-messages"],
+            // Current toolchains default musl targets to static PIE too, so pin this one to a non-PIE executable
+            // or it would build the same binary as `MuslStaticPie`
+            TargetKind::Musl => &["-C", "relocation-model=static"],
+            TargetKind::MuslStaticPie => &["-C", "relocation-model=pie"],
+        }
     }
+}
 
-    /// Find the docker image for this environment in the local docker daemon
-    async fn find_docker_image(&self, docker: &shiplift::Docker) -> Result<shiplift::rep::Image> {
-        crate::docker::get_image_by_label(docker, &self.name).await
+impl fmt::Display for TargetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TargetKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "gnu-static" => Ok(TargetKind::GnuStatic),
+            "musl" => Ok(TargetKind::Musl),
+            "musl-static-pie" => Ok(TargetKind::MuslStaticPie),
+            other => Err(format!("'{}' is not a valid target", other)),
+        }
+    }
+}
+
+/// A target that an environment can build for
+pub(crate) struct Target {
+    kind: TargetKind,
+
+    /// The Rust target triple
+    triple: String,
+
+    /// The emulator that `cargo run` uses to run binaries for this target, if it's not the architecture of the
+    /// environment itself
+    runner: Option<String>,
+
+    /// The linker the environment sets for this target with `CARGO_TARGET_<TRIPLE>_LINKER`, if it's not rustc's
+    /// default of `cc`
+    linker: Option<String>,
+
+    /// For a cross target, the dirs with its own libraries: the cross toolchain's sysroot, and whatever the
+    /// environment built for the target
+    sysroot_lib_dirs: Vec<String>,
+}
+
+impl Target {
+    pub fn kind(&self) -> TargetKind {
+        self.kind
+    }
+
+    pub fn triple(&self) -> &str {
+        &self.triple
+    }
+
+    pub fn runner(&self) -> Option<&str> {
+        self.runner.as_deref()
+    }
+
+    /// The linker a test is linked with if it doesn't choose one itself
+    pub fn default_linker(&self) -> &str {
+        self.linker.as_deref().unwrap_or("cc")
+    }
+
+    /// The dirs where the libraries a binary for this target links with are found, in the order to look in them.
+    ///
+    /// A cross target only has its own dirs, since everything else in the environment is for another architecture.
+    /// `/opt/musl/lib` is where the `debian-static-libs` image has static libraries built for musl, so it comes first
+    /// for musl targets and isn't there at all for glibc ones.  The distro's own dirs, including Debian's multiarch
+    /// dirs, come after
+    pub fn lib_dirs(&self) -> Vec<String> {
+        if !self.sysroot_lib_dirs.is_empty() {
+            return self.sysroot_lib_dirs.clone();
+        }
+
+        let arch = self.triple.split('-').next().unwrap_or_default();
+        let mut dirs = Vec::new();
+        if self.kind != TargetKind::GnuStatic {
+            dirs.push("/opt/musl/lib".to_string());
+            dirs.push(format!("/usr/lib/{}-linux-musl", arch));
+        }
+        dirs.extend(
+            ["/lib", "/lib64", "/usr/lib", "/usr/lib64", "/usr/local/lib"]
+                .iter()
+                .map(|dir| dir.to_string()),
+        );
+        dirs.push(format!("/lib/{}-linux-gnu", arch));
+        dirs.push(format!("/usr/lib/{}-linux-gnu", arch));
+
+        dirs
+    }
+
+    /// The name of the cargo env vars that configure this target, like `CARGO_TARGET_<TRIPLE>_RUSTFLAGS`
+    pub fn cargo_env_var(&self, setting: &str) -> String {
+        format!(
+            "CARGO_TARGET_{}_{}",
+            self.triple.to_uppercase().replace('-', "_"),
+            setting
+        )
+    }
+}
+
+/// The targets of an environment which only has musl
+fn musl_targets(triple: &str) -> Vec<Target> {
+    vec![
+        Target {
+            kind: TargetKind::Musl,
+            triple: triple.to_string(),
+            runner: None,
+            linker: None,
+            sysroot_lib_dirs: Vec::new(),
+        },
+        Target {
+            kind: TargetKind::MuslStaticPie,
+            triple: triple.to_string(),
+            runner: None,
+            linker: None,
+            sysroot_lib_dirs: Vec::new(),
+        },
+    ]
+}
+
+/// The targets of an environment which has glibc and the official musl target
+fn gnu_and_musl_targets() -> Vec<Target> {
+    let mut targets = vec![Target {
+        kind: TargetKind::GnuStatic,
+        triple: "x86_64-unknown-linux-gnu".to_string(),
+        runner: None,
+        linker: None,
+        sysroot_lib_dirs: Vec::new(),
+    }];
+    targets.extend(musl_targets("x86_64-unknown-linux-musl"));
+
+    targets
+}
+
+/// The targets of an x86_64 environment which cross-compiles to aarch64 musl.  The image sets the cross toolchain's
+/// gcc as the linker, and has the target's libraries in the toolchain's sysroot and in `/opt/aarch64-musl`.  The
+/// binaries are run under QEMU's user-mode emulation
+fn aarch64_musl_targets() -> Vec<Target> {
+    musl_targets("aarch64-unknown-linux-musl")
+        .into_iter()
+        .map(|target| Target {
+            runner: Some("qemu-aarch64".to_string()),
+            linker: Some("aarch64-linux-musl-gcc".to_string()),
+            sysroot_lib_dirs: vec![
+                "/opt/aarch64-musl/lib".to_string(),
+                "/opt/aarch64-linux-musl-cross/aarch64-linux-musl/lib".to_string(),
+            ],
+            ..target
+        })
+        .collect()
+}
+
+/// One target of one environment, built with one profile, which is a column of the test matrix
+#[derive(Clone, Copy)]
+pub(crate) struct Platform<'a> {
+    pub env: &'a Environment,
+    pub target: &'a Target,
+    pub profile: BuildProfile,
+}
+
+impl<'a> Platform<'a> {
+    /// Look up a platform by environment name and kind of target, for a debug build
+    #[cfg(test)]
+    pub fn find(env_name: &str, kind: TargetKind) -> Option<Platform<'static>> {
+        let env = Environment::from_name(env_name)?;
+        let target = env.targets.iter().find(|target| target.kind == kind)?;
+
+        Some(Platform {
+            env,
+            target,
+            profile: BuildProfile::Debug,
+        })
+    }
+
+    /// The name of the platform for reporting purposes, as `env/target/profile`
+    pub fn name(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.env.name(),
+            self.target.kind(),
+            self.profile
+        )
     }
 }
 
@@ -129,6 +362,14 @@ pub(crate) fn all_environments() -> &'static [Environment] {
     ENVIRONMENTS.as_slice()
 }
 
+/// The environments which are used when none are specified explicitly.
+///
+/// This is every Docker environment; the `host` environment depends on how this machine is set up, so it must
+/// be requested by name
+pub(crate) fn default_environments() -> impl Iterator<Item = &'static Environment> {
+    all_environments().iter().filter(|env| env.uses_docker())
+}
+
 pub(crate) fn all_environment_names() -> &'static [&'static str] {
     ENVIRONMENT_NAMES.as_slice()
 }
@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// A command to run in a test environment, either in a container or on the host
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ExecRequest {
+    /// The program to run followed by its arguments.
+    ///
+    /// Each element is passed to the process verbatim; nothing is interpreted by a shell unless the request was
+    /// made with [`ExecRequest::shell`]
+    pub cmd: Vec<String>,
+
+    /// Bytes to feed to the command's stdin.  If not set, stdin is empty
+    pub stdin: Option<Vec<u8>>,
+
+    /// How long the command may run before it's killed.  If not set, it can run forever
+    pub timeout: Option<Duration>,
+}
+
+impl ExecRequest {
+    /// Run a program with arguments, passed as is
+    pub fn new<C, S>(cmd: C) -> Self
+    where
+        C: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            cmd: cmd.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Run a script with `bash -c`, for those cases where shell features like pipes or globs are actually needed
+    pub fn shell(script: impl Into<String>) -> Self {
+        Self::new(vec!["bash".to_string(), "-c".to_string(), script.into()])
+    }
+
+    pub fn stdin(mut self, stdin: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The command line for display purposes, with any argument that isn't obviously safe in single quotes
+    pub fn display_cmd(&self) -> String {
+        self.cmd
+            .iter()
+            .map(|arg| {
+                if !arg.is_empty()
+                    && arg
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_./=:+,@".contains(c))
+                {
+                    arg.clone()
+                } else {
+                    format!("'{}'", arg.replace('\'', r"'\''"))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The results of a completed [`ExecRequest`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ExecOutput {
+    /// The exit code of the command.  Meaningless if the command timed out
+    pub exit_code: i64,
+
+    /// The command didn't finish within its timeout and was killed; the output is whatever it produced until then
+    pub timed_out: bool,
+
+    /// Everything the command wrote to stdout
+    pub stdout: String,
+
+    /// Everything the command wrote to stderr
+    pub stderr: String,
+
+    /// stdout and stderr interleaved in the order the output was received, as it would appear in a terminal
+    pub combined: String,
+
+    /// How long the command took to run
+    pub duration: Duration,
+}
+
+impl ExecOutput {
+    pub fn succeeded(&self) -> bool {
+        !self.timed_out && self.exit_code == 0
+    }
+}
+
+/// Receives the output of a command live, as it runs
+pub(crate) trait OutputSink: Sync {
+    fn stdout(&self, chunk: &[u8]);
+
+    fn stderr(&self, chunk: &[u8]);
+}
+
+/// An [`OutputSink`] which writes the output verbatim to this process' stdout/stderr streams
+pub(crate) struct EchoOutput;
+
+impl OutputSink for EchoOutput {
+    fn stdout(&self, chunk: &[u8]) {
+        let _ = io::stdout().write_all(chunk);
+    }
+
+    fn stderr(&self, chunk: &[u8]) {
+        let _ = io::stderr().write_all(chunk);
+    }
+}
+
+/// Accumulates output from a running command into the separate and combined streams of an [`ExecOutput`], passing
+/// it on to an [`OutputSink`] as it arrives
+pub(crate) struct OutputCollector<'a> {
+    sink: &'a dyn OutputSink,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    combined: Vec<u8>,
+}
+
+impl<'a> OutputCollector<'a> {
+    pub fn new(sink: &'a dyn OutputSink) -> Self {
+        Self {
+            sink,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            combined: Vec::new(),
+        }
+    }
+
+    pub fn stdout(&mut self, chunk: &[u8]) {
+        self.sink.stdout(chunk);
+        self.stdout.extend_from_slice(chunk);
+        self.combined.extend_from_slice(chunk);
+    }
+
+    pub fn stderr(&mut self, chunk: &[u8]) {
+        self.sink.stderr(chunk);
+        self.stderr.extend_from_slice(chunk);
+        self.combined.extend_from_slice(chunk);
+    }
+
+    pub fn finish(self, exit_code: i64, duration: Duration) -> ExecOutput {
+        ExecOutput {
+            exit_code,
+            timed_out: false,
+            stdout: String::from_utf8_lossy(&self.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&self.stderr).to_string(),
+            combined: String::from_utf8_lossy(&self.combined).to_string(),
+            duration,
+        }
+    }
+
+    /// The output of a command that was killed because it exceeded its timeout
+    pub fn timed_out(self, duration: Duration) -> ExecOutput {
+        ExecOutput {
+            timed_out: true,
+            ..self.finish(-1, duration)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_cmd_quotes_unsafe_args() {
+        let request = ExecRequest::new(vec!["echo", "plain", "has space", "it's", "$HOME", ""]);
+
+        assert_eq!(
+            r#"echo plain 'has space' 'it'\''s' '$HOME' ''"#,
+            request.display_cmd()
+        );
+    }
+
+    #[test]
+    fn collector_keeps_streams_separate_and_interleaved() {
+        struct NoOutput;
+        impl OutputSink for NoOutput {
+            fn stdout(&self, _chunk: &[u8]) {}
+            fn stderr(&self, _chunk: &[u8]) {}
+        }
+
+        let mut collector = OutputCollector::new(&NoOutput);
+        collector.stdout(b"one\n");
+        collector.stderr(b"two\n");
+        collector.stdout(b"three\n");
+
+        let output = collector.finish(3, Duration::from_secs(1));
+
+        assert_eq!("one\nthree\n", output.stdout);
+        assert_eq!("two\n", output.stderr);
+        assert_eq!("one\ntwo\nthree\n", output.combined);
+        assert!(!output.succeeded());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::docker::{ContainerDaemon, ContainerSpec};
+use crate::exec::{ExecOutput, ExecRequest, OutputSink};
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use std::sync::Mutex;
+
+/// What the fake does when a command is exec'd in one of its containers
+#[derive(Clone, Debug)]
+pub(crate) enum FakeExec {
+    /// The command runs to completion with this exit code and output on stdout and stderr
+    Exit {
+        exit_code: i64,
+        stdout: String,
+        stderr: String,
+    },
+
+    /// The command hangs until its timeout expires, having written `stdout` to stdout
+    Hang { stdout: String },
+
+    /// The command never finishes, even if it has a timeout, so only dropping the exec stops it
+    Block,
+
+    /// The exec operation itself fails, as if the daemon returned an error
+    Error(String),
+}
+
+/// Everything the fake has been asked to do, in order
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FakeCalls {
+    /// Image ID and spec of each launched container
+    pub launched: Vec<(String, ContainerSpec)>,
+
+    /// Container ID and request of each exec
+    pub execs: Vec<(String, ExecRequest)>,
+
+    /// IDs of each stopped container
+    pub stopped: Vec<String>,
+
+    /// IDs of each deleted container
+    pub deleted: Vec<String>,
+}
+
+/// A scripted, in-process stand-in for the Docker daemon, so the logic that drives containers can be tested
+/// without Docker or any of the `elastio:*` images.
+///
+/// Execs are answered from a script of canned responses, and every call is recorded so tests can inspect it
+#[derive(Default)]
+pub(crate) struct FakeDaemon {
+    /// The labels for which an image "exists"
+    labels: Vec<String>,
+
+    /// Canned responses, matched against the start of each exec'd command.  The most recently added match wins, so
+    /// tests can override the responses of a helper that set up the rest of the script
+    script: Vec<(Vec<String>, FakeExec)>,
+
+    /// If set, launching a container fails with this error
+    launch_error: Option<String>,
+
+    calls: Mutex<FakeCalls>,
+}
+
+impl FakeDaemon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pretend the image `elastio:$label` exists
+    pub fn with_image(mut self, label: &str) -> Self {
+        self.labels.push(label.to_string());
+        self
+    }
+
+    /// Any command starting with `cmd_prefix` exits with `exit_code` and writes `stdout` to stdout.
+    ///
+    /// Commands that don't match anything in the script succeed with no output
+    pub fn on_exec(self, cmd_prefix: &[&str], exit_code: i64, stdout: &str) -> Self {
+        self.on_exec_with_stderr(cmd_prefix, exit_code, stdout, "")
+    }
+
+    /// Like `on_exec`, but the command also writes `stderr` to stderr
+    pub fn on_exec_with_stderr(
+        mut self,
+        cmd_prefix: &[&str],
+        exit_code: i64,
+        stdout: &str,
+        stderr: &str,
+    ) -> Self {
+        self.script.push((
+            cmd_prefix.iter().map(|c| c.to_string()).collect(),
+            FakeExec::Exit {
+                exit_code,
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            },
+        ));
+        self
+    }
+
+    /// Any command starting with `cmd_prefix` writes `stdout` and then hangs until it times out
+    pub fn on_exec_timeout(mut self, cmd_prefix: &[&str], stdout: &str) -> Self {
+        self.script.push((
+            cmd_prefix.iter().map(|c| c.to_string()).collect(),
+            FakeExec::Hang {
+                stdout: stdout.to_string(),
+            },
+        ));
+        self
+    }
+
+    /// Any command starting with `cmd_prefix` never finishes
+    pub fn on_exec_block(mut self, cmd_prefix: &[&str]) -> Self {
+        self.script.push((
+            cmd_prefix.iter().map(|c| c.to_string()).collect(),
+            FakeExec::Block,
+        ));
+        self
+    }
+
+    /// Any command starting with `cmd_prefix` fails to exec at all
+    pub fn on_exec_error(mut self, cmd_prefix: &[&str], error: &str) -> Self {
+        self.script.push((
+            cmd_prefix.iter().map(|c| c.to_string()).collect(),
+            FakeExec::Error(error.to_string()),
+        ));
+        self
+    }
+
+    /// Fail every attempt to launch a container
+    pub fn fail_launch(mut self, error: &str) -> Self {
+        self.launch_error = Some(error.to_string());
+        self
+    }
+
+    /// A snapshot of everything this fake has been asked to do so far
+    pub fn calls(&self) -> FakeCalls {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn image_id(label: &str) -> String {
+        format!("sha256:{}", label)
+    }
+}
+
+#[async_trait]
+impl ContainerDaemon for FakeDaemon {
+    async fn find_image_by_label(&self, label: &str) -> Result<String> {
+        if self.labels.iter().any(|l| l == label) {
+            Ok(Self::image_id(label))
+        } else {
+            Err(eyre!("No docker image found with label '{}'", label))
+        }
+    }
+
+    async fn launch_container(&self, image_id: &str, spec: &ContainerSpec) -> Result<String> {
+        if let Some(error) = &self.launch_error {
+            return Err(eyre!("{}", error));
+        }
+
+        let mut calls = self.calls.lock().unwrap();
+        calls.launched.push((image_id.to_string(), spec.clone()));
+
+        Ok(format!("container-{}", calls.launched.len()))
+    }
+
+    async fn exec(
+        &self,
+        container_id: &str,
+        request: &ExecRequest,
+        output: &dyn OutputSink,
+    ) -> Result<ExecOutput> {
+        self.calls
+            .lock()
+            .unwrap()
+            .execs
+            .push((container_id.to_string(), request.clone()));
+
+        let response = self
+            .script
+            .iter()
+            .rev()
+            .find(|(prefix, _)| request.cmd.starts_with(prefix))
+            .map(|(_, response)| response.clone());
+
+        match response {
+            Some(FakeExec::Exit {
+                exit_code,
+                stdout,
+                stderr,
+            }) => {
+                output.stdout(stdout.as_bytes());
+                output.stderr(stderr.as_bytes());
+
+                Ok(ExecOutput {
+                    exit_code,
+                    combined: format!("{}{}", stdout, stderr),
+                    stdout,
+                    stderr,
+                    ..Default::default()
+                })
+            }
+            Some(FakeExec::Hang { stdout }) => match request.timeout {
+                Some(timeout) => {
+                    output.stdout(stdout.as_bytes());
+
+                    // Don't actually wait; the result is the same
+                    Ok(ExecOutput {
+                        timed_out: true,
+                        exit_code: -1,
+                        combined: stdout.clone(),
+                        stdout,
+                        duration: timeout,
+                        ..Default::default()
+                    })
+                }
+                None => Err(eyre!(
+                    "Command {} would hang forever",
+                    request.display_cmd()
+                )),
+            },
+            Some(FakeExec::Block) => futures::future::pending().await,
+            Some(FakeExec::Error(error)) => Err(eyre!("{}", error)),
+            None => Ok(ExecOutput::default()),
+        }
+    }
+
+    async fn stop_container(&self, container_id: &str) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .stopped
+            .push(container_id.to_string());
+        Ok(())
+    }
+
+    async fn delete_container(&self, container_id: &str) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .deleted
+            .push(container_id.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use crate::exec::{ExecOutput, ExecRequest, OutputCollector, OutputSink};
+use color_eyre::{eyre::eyre, eyre::WrapErr, Result};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tracing::*;
+
+/// The state needed to run a test's commands directly on this host, as local processes, instead of
+/// in a Docker container.
+///
+/// This is the host equivalent of a launched container: the working directory is the test crate itself,
+/// and each test run gets its own scratch target dir so it can't interfere with (or be affected by) any
+/// other build of the same crate.
+pub(crate) struct HostSandbox {
+    /// The directory in which commands are run unless the request specifies otherwise (the root of the test crate)
+    workdir: PathBuf,
+
+    /// The scratch directory which is used as the cargo target dir
+    target_dir: PathBuf,
+
+    /// Env vars to set for each command, on top of this process' own environment
+    env_vars: Vec<(String, String)>,
+}
+
+impl HostSandbox {
+    /// Prepare a new host sandbox.  `env_vars` are in the same `NAME=VALUE` format that is used for
+    /// Docker containers; a variable without a value is inherited from this process as is.
+    pub fn new<S: AsRef<str>>(workdir: &Path, target_dir: PathBuf, env_vars: &[S]) -> Result<Self> {
+        std::fs::create_dir_all(&target_dir).wrap_err_with(|| {
+            eyre!("Error creating scratch target dir {}", target_dir.display())
+        })?;
+
+        Ok(Self {
+            workdir: workdir.to_owned(),
+            target_dir,
+            env_vars: parse_env_vars(env_vars),
+        })
+    }
+
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    /// Run a command on the host, as a local process.
+    ///
+    /// Like `docker::exec_in_container`, the stdout/stderr for this command are passed live to `sink`, and also
+    /// captured separately and combined in the returned output
+    pub async fn exec(&self, request: &ExecRequest, sink: &dyn OutputSink) -> Result<ExecOutput> {
+        let (program, args) = request
+            .cmd
+            .split_first()
+            .ok_or_else(|| eyre!("Cannot run an empty command"))?;
+
+        debug!(command = %request.display_cmd(), "Running command on host");
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .current_dir(&self.workdir)
+            .env("CARGO_TARGET_DIR", &self.target_dir)
+            .envs(self.env_vars.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Put the command in its own process group, so that if it times out any processes it started (like the
+            // binary started by `cargo run`) can be killed along with it
+            .process_group(0)
+            .kill_on_drop(true);
+
+        let started = Instant::now();
+        let mut child = command
+            .spawn()
+            .wrap_err_with(|| eyre!("Error running `{}` on the host", program))?;
+        let mut group = ProcessGroupGuard(child.id());
+
+        // Feed stdin (if any) and then close it, so the process never waits for input that won't come.  This happens
+        // in its own task, since a process that doesn't read all of it would otherwise block us past the timeout.  A
+        // process that exits without reading all of it is up to the process, not an error running it
+        let mut child_stdin = child.stdin.take().expect("stdin is piped");
+        let stdin = request.stdin.clone();
+        let display_cmd = request.display_cmd();
+        tokio::spawn(async move {
+            if let Some(stdin) = stdin {
+                if let Err(e) = child_stdin.write_all(&stdin).await {
+                    if e.kind() != std::io::ErrorKind::BrokenPipe {
+                        warn!(command = %display_cmd, error = %e, "Error writing to stdin");
+                    }
+                }
+            }
+        });
+
+        let mut child_stdout = child.stdout.take().expect("stdout is piped");
+        let mut child_stderr = child.stderr.take().expect("stderr is piped");
+        let mut output = OutputCollector::new(sink);
+        let mut stdout_buffer = [0u8; 8192];
+        let mut stderr_buffer = [0u8; 8192];
+        let mut stdout_open = true;
+        let mut stderr_open = true;
+
+        let deadline = request
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        let timed_out = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::pin!(timed_out);
+
+        while stdout_open || stderr_open {
+            tokio::select! {
+                _ = &mut timed_out => {
+                    warn!(command = %request.display_cmd(), "Command timed out; killing it");
+                    kill_process_group(&mut child).await;
+                    group.disarm();
+
+                    return Ok(output.timed_out(started.elapsed()));
+                }
+                count = child_stdout.read(&mut stdout_buffer), if stdout_open => {
+                    let count = count?;
+                    if count == 0 {
+                        stdout_open = false;
+                    } else {
+                        output.stdout(&stdout_buffer[..count]);
+                    }
+                }
+                count = child_stderr.read(&mut stderr_buffer), if stderr_open => {
+                    let count = count?;
+                    if count == 0 {
+                        stderr_open = false;
+                    } else {
+                        output.stderr(&stderr_buffer[..count]);
+                    }
+                }
+            }
+        }
+
+        // The output streams can close before the process exits, so the timeout still applies while waiting for it
+        let status = tokio::select! {
+            _ = &mut timed_out => {
+                warn!(command = %request.display_cmd(), "Command timed out; killing it");
+                kill_process_group(&mut child).await;
+                group.disarm();
+
+                return Ok(output.timed_out(started.elapsed()));
+            }
+            status = child.wait() => status?,
+        };
+        group.disarm();
+
+        // Processes killed by a signal don't have an exit code; report them the way a shell would
+        let exit_code = status
+            .code()
+            .map(i64::from)
+            .or_else(|| status.signal().map(|signal| 128 + i64::from(signal)))
+            .unwrap_or(-1);
+
+        Ok(output.finish(exit_code, started.elapsed()))
+    }
+}
+
+/// Kills the process group of a command that's dropped before it finishes, like when the run is cancelled while it's
+/// running.  `kill_on_drop` only kills the command itself, not whatever it started
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+    /// The command is finished and reaped, so its process group ID may already belong to someone else
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            // SAFETY: `killpg` has no memory safety requirements; the worst case is that the group no longer exists
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Kill a child process which was started in its own process group, and everything else in that group
+async fn kill_process_group(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: `killpg` has no memory safety requirements; the worst case is that the group no longer exists
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+
+    // Make sure the immediate child is reaped, even if it somehow escaped its process group
+    let _ = child.kill().await;
+}
+
+/// Parse env vars in `NAME=VALUE` form into pairs, skipping any without a value
+fn parse_env_vars<S: AsRef<str>>(env_vars: &[S]) -> Vec<(String, String)> {
+    env_vars
+        .iter()
+        .filter_map(|var| {
+            var.as_ref()
+                .split_once('=')
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exec::EchoOutput;
+    use std::time::Duration;
+
+    fn sandbox(workdir: &Path, env_vars: &[&str]) -> HostSandbox {
+        HostSandbox::new(workdir, workdir.join("target"), env_vars).unwrap()
+    }
+
+    #[tokio::test]
+    async fn args_are_not_interpreted_by_a_shell() {
+        let workdir = tempfile::tempdir().unwrap();
+        let output = sandbox(workdir.path(), &[])
+            .exec(
+                &ExecRequest::new(vec!["printf", "%s|", "a \"quoted\" arg", "$HOME", "`id`"]),
+                &EchoOutput,
+            )
+            .await
+            .unwrap();
+
+        assert!(output.succeeded());
+        assert_eq!("a \"quoted\" arg|$HOME|`id`|", output.stdout);
+    }
+
+    #[tokio::test]
+    async fn streams_env_workdir_and_stdin() {
+        let workdir = tempfile::tempdir().unwrap();
+        let output = sandbox(workdir.path(), &["FROM_SANDBOX=sandbox", "ALSO=set"])
+            .exec(
+                &ExecRequest::shell("echo $FROM_SANDBOX $ALSO; pwd >&2; cat; exit 3")
+                    .stdin("from stdin"),
+                &EchoOutput,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(3, output.exit_code);
+        let pwd = format!("{}\n", workdir.path().display());
+        assert_eq!("sandbox set\nfrom stdin", output.stdout);
+        assert_eq!(pwd, output.stderr);
+        assert!(output.combined.contains(&pwd));
+        assert!(output.combined.contains("sandbox set\n"));
+    }
+
+    #[tokio::test]
+    async fn stdin_that_is_not_read_is_not_an_error() {
+        // Much more than fits in a pipe's buffer
+        let stdin = vec![b'x'; 4 * 1024 * 1024];
+
+        let workdir = tempfile::tempdir().unwrap();
+        let output = sandbox(workdir.path(), &[])
+            .exec(
+                &ExecRequest::new(vec!["true"]).stdin(stdin.clone()),
+                &EchoOutput,
+            )
+            .await
+            .unwrap();
+        assert!(output.succeeded());
+
+        let started = Instant::now();
+        let workdir = tempfile::tempdir().unwrap();
+        let output = sandbox(workdir.path(), &[])
+            .exec(
+                &ExecRequest::new(vec!["sleep", "30"])
+                    .stdin(stdin)
+                    .timeout(Duration::from_millis(500)),
+                &EchoOutput,
+            )
+            .await
+            .unwrap();
+        assert!(output.timed_out);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn dropped_command_kills_its_children() {
+        let pid_file = tempfile::NamedTempFile::new().unwrap();
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.path().display());
+
+        let workdir = tempfile::tempdir().unwrap();
+        let sandbox = sandbox(workdir.path(), &[]);
+        let request = ExecRequest::shell(script);
+        let exec = sandbox.exec(&request, &EchoOutput);
+        assert!(tokio::time::timeout(Duration::from_millis(500), exec)
+            .await
+            .is_err());
+
+        let pid = std::fs::read_to_string(pid_file.path()).unwrap();
+        let proc_dir = Path::new("/proc").join(pid.trim());
+        let started = Instant::now();
+        while proc_dir.exists()
+            && !std::fs::read_to_string(proc_dir.join("stat"))
+                .unwrap_or_default()
+                .contains(") Z ")
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "sleep is still running"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_command_and_children() {
+        let started = Instant::now();
+        let workdir = tempfile::tempdir().unwrap();
+        let output = sandbox(workdir.path(), &[])
+            .exec(
+                &ExecRequest::shell("echo partial; sleep 30 & sleep 30; echo never")
+                    .timeout(Duration::from_millis(500)),
+                &EchoOutput,
+            )
+            .await
+            .unwrap();
+
+        assert!(output.timed_out);
+        assert!(!output.succeeded());
+        assert_eq!("partial\n", output.stdout);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
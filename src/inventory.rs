@@ -0,0 +1,453 @@
+use crate::elf::ElfInfo;
+use cargo_metadata::{DependencyKind, Metadata, Package};
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+/// What a build script told cargo about the native libraries to link, from the `output` file cargo keeps for it in
+/// the target dir
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct BuildScriptOutput {
+    /// The name of the build script's dir, which is the package name followed by a hash, like `openssl-sys-1a2b3c4d`
+    pub dir_name: String,
+
+    /// `cargo:rustc-link-lib` values, like `static=ssl`
+    pub link_libs: Vec<String>,
+
+    /// `cargo:rustc-link-search` values, like `native=/usr/lib`
+    pub link_search: Vec<String>,
+}
+
+impl BuildScriptOutput {
+    /// The `find` command line which prints the path and then the contents of the `output` of every build script that
+    /// was run for `triple`, for [`BuildScriptOutput::parse`].
+    ///
+    /// Build scripts of packages that are only built for the host, like the dependencies of proc macros, are under
+    /// the target dir without the triple, so they're left out
+    pub fn find_command(target_dir: &str, triple: &str) -> Vec<String> {
+        vec![
+            "find".to_string(),
+            target_dir.to_string(),
+            "-path".to_string(),
+            format!("*/{}/*/build/*/output", triple),
+            "-type".to_string(),
+            "f".to_string(),
+            "-print".to_string(),
+            "-exec".to_string(),
+            "cat".to_string(),
+            "{}".to_string(),
+            ";".to_string(),
+        ]
+    }
+
+    pub fn parse(find_output: &str) -> Vec<Self> {
+        let mut outputs: Vec<Self> = Vec::new();
+
+        for line in find_output.lines() {
+            let line = line.trim();
+
+            if let Some(directive) = line
+                .strip_prefix("cargo::")
+                .or_else(|| line.strip_prefix("cargo:"))
+            {
+                let output = match outputs.last_mut() {
+                    Some(output) => output,
+                    None => continue,
+                };
+
+                if let Some(lib) = directive.strip_prefix("rustc-link-lib=") {
+                    output.link_libs.push(lib.to_string());
+                } else if let Some(path) = directive.strip_prefix("rustc-link-search=") {
+                    output.link_search.push(path.to_string());
+                }
+            } else if let Some(dir) = line
+                .strip_suffix("/output")
+                .filter(|dir| dir.contains("/build/"))
+            {
+                outputs.push(Self {
+                    dir_name: dir.rsplit('/').next().unwrap().to_string(),
+                    ..Default::default()
+                });
+            }
+        }
+
+        outputs
+    }
+
+    /// The name of the package the build script belongs to
+    pub fn package_name(&self) -> &str {
+        match self.dir_name.rsplit_once('-') {
+            Some((name, _hash)) => name,
+            None => &self.dir_name,
+        }
+    }
+
+    /// The paths of the `rustc-link-search` values, without the kind
+    fn search_paths(&self) -> impl Iterator<Item = &str> {
+        self.link_search
+            .iter()
+            .map(|search| match search.split_once('=') {
+                Some((kind, path))
+                    if ["native", "dependency", "crate", "framework", "all"].contains(&kind) =>
+                {
+                    path
+                }
+                _ => search.as_str(),
+            })
+    }
+
+    /// Whether any of the search paths are in the build script's own `OUT_DIR`, where it builds things from source
+    fn searches_out_dir(&self) -> bool {
+        let out_dir = format!("/build/{}/out", self.dir_name);
+
+        self.search_paths().any(|path| path.contains(&out_dir))
+    }
+}
+
+/// Where a native library comes from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NativeSource {
+    /// Built from source that's bundled in the `-sys` crate itself
+    Bundled,
+
+    /// Built from source by one of the `*-src` crates, like `openssl-src`
+    Vendored(String),
+
+    /// Found with `pkg-config`
+    PkgConfig,
+
+    /// Found where an env var like `OPENSSL_DIR` says it is
+    EnvVar(String),
+
+    /// Whatever the linker finds in its default search path
+    System,
+}
+
+impl fmt::Display for NativeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NativeSource::Bundled => f.write_str("bundled source"),
+            NativeSource::Vendored(crate_name) => write!(f, "vendored by {}", crate_name),
+            NativeSource::PkgConfig => f.write_str("pkg-config"),
+            NativeSource::EnvVar(name) => write!(f, "env var {}", name),
+            NativeSource::System => f.write_str("system"),
+        }
+    }
+}
+
+/// How a native library ended up in the binary
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Linkage {
+    /// The binary doesn't need the library at runtime, so it's embedded in it
+    Static,
+
+    /// The binary needs the library's shared object at runtime
+    Dynamic,
+
+    /// A package declares the library with `links`, but its build script didn't say how to link it
+    Unknown,
+}
+
+impl fmt::Display for Linkage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Linkage::Static => "static",
+            Linkage::Dynamic => "dynamic",
+            Linkage::Unknown => "unknown",
+        })
+    }
+}
+
+/// A native library that a test's binary links to, and how it got it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct NativeLib {
+    /// The library's name as the linker knows it, like `ssl` for `libssl`
+    pub name: String,
+
+    /// The package whose build script links the library
+    pub package: String,
+
+    /// The kind the build script asked for, like `static` or `dylib`, if it said
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+
+    pub source: NativeSource,
+
+    pub linkage: Linkage,
+}
+
+/// List the native libraries a binary links to, from what the build scripts told cargo and the `links` keys of the
+/// packages in `metadata`, and compare them with the libraries the binary actually needs at runtime.
+///
+/// `env_vars` is the environment the build ran in for `triple`, as `NAME=VALUE`, including whatever the image itself
+/// sets
+pub(crate) fn inventory(
+    metadata: &Metadata,
+    outputs: &[BuildScriptOutput],
+    env_vars: &[String],
+    triple: &str,
+    elf: &ElfInfo,
+) -> Vec<NativeLib> {
+    let mut libs = Vec::new();
+
+    for output in outputs {
+        let package = metadata
+            .packages
+            .iter()
+            .find(|package| package.name == output.package_name());
+        let links = package.and_then(|package| package.links.as_deref());
+
+        let mut declared = output
+            .link_libs
+            .iter()
+            .map(|lib| parse_link_lib(lib))
+            .collect::<Vec<_>>();
+        if declared.is_empty() {
+            match links {
+                Some(links) => declared.push((None, links.to_string())),
+                None => continue,
+            }
+        }
+
+        for (kind, name) in declared {
+            let source = source(
+                metadata,
+                package,
+                links,
+                output,
+                kind.as_deref(),
+                env_vars,
+                triple,
+            );
+            let linkage = if is_needed(elf, &name) {
+                Linkage::Dynamic
+            } else if output.link_libs.is_empty() {
+                Linkage::Unknown
+            } else {
+                Linkage::Static
+            };
+
+            libs.push(NativeLib {
+                name,
+                package: output.package_name().to_string(),
+                kind,
+                source,
+                linkage,
+            });
+        }
+    }
+
+    libs
+}
+
+/// Split a `rustc-link-lib` value, which is `[KIND[:MODIFIERS]=]NAME[:RENAME]`, into the kind and the name
+fn parse_link_lib(lib: &str) -> (Option<String>, String) {
+    let (kind, name) = match lib.split_once('=') {
+        Some((kind, name)) => (Some(kind.split(':').next().unwrap().to_string()), name),
+        None => (None, lib),
+    };
+
+    (kind, name.split(':').next().unwrap().to_string())
+}
+
+/// The env vars in the output of `env`.  A value with a newline in it continues on the next line, which is skipped
+pub(crate) fn parse_env(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| match line.split_once('=') {
+            Some((name, _)) => {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            None => false,
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+fn source(
+    metadata: &Metadata,
+    package: Option<&Package>,
+    links: Option<&str>,
+    output: &BuildScriptOutput,
+    kind: Option<&str>,
+    env_vars: &[String],
+    triple: &str,
+) -> NativeSource {
+    if let Some(name) = dir_env_var(links, output, env_vars, triple) {
+        return NativeSource::EnvVar(name.to_string());
+    }
+
+    let deps = package
+        .map(|package| dependencies(metadata, package))
+        .unwrap_or_default();
+    let is_static = kind == Some("static");
+
+    if let Some((vendor, _)) = deps.iter().find(|(name, _)| name.ends_with("-src")) {
+        if is_static {
+            return NativeSource::Vendored(vendor.clone());
+        }
+    }
+
+    if is_static && output.searches_out_dir() {
+        NativeSource::Bundled
+    } else if deps
+        .iter()
+        .any(|(name, kind)| name == "pkg-config" && *kind == DependencyKind::Build)
+    {
+        NativeSource::PkgConfig
+    } else {
+        NativeSource::System
+    }
+}
+
+/// The env var named after the library which points at a dir, like `OPENSSL_DIR`, `SQLITE3_LIB_DIR` or
+/// `X86_64_UNKNOWN_LINUX_MUSL_OPENSSL_DIR`.
+///
+/// The image can set such a var for every build, including those that ignore it, so if the build script gave search
+/// paths one of them has to be in the var's dir
+fn dir_env_var<'a>(
+    links: Option<&str>,
+    output: &BuildScriptOutput,
+    env_vars: &'a [String],
+    triple: &str,
+) -> Option<&'a str> {
+    let prefixes = links
+        .into_iter()
+        .chain(std::iter::once(
+            output.package_name().trim_end_matches("-sys"),
+        ))
+        .map(|name| format!("{}_", name.to_uppercase().replace('-', "_")))
+        .collect::<Vec<_>>();
+    let triple_prefix = format!("{}_", triple.to_uppercase().replace('-', "_"));
+    let search_paths = output.search_paths().collect::<Vec<_>>();
+
+    env_vars.iter().find_map(|var| {
+        let (name, value) = var.split_once('=')?;
+        let unprefixed = name.strip_prefix(&triple_prefix).unwrap_or(name);
+        let dir = value.trim_end_matches('/');
+        let is_named = unprefixed.ends_with("DIR")
+            && prefixes.iter().any(|prefix| unprefixed.starts_with(prefix));
+        let is_searched = search_paths.is_empty()
+            || search_paths
+                .iter()
+                .any(|path| !dir.is_empty() && Path::new(path).starts_with(dir));
+
+        Some(name).filter(|_| is_named && is_searched)
+    })
+}
+
+/// The names of a package's dependencies in the resolved graph, with their kinds
+fn dependencies(metadata: &Metadata, package: &Package) -> Vec<(String, DependencyKind)> {
+    let node = metadata
+        .resolve
+        .iter()
+        .flat_map(|resolve| &resolve.nodes)
+        .find(|node| node.id == package.id);
+
+    node.into_iter()
+        .flat_map(|node| &node.deps)
+        .flat_map(|dep| {
+            let name = metadata
+                .packages
+                .iter()
+                .find(|package| package.id == dep.pkg)
+                .map(|package| package.name.clone())
+                .unwrap_or_else(|| dep.name.clone());
+
+            dep.dep_kinds
+                .iter()
+                .map(move |info| (name.clone(), info.kind))
+        })
+        .collect()
+}
+
+/// Whether the binary needs a shared object for the library `name` at runtime
+fn is_needed(elf: &ElfInfo, name: &str) -> bool {
+    let so_name = format!("lib{}.so", name);
+
+    elf.needed
+        .iter()
+        .any(|needed| needed == &so_name || needed.starts_with(&format!("{}.", so_name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_script_output_is_parsed() {
+        let outputs = BuildScriptOutput::parse(
+            "target/x86_64-unknown-linux-musl/debug/build/openssl-sys-9f4e2a1b3c5d6e7f/output\n\
+             cargo:rustc-cfg=const_fn\n\
+             cargo:rustc-link-search=native=/build/target/x86_64-unknown-linux-musl/debug/build/openssl-sys-9f4e2a1b3c5d6e7f/out/openssl-build/install/lib\n\
+             cargo:rustc-link-lib=static=ssl\n\
+             cargo::rustc-link-lib=static:+whole-archive=crypto\n\
+             target/x86_64-unknown-linux-musl/debug/build/proc-macro2-0123456789abcdef/output\n\
+             cargo:rustc-cfg=wrap_proc_macro\n",
+        );
+
+        assert_eq!(2, outputs.len());
+        assert_eq!("openssl-sys", outputs[0].package_name());
+        assert_eq!(
+            vec!["static=ssl", "static:+whole-archive=crypto"],
+            outputs[0].link_libs
+        );
+        assert!(outputs[0].searches_out_dir());
+        assert_eq!("proc-macro2", outputs[1].package_name());
+        assert!(outputs[1].link_libs.is_empty());
+        assert_eq!(
+            (Some("static".to_string()), "crypto".to_string()),
+            parse_link_lib(&outputs[0].link_libs[1])
+        );
+    }
+
+    #[test]
+    fn dir_env_vars_can_be_for_the_target() {
+        let output = |search: &str| BuildScriptOutput {
+            dir_name: "openssl-sys-9f4e2a1b3c5d6e7f".to_string(),
+            link_libs: vec!["static=ssl".to_string()],
+            link_search: vec![format!("native={}", search)],
+        };
+        let env = parse_env(
+            "PATH=/usr/local/bin:/usr/bin\n\
+             X86_64_UNKNOWN_LINUX_GNU_OPENSSL_DIR=/opt/gnu/\n\
+             X86_64_UNKNOWN_LINUX_MUSL_OPENSSL_DIR=/opt/musl/\n\
+             MULTI_LINE=one\n\
+             two\n",
+        );
+        let triple = "x86_64-unknown-linux-musl";
+
+        assert_eq!(4, env.len());
+        assert_eq!(
+            Some("X86_64_UNKNOWN_LINUX_MUSL_OPENSSL_DIR"),
+            dir_env_var(Some("openssl"), &output("/opt/musl/lib"), &env, triple)
+        );
+        assert_eq!(
+            None,
+            dir_env_var(
+                Some("openssl"),
+                &output("/build/target/debug/build/openssl-sys-9f4e2a1b3c5d6e7f/out/install/lib"),
+                &env,
+                triple
+            )
+        );
+        assert_eq!(
+            None,
+            dir_env_var(Some("openssl"), &output("/opt/gnu/lib"), &env, triple)
+        );
+    }
+
+    #[test]
+    fn needed_libs_are_dynamic() {
+        let elf = ElfInfo {
+            needed: vec!["libudev.so.1".to_string(), "libc.so.6".to_string()],
+            ..Default::default()
+        };
+
+        assert!(is_needed(&elf, "udev"));
+        assert!(is_needed(&elf, "c"));
+        assert!(!is_needed(&elf, "ssl"));
+    }
+}
@@ -0,0 +1,323 @@
+use crate::probe::Scan;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::time::Duration;
+
+/// Where static libraries and `.pc` files are looked for.  `/opt` is where the `debian-static-libs` image builds
+/// libraries from source, in `/opt/musl`
+const LIB_DIRS: &str = "/lib /lib64 /usr/lib /usr/lib64 /usr/local/lib /opt";
+
+/// Where else `.pc` files are looked for, for the architecture-independent ones
+const PC_DIRS: &str = "/usr/share /usr/local/share";
+
+/// How long the scan may run
+const SCAN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The static libraries and `pkg-config` files in an environment
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct LibScan {
+    /// The path of every `.a` archive, except those of the Rust toolchain itself
+    pub archives: Vec<String>,
+
+    pub pc_files: Vec<PcFile>,
+}
+
+/// What matters about a `.pc` file for linking
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PcFile {
+    pub path: String,
+
+    /// The `libdir` variable, with any variables in it expanded
+    pub libdir: Option<String>,
+
+    /// The libraries its `Libs` links with `-l`
+    pub libs: Vec<String>,
+}
+
+impl PcFile {
+    /// The `pkg-config` crate won't link a library statically from a system dir, which on Linux is anything under
+    /// `/usr`, even with `PKG_CONFIG_ALL_STATIC` set
+    pub fn libdir_under_usr(&self) -> bool {
+        self.libdir
+            .as_deref()
+            .map(|libdir| Path::new(libdir).starts_with("/usr"))
+            .unwrap_or(false)
+    }
+}
+
+impl LibScan {
+    /// The scan, which runs like the environment probe and is cached the same way
+    pub fn scan() -> Scan {
+        Scan {
+            kind: "libs",
+            script: format!(
+                r#"echo '== archives'; find {lib_dirs} -path '*/rustlib' -prune -o -type f -name '*.a' -print 2>/dev/null
+echo '== pc-files'; find {lib_dirs} {pc_dirs} -path '*/rustlib' -prune -o -type f -name '*.pc' -print 2>/dev/null | while read -r pc; do echo "-- $pc"; grep -E '^[A-Za-z0-9_.]+[[:space:]]*=|^Libs:' "$pc"; done
+true"#,
+                lib_dirs = LIB_DIRS,
+                pc_dirs = PC_DIRS
+            ),
+            timeout: SCAN_TIMEOUT,
+        }
+    }
+
+    /// Parse the output of the script from [`LibScan::scan`]
+    pub fn parse(output: &str) -> Self {
+        let mut scan = Self::default();
+        let mut pc_vars = BTreeMap::new();
+
+        let mut section = "";
+        for line in output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            if let Some(name) = line.strip_prefix("== ") {
+                section = name;
+                continue;
+            }
+
+            match section {
+                "archives" => scan.archives.push(line.to_string()),
+                "pc-files" => {
+                    if let Some(path) = line.strip_prefix("-- ") {
+                        // pkg-config defines `pcfiledir` itself, and some `.pc` files are relative to it
+                        pc_vars.clear();
+                        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("/"));
+                        pc_vars.insert("pcfiledir".to_string(), dir.display().to_string());
+
+                        scan.pc_files.push(PcFile {
+                            path: path.to_string(),
+                            ..Default::default()
+                        });
+                        continue;
+                    }
+
+                    let pc_file = match scan.pc_files.last_mut() {
+                        Some(pc_file) => pc_file,
+                        None => continue,
+                    };
+                    if let Some(libs) = line.strip_prefix("Libs:") {
+                        pc_file.libs = expand(libs, &pc_vars)
+                            .split_whitespace()
+                            .filter_map(|flag| flag.strip_prefix("-l"))
+                            .map(str::to_string)
+                            .collect();
+                    } else if let Some((name, value)) = line.split_once('=') {
+                        let value = expand(value.trim(), &pc_vars);
+                        if name.trim() == "libdir" {
+                            pc_file.libdir = Some(value.clone());
+                        }
+                        pc_vars.insert(name.trim().to_string(), value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // The same file can be found through more than one of the dirs
+        scan.archives.sort();
+        scan.archives.dedup();
+        scan.pc_files.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.pc_files.dedup_by(|a, b| a.path == b.path);
+
+        scan
+    }
+
+    /// The name of every library there's an archive or a `.pc` file for
+    pub fn lib_names(&self) -> BTreeSet<&str> {
+        self.archives
+            .iter()
+            .filter_map(|path| archive_lib_name(path))
+            .chain(
+                self.pc_files
+                    .iter()
+                    .flat_map(|pc_file| pc_file.libs.iter().map(|lib| lib.as_str())),
+            )
+            .collect()
+    }
+
+    /// The archives of a library, like `libssl.a` for `ssl`
+    pub fn archives_of(&self, lib: &str) -> Vec<&str> {
+        self.archives
+            .iter()
+            .filter(|path| archive_lib_name(path) == Some(lib))
+            .map(|path| path.as_str())
+            .collect()
+    }
+
+    /// The `.pc` files which link a library
+    pub fn pc_files_of(&self, lib: &str) -> Vec<&PcFile> {
+        self.pc_files
+            .iter()
+            .filter(|pc_file| pc_file.libs.iter().any(|l| l == lib))
+            .collect()
+    }
+
+    /// What an environment has of a library, for a cell of the comparison table: `a` if there's an archive, `pc` if
+    /// there's a `.pc` file, and `!` if a `.pc` file has its libdir under `/usr`
+    fn cell(&self, lib: &str) -> String {
+        let mut cell = Vec::new();
+        if !self.archives_of(lib).is_empty() {
+            cell.push("a".to_string());
+        }
+
+        let pc_files = self.pc_files_of(lib);
+        if !pc_files.is_empty() {
+            if pc_files.iter().any(|pc_file| pc_file.libdir_under_usr()) {
+                cell.push("pc!".to_string());
+            } else {
+                cell.push("pc".to_string());
+            }
+        }
+
+        if cell.is_empty() {
+            "-".to_string()
+        } else {
+            cell.join(" ")
+        }
+    }
+}
+
+/// The name a static library is linked by, like `ssl` for `libssl.a`
+pub(crate) fn archive_lib_name(path: &str) -> Option<&str> {
+    let file_name = Path::new(path).file_name()?.to_str()?;
+    let stem = file_name.strip_suffix(".a")?;
+
+    Some(stem.strip_prefix("lib").unwrap_or(stem))
+}
+
+/// Expand the `${name}` variables in a value from a `.pc` file
+fn expand(value: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut expanded = String::new();
+
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}') {
+            Some(end) => {
+                expanded.push_str(vars.get(&after[..end]).map(|v| v.as_str()).unwrap_or(""));
+                rest = &after[end + 1..];
+            }
+            None => {
+                expanded.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    expanded.push_str(rest);
+
+    expanded
+}
+
+/// A table with a row for each library and a column for each environment, showing what each environment has of it.
+/// `libs` are the rows; if there are none, every library found anywhere gets a row
+pub(crate) fn comparison_table(scans: &[(&str, LibScan)], libs: &[String]) -> String {
+    let libs = if libs.is_empty() {
+        scans
+            .iter()
+            .flat_map(|(_, scan)| scan.lib_names())
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    } else {
+        libs.to_vec()
+    };
+
+    let lib_width = libs
+        .iter()
+        .map(|lib| lib.len())
+        .chain(std::iter::once("LIB".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!("{:<width$}", "LIB", width = lib_width);
+    for (env_name, _) in scans {
+        table.push_str(&format!("  {}", env_name));
+    }
+    table.push('\n');
+
+    for lib in &libs {
+        table.push_str(&format!("{:<width$}", lib, width = lib_width));
+        for (env_name, scan) in scans {
+            table.push_str(&format!(
+                "  {:<width$}",
+                scan.cell(lib),
+                width = env_name.len()
+            ));
+        }
+        table.truncate(table.trim_end().len());
+        table.push('\n');
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DEBIAN_STATIC_LIBS: &str = "== archives\n\
+                                      /opt/musl/lib/libssl.a\n\
+                                      /opt/musl/lib/libcrypto.a\n\
+                                      /usr/lib/x86_64-linux-gnu/libudev.a\n\
+                                      == pc-files\n\
+                                      -- /opt/musl/lib/pkgconfig/openssl.pc\n\
+                                      prefix=/opt/musl\n\
+                                      exec_prefix=${prefix}\n\
+                                      libdir=${exec_prefix}/lib\n\
+                                      Libs: -L${libdir} -lssl -lcrypto\n\
+                                      -- /usr/lib/x86_64-linux-gnu/pkgconfig/libudev.pc\n\
+                                      libdir=/usr/lib/x86_64-linux-gnu\n\
+                                      Libs: -L${libdir} -ludev\n\
+                                      -- /usr/share/pkgconfig/xproto.pc\n\
+                                      prefix=/usr\n";
+
+    #[test]
+    fn scan_output_is_parsed() {
+        let scan = LibScan::parse(DEBIAN_STATIC_LIBS);
+
+        assert_eq!(
+            vec!["crypto", "ssl", "udev"],
+            scan.lib_names().into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["/opt/musl/lib/libssl.a"], scan.archives_of("ssl"));
+
+        let openssl = scan.pc_files_of("crypto")[0];
+        assert_eq!("/opt/musl/lib/pkgconfig/openssl.pc", openssl.path);
+        assert_eq!(Some("/opt/musl/lib"), openssl.libdir.as_deref());
+        assert!(!openssl.libdir_under_usr());
+        assert!(scan.pc_files_of("udev")[0].libdir_under_usr());
+    }
+
+    #[test]
+    fn environments_are_compared() {
+        let scans = vec![
+            ("alpine-official-rust", LibScan::default()),
+            ("debian-static-libs", LibScan::parse(DEBIAN_STATIC_LIBS)),
+        ];
+
+        assert_eq!(
+            "LIB     alpine-official-rust  debian-static-libs\n\
+             crypto  -                     a pc\n\
+             udev    -                     a pc!\n\
+             z       -                     -\n",
+            comparison_table(
+                &scans,
+                &["crypto".to_string(), "udev".to_string(), "z".to_string()]
+            )
+        );
+        assert_eq!(
+            vec!["LIB", "crypto", "ssl", "udev"],
+            comparison_table(&scans, &[])
+                .lines()
+                .map(|line| line.split_whitespace().next().unwrap())
+                .collect::<Vec<_>>()
+        );
+    }
+}
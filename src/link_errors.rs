@@ -0,0 +1,603 @@
+use crate::environments::Target;
+use crate::libs::archive_lib_name;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How many symbols are listed in the description of a group of undefined symbols; the log has the rest
+const SYMBOLS_SHOWN: usize = 5;
+
+/// The script that looks for the static archives which define each of its args after the first, a symbol, in the lib
+/// dirs in the first.  It prints a line with the archive and the symbol for each definition it finds, in the order
+/// the dirs are searched
+const SYMBOL_SEARCH_SCRIPT: &str = r#"lib_dirs=$1
+shift
+symbols=$(mktemp)
+printf '%s\n' "$@" > "$symbols"
+for dir in $lib_dirs; do
+    find -H "$dir" -maxdepth 1 -type f -name '*.a' -print0 2>/dev/null | sort -z
+done \
+    | xargs -0 -r nm -A -g --defined-only 2>/dev/null \
+    | awk 'NR == FNR { wanted[$1]; next } ($NF in wanted) { sub(/:.*/, "", $1); print $1, $NF }' "$symbols" - \
+    | awk '!seen[$0]++'
+rm -f "$symbols"
+true"#;
+
+/// The root cause of a link failure, found in the linker's errors in the build output.
+///
+/// GNU `ld`, `lld` and `mold` each word these differently, but they all report the same handful of problems
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum LinkError {
+    /// Symbols that nothing on the link line defines.  `archive` is the static archive in the environment that
+    /// defines them, if there's one
+    UndefinedSymbols {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        archive: Option<String>,
+        symbols: Vec<String>,
+    },
+
+    /// A `-l` the linker couldn't find any library for
+    MissingLibrary { lib: String },
+
+    /// An object, usually in a static archive, that wasn't compiled as position-independent code, so it can't be
+    /// linked into a position-independent executable
+    NeedsPic {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        object: Option<String>,
+        relocation: String,
+        symbol: String,
+    },
+
+    /// A symbol that's defined by more than one object, which usually means two libraries bundle the same code
+    MultipleDefinition {
+        symbol: String,
+        objects: Vec<String>,
+    },
+}
+
+impl LinkError {
+    /// Find the link errors in the output of `cargo build`.  Every undefined symbol is in one group, without an
+    /// archive; [`LinkError::attribute_undefined`] splits them up by the archive that defines them
+    pub fn parse(build_output: &str) -> Vec<Self> {
+        let mut errors = Vec::new();
+        let mut undefined = Vec::new();
+
+        let mut lines = build_output.lines().map(strip_linker_prefix).peekable();
+        while let Some(line) = lines.next() {
+            let error = if let Some(symbol) = undefined_symbol(line) {
+                if !undefined.contains(&symbol) {
+                    undefined.push(symbol);
+                }
+                continue;
+            } else if let Some(lib) = missing_library(line) {
+                LinkError::MissingLibrary { lib }
+            } else if line.contains("recompile with -fPI") {
+                // `lld` puts the object on the next line
+                let object = match lines
+                    .peek()
+                    .and_then(|next| next.strip_prefix(">>> defined in "))
+                {
+                    Some(object) => Some(object.trim().to_string()),
+                    None => line
+                        .split_once(": relocation ")
+                        .and_then(|(before, _)| object_of(before)),
+                };
+                match needs_pic(line, object) {
+                    Some(error) => error,
+                    None => continue,
+                }
+            } else if let Some(error) = multiple_definition(line) {
+                error
+            } else {
+                continue;
+            };
+
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+
+        if !undefined.is_empty() {
+            errors.insert(
+                0,
+                LinkError::UndefinedSymbols {
+                    archive: None,
+                    symbols: undefined,
+                },
+            );
+        }
+
+        errors
+    }
+
+    /// Every undefined symbol in `errors`
+    pub fn undefined_symbols(errors: &[Self]) -> Vec<String> {
+        errors
+            .iter()
+            .flat_map(|error| match error {
+                LinkError::UndefinedSymbols { symbols, .. } => symbols.clone(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// The command that finds the static archives in `target`'s lib dirs which define `symbols`.  The symbols are
+    /// passed as args, so nothing in them is interpreted by the shell
+    pub fn symbol_search_command(target: &Target, symbols: &[String]) -> Vec<String> {
+        search_in(&target.lib_dirs(), symbols)
+    }
+
+    /// Split the undefined symbols in `errors` into a group for each archive that defines some of them, according
+    /// to the output of [`LinkError::symbol_search_command`].  When more than one archive defines a symbol, like
+    /// `libssl.a` and a compatibility library that defines a few of the same functions, the one that defines the most
+    /// of the undefined symbols is the likely culprit.  Between archives that define as many, it's the one in the dir
+    /// the linker searches first.  Symbols no archive defines stay in a group of their own
+    pub fn attribute_undefined(errors: Vec<Self>, search_output: &str) -> Vec<Self> {
+        let mut definitions: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let mut defined_by_archive: BTreeMap<&str, usize> = BTreeMap::new();
+        for line in search_output.lines() {
+            if let Some((archive, symbol)) = line.trim().rsplit_once(' ') {
+                definitions.entry(symbol).or_default().push(archive);
+                *defined_by_archive.entry(archive).or_default() += 1;
+            }
+        }
+        let archive_of = |symbol: &str| {
+            definitions.get(symbol).and_then(|archives| {
+                // The first of the archives that define the most, which are listed in search order
+                archives
+                    .iter()
+                    .copied()
+                    .min_by(|a, b| defined_by_archive[b].cmp(&defined_by_archive[a]))
+            })
+        };
+
+        let mut attributed = Vec::new();
+        for error in errors {
+            let symbols = match error {
+                LinkError::UndefinedSymbols {
+                    archive: None,
+                    symbols,
+                } => symbols,
+                other => {
+                    attributed.push(other);
+                    continue;
+                }
+            };
+
+            let mut by_archive: BTreeMap<Option<&str>, Vec<String>> = BTreeMap::new();
+            for symbol in symbols {
+                let archive = archive_of(&symbol);
+                by_archive.entry(archive).or_default().push(symbol);
+            }
+
+            // The symbols no archive defines go last, since there's less to say about them
+            let unknown = by_archive.remove(&None);
+            for (archive, symbols) in by_archive.into_iter().chain(unknown.map(|s| (None, s))) {
+                attributed.push(LinkError::UndefinedSymbols {
+                    archive: archive.map(str::to_string),
+                    symbols,
+                });
+            }
+        }
+
+        attributed
+    }
+}
+
+/// The command that finds the static archives in `lib_dirs` which define `symbols`
+fn search_in(lib_dirs: &[String], symbols: &[String]) -> Vec<String> {
+    let mut cmd = vec![
+        "bash".to_string(),
+        "-c".to_string(),
+        SYMBOL_SEARCH_SCRIPT.to_string(),
+        "bash".to_string(),
+        lib_dirs.join(" "),
+    ];
+    cmd.extend(symbols.iter().cloned());
+
+    cmd
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbols { archive, symbols } => {
+                let mut shown = symbols
+                    .iter()
+                    .take(SYMBOLS_SHOWN)
+                    .map(|symbol| format!("`{}`", symbol))
+                    .collect::<Vec<_>>();
+                if symbols.len() > SYMBOLS_SHOWN {
+                    shown.push(format!("and {} more", symbols.len() - SYMBOLS_SHOWN));
+                }
+                let shown = shown.join(", ");
+
+                match archive {
+                    Some(archive) => {
+                        write!(f, "{} is never linked, or comes too early on the link line", archive)?;
+                        if let Some(lib) = archive_lib_name(archive) {
+                            write!(f, " (`-l{}`)", lib)?;
+                        }
+                        write!(f, "; it defines the undefined {}", shown)
+                    }
+                    None => write!(
+                        f,
+                        "undefined {}, which no static library in the environment defines",
+                        shown
+                    ),
+                }
+            }
+            LinkError::MissingLibrary { lib } => write!(
+                f,
+                "the linker can't find `-l{}`; `libs {}` shows which environments have a static lib{}.a",
+                lib, lib, lib
+            ),
+            LinkError::NeedsPic {
+                object,
+                relocation,
+                symbol,
+            } => {
+                match object {
+                    Some(object) => write!(f, "{}", object)?,
+                    None => write!(f, "an object")?,
+                }
+                write!(
+                    f,
+                    " has a {} relocation against `{}`, so it wasn't compiled with -fPIC; rebuild it with -fPIC, or \
+                     link a non-PIE binary",
+                    relocation, symbol
+                )
+            }
+            LinkError::MultipleDefinition { symbol, objects } => {
+                write!(f, "`{}` is defined more than once", symbol)?;
+                if !objects.is_empty() {
+                    write!(f, ", in {}", objects.join(" and "))?;
+                }
+                write!(f, "; two libraries probably bundle the same code")
+            }
+        }
+    }
+}
+
+/// A line of linker output without what cargo and the linker put in front of the message: cargo's `= note: `, and
+/// the linker's name and severity, like `/usr/bin/ld: ` or `ld.lld: error: `
+fn strip_linker_prefix(line: &str) -> &str {
+    let mut line = line.trim();
+    for prefix in &["= note: ", "note: "] {
+        line = line.strip_prefix(prefix).unwrap_or(line);
+    }
+
+    if let Some((program, rest)) = line.split_once(": ") {
+        let program = program.rsplit('/').next().unwrap_or(program);
+        let is_linker = program == "ld"
+            || program.ends_with("-ld")
+            || program.starts_with("ld.")
+            || program.ends_with("lld")
+            || program == "mold"
+            || program == "collect2";
+        if is_linker && !program.contains(' ') {
+            line = rest;
+        }
+    }
+
+    for severity in &["error: ", "fatal: ", "warning: "] {
+        line = line.strip_prefix(severity).unwrap_or(line);
+    }
+
+    line
+}
+
+/// The symbol in GNU `ld`'s "undefined reference to `foo'", or `lld`'s and `mold`'s "undefined symbol: foo"
+fn undefined_symbol(line: &str) -> Option<String> {
+    let symbol = if let Some((_, rest)) = line.split_once("undefined reference to ") {
+        rest.trim()
+            .trim_start_matches(['`', '\''])
+            .trim_end_matches('\'')
+    } else if let Some(rest) = line.strip_prefix("undefined symbol: ") {
+        // Older versions of `mold` put the object that refers to the symbol first
+        rest.rsplit(": ").next().unwrap_or(rest).trim()
+    } else {
+        return None;
+    };
+
+    if symbol.is_empty() {
+        None
+    } else {
+        Some(symbol.to_string())
+    }
+}
+
+/// The library in GNU `ld`'s "cannot find -lfoo", `lld`'s "unable to find library -lfoo", or `mold`'s "library not
+/// found: foo"
+fn missing_library(line: &str) -> Option<String> {
+    let lib = if let Some((_, rest)) = line
+        .split_once("cannot find -l")
+        .or_else(|| line.split_once("unable to find library -l"))
+    {
+        // Newer versions of `ld` add ": No such file or directory"
+        rest.split(|c: char| c == ':' || c.is_whitespace())
+            .next()
+            .unwrap_or(rest)
+    } else if let Some(rest) = line.strip_prefix("library not found: ") {
+        rest.trim()
+    } else {
+        return None;
+    };
+
+    if lib.is_empty() {
+        None
+    } else {
+        Some(lib.to_string())
+    }
+}
+
+/// The relocation error in GNU `ld`'s "foo.o: relocation R_X86_64_32 against `.rodata' can not be used when making a
+/// PIE object; recompile with -fPIE", or `lld`'s "relocation R_X86_64_32 cannot be used against symbol 'foo';
+/// recompile with -fPIC"
+fn needs_pic(line: &str, object: Option<String>) -> Option<LinkError> {
+    let (_, rest) = line.split_once("relocation ")?;
+    let relocation = rest.split_whitespace().next()?.to_string();
+
+    let (_, against) = rest.split_once("against ")?;
+    let against = against.strip_prefix("symbol ").unwrap_or(against);
+    let symbol = quoted(against)?;
+
+    Some(LinkError::NeedsPic {
+        object,
+        relocation,
+        symbol,
+    })
+}
+
+/// The symbol and objects in GNU `ld`'s "a.o: in function `foo': (.text+0x0): multiple definition of `foo'; b.o:(.text+0x0):
+/// first defined here", or `lld`'s and `mold`'s "duplicate symbol: foo"
+fn multiple_definition(line: &str) -> Option<LinkError> {
+    if let Some((before, rest)) = line.split_once("multiple definition of ") {
+        let symbol = quoted(rest)?;
+
+        let mut objects = Vec::new();
+        objects.extend(object_of(before));
+        if let Some((_, first)) = rest.split_once("; ") {
+            objects.extend(object_of(first));
+        }
+
+        Some(LinkError::MultipleDefinition { symbol, objects })
+    } else {
+        // `mold` puts the objects first
+        line.strip_prefix("duplicate symbol: ")
+            .map(|rest| LinkError::MultipleDefinition {
+                symbol: rest.rsplit(": ").next().unwrap_or(rest).trim().to_string(),
+                objects: Vec::new(),
+            })
+    }
+}
+
+/// The object at the start of a linker message, like `libz.a(deflate.o)` in "libz.a(deflate.o): in function `foo'"
+fn object_of(message: &str) -> Option<String> {
+    let object = message.split(':').next()?.trim();
+
+    if object.is_empty() || object.contains(' ') {
+        None
+    } else {
+        Some(object.to_string())
+    }
+}
+
+/// The first quoted name in a linker message.  GNU `ld` quotes like `` `this' ``, and `lld` like `'this'`
+fn quoted(message: &str) -> Option<String> {
+    let start = message.find(['`', '\''])?;
+    let rest = &message[start + 1..];
+    let end = rest.find('\'')?;
+
+    Some(rest[..end].to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environments::{Platform, TargetKind};
+
+    const BUILD_OUTPUT: &str = r#"   Compiling with-openssl v0.1.0 (/build)
+error: linking with `cc` failed: exit status: 1
+  |
+  = note: "cc" "-m64" "/build/target/debug/deps/with_openssl-1234.o" "-lssl" "-lcrypto" "-static"
+  = note: /usr/bin/ld: /build/target/debug/deps/with_openssl-1234.o: in function `openssl::ssl::SslContext::builder':
+          ssl/mod.rs:(.text+0x1f): undefined reference to `SSL_CTX_new'
+          /usr/bin/ld: ssl/mod.rs:(.text+0x3e): undefined reference to `SSL_CTX_free'
+          /usr/bin/ld: ssl/mod.rs:(.text+0x5d): undefined reference to `SSL_CTX_new'
+          /usr/bin/ld: ssl/mod.rs:(.text+0x7c): undefined reference to `udev_new'
+          /usr/bin/ld: cannot find -lz: No such file or directory
+          /usr/bin/ld: /opt/musl/lib/libz.a(deflate.o): relocation R_X86_64_32 against `.rodata' can not be used when making a PIE object; recompile with -fPIE
+          /usr/bin/ld: /opt/musl/lib/libcrypto.a(b.o): in function `OPENSSL_init': b.c:(.text+0x0): multiple definition of `OPENSSL_init'; /opt/musl/lib/libssl.a(a.o):a.c:(.text+0x0): first defined here
+          collect2: error: ld returned 1 exit status
+
+error: could not compile `with-openssl` due to previous error
+"#;
+
+    #[test]
+    fn gnu_ld_errors_are_parsed() {
+        assert_eq!(
+            vec![
+                LinkError::UndefinedSymbols {
+                    archive: None,
+                    symbols: vec![
+                        "SSL_CTX_new".to_string(),
+                        "SSL_CTX_free".to_string(),
+                        "udev_new".to_string(),
+                    ],
+                },
+                LinkError::MissingLibrary {
+                    lib: "z".to_string()
+                },
+                LinkError::NeedsPic {
+                    object: Some("/opt/musl/lib/libz.a(deflate.o)".to_string()),
+                    relocation: "R_X86_64_32".to_string(),
+                    symbol: ".rodata".to_string(),
+                },
+                LinkError::MultipleDefinition {
+                    symbol: "OPENSSL_init".to_string(),
+                    objects: vec![
+                        "/opt/musl/lib/libcrypto.a(b.o)".to_string(),
+                        "/opt/musl/lib/libssl.a(a.o)".to_string(),
+                    ],
+                },
+            ],
+            LinkError::parse(BUILD_OUTPUT)
+        );
+    }
+
+    #[test]
+    fn lld_and_mold_errors_are_parsed() {
+        let output = "ld.lld: error: undefined symbol: SSL_CTX_new\n\
+                      >>> referenced by mod.rs:12\n\
+                      ld.lld: error: unable to find library -lz\n\
+                      ld.lld: error: relocation R_X86_64_32 cannot be used against symbol 'z_errmsg'; recompile with -fPIC\n\
+                      >>> defined in /opt/musl/lib/libz.a(deflate.o)\n\
+                      mold: fatal: library not found: udev\n\
+                      mold: error: duplicate symbol: /opt/musl/lib/libssl.a(a.o): /opt/musl/lib/libcrypto.a(b.o): OPENSSL_init\n";
+
+        assert_eq!(
+            vec![
+                LinkError::UndefinedSymbols {
+                    archive: None,
+                    symbols: vec!["SSL_CTX_new".to_string()],
+                },
+                LinkError::MissingLibrary {
+                    lib: "z".to_string()
+                },
+                LinkError::NeedsPic {
+                    object: Some("/opt/musl/lib/libz.a(deflate.o)".to_string()),
+                    relocation: "R_X86_64_32".to_string(),
+                    symbol: "z_errmsg".to_string(),
+                },
+                LinkError::MissingLibrary {
+                    lib: "udev".to_string()
+                },
+                LinkError::MultipleDefinition {
+                    symbol: "OPENSSL_init".to_string(),
+                    objects: Vec::new(),
+                },
+            ],
+            LinkError::parse(output)
+        );
+    }
+
+    #[test]
+    fn undefined_symbols_are_grouped_by_archive() {
+        let errors = LinkError::parse(BUILD_OUTPUT);
+        assert_eq!(
+            vec!["SSL_CTX_new", "SSL_CTX_free", "udev_new"],
+            LinkError::undefined_symbols(&errors)
+        );
+
+        let search_output = "/opt/musl/lib/libgnutls-openssl.a SSL_CTX_new\n\
+                             /opt/musl/lib/libssl.a SSL_CTX_free\n\
+                             /opt/musl/lib/libssl.a SSL_CTX_new\n\
+                             /usr/lib/x86_64-linux-gnu/libssl.a SSL_CTX_new\n";
+        let errors = LinkError::attribute_undefined(errors, search_output);
+
+        assert_eq!(
+            vec![
+                "/opt/musl/lib/libssl.a is never linked, or comes too early on the link line (`-lssl`); it defines \
+                 the undefined `SSL_CTX_new`, `SSL_CTX_free`",
+                "undefined `udev_new`, which no static library in the environment defines",
+                "the linker can't find `-lz`; `libs z` shows which environments have a static libz.a",
+                "/opt/musl/lib/libz.a(deflate.o) has a R_X86_64_32 relocation against `.rodata`, so it wasn't \
+                 compiled with -fPIC; rebuild it with -fPIC, or link a non-PIE binary",
+                "`OPENSSL_init` is defined more than once, in /opt/musl/lib/libcrypto.a(b.o) and \
+                 /opt/musl/lib/libssl.a(a.o); two libraries probably bundle the same code",
+            ],
+            errors
+                .iter()
+                .map(|error| error.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn symbol_search_script_runs() {
+        let target = Platform::find("host", TargetKind::GnuStatic)
+            .unwrap()
+            .target;
+        let cmd =
+            LinkError::symbol_search_command(target, &["not_a_real_symbol_anywhere".to_string()]);
+
+        let output = std::process::Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!("", String::from_utf8_lossy(&output.stdout));
+    }
+
+    #[test]
+    fn gnu_static_symbols_are_not_found_in_the_musl_libs() {
+        // A root with a musl libz.a in `/opt/musl/lib` and glibc's in the multiarch dir, which both define `deflate`
+        let root = tempfile::tempdir().unwrap();
+        let object = root.path().join("deflate.o");
+        let assembled = std::process::Command::new("as")
+            .arg("-o")
+            .arg(&object)
+            .arg("-")
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                use std::io::Write;
+                child
+                    .stdin
+                    .take()
+                    .unwrap()
+                    .write_all(b".globl deflate\n.data\ndeflate: .byte 0\n")?;
+                child.wait()
+            })
+            .unwrap();
+        assert!(assembled.success());
+        for dir in ["opt/musl/lib", "usr/lib/x86_64-linux-gnu"] {
+            std::fs::create_dir_all(root.path().join(dir)).unwrap();
+            let archived = std::process::Command::new("ar")
+                .arg("rcs")
+                .arg(root.path().join(dir).join("libz.a"))
+                .arg(&object)
+                .status()
+                .unwrap();
+            assert!(archived.success());
+        }
+
+        let archive = |kind| {
+            let lib_dirs = Platform::find("debian-static-libs", kind)
+                .unwrap()
+                .target
+                .lib_dirs()
+                .iter()
+                .map(|dir| format!("{}{}", root.path().display(), dir))
+                .collect::<Vec<_>>();
+            let cmd = search_in(&lib_dirs, &["deflate".to_string()]);
+            let output = std::process::Command::new(&cmd[0])
+                .args(&cmd[1..])
+                .output()
+                .unwrap();
+            let errors = vec![LinkError::UndefinedSymbols {
+                archive: None,
+                symbols: vec!["deflate".to_string()],
+            }];
+
+            match LinkError::attribute_undefined(errors, &String::from_utf8_lossy(&output.stdout))
+                .remove(0)
+            {
+                LinkError::UndefinedSymbols { archive, .. } => archive
+                    .unwrap()
+                    .trim_start_matches(&root.path().display().to_string())
+                    .to_string(),
+                other => panic!("{:?}", other),
+            }
+        };
+
+        assert_eq!(
+            "/usr/lib/x86_64-linux-gnu/libz.a",
+            archive(TargetKind::GnuStatic)
+        );
+        assert_eq!("/opt/musl/lib/libz.a", archive(TargetKind::Musl));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::environments::{Target, TargetKind};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The linker setups a test can be built with.
+///
+/// The linker itself is set with `CARGO_TARGET_<TRIPLE>_LINKER`, so it's only used for the target and not for build
+/// scripts and proc macros.  Choosing `ld` through the C compiler driver needs a link arg, which goes in the
+/// rustflags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Linker {
+    /// Whatever the environment uses by default, which is usually `cc`
+    #[default]
+    Default,
+
+    /// The `musl-gcc` wrapper from `musl-tools`, which links against musl's own libc and startup files
+    MuslGcc,
+
+    /// `clang` as the driver, linking with LLVM's `lld`
+    ClangLld,
+
+    /// `clang` as the driver, linking with `mold`
+    Mold,
+}
+
+impl Linker {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Linker::Default => "default",
+            Linker::MuslGcc => "musl-gcc",
+            Linker::ClangLld => "clang-lld",
+            Linker::Mold => "mold",
+        }
+    }
+
+    /// The program rustc should link with, if not the default
+    pub fn program(&self) -> Option<&'static str> {
+        match self {
+            Linker::Default => None,
+            Linker::MuslGcc => Some("musl-gcc"),
+            Linker::ClangLld | Linker::Mold => Some("clang"),
+        }
+    }
+
+    /// The programs that have to be installed to link with this setup
+    pub fn required_programs(&self) -> &'static [&'static str] {
+        match self {
+            Linker::Default => &[],
+            Linker::MuslGcc => &["musl-gcc"],
+            Linker::ClangLld => &["clang", "ld.lld"],
+            Linker::Mold => &["clang", "mold"],
+        }
+    }
+
+    /// Why this setup can't link binaries for `target` in any environment, if it can't.  `musl-gcc` only links
+    /// against musl, and none of the setups are cross linkers, so they only work for the environment's own
+    /// architecture
+    pub fn incompatibility(&self, target: &Target) -> Option<String> {
+        if *self == Linker::Default {
+            return None;
+        }
+
+        if *self == Linker::MuslGcc && target.kind() == TargetKind::GnuStatic {
+            Some(format!(
+                "the {} linker only links against musl, not glibc",
+                self
+            ))
+        } else if target.runner().is_some() {
+            Some(format!(
+                "the {} linker can't link for {}",
+                self,
+                target.triple()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Flags for rustc to pass to the linker program
+    pub fn rustflags(&self) -> &'static [&'static str] {
+        match self {
+            Linker::Default | Linker::MuslGcc => &[],
+            Linker::ClangLld => &["-C", "link-arg=-fuse-ld=lld"],
+            Linker::Mold => &["-C", "link-arg=-fuse-ld=mold"],
+        }
+    }
+}
+
+impl fmt::Display for Linker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
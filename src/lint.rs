@@ -0,0 +1,259 @@
+use cargo_metadata::{DependencyKind, Metadata, Package, PackageId};
+use std::collections::{HashMap, VecDeque};
+
+/// Crates which are known to make a static binary harder to get, wherever they appear in the dependency graph
+const BUILD_TOOL_CRATES: &[(&str, &str)] = &[
+    (
+        "pkg-config",
+        "finds native libraries with `pkg-config`, which won't link them statically if they're under `/usr`",
+    ),
+    (
+        "bindgen",
+        "runs `bindgen` at build time, which needs libclang in the build environment",
+    ),
+];
+
+/// Something in a crate's dependency graph which is likely to get in the way of a static binary
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Finding {
+    /// The package the problem is with, as `name vX.Y.Z`
+    pub package: String,
+
+    pub problem: String,
+
+    /// The names of the packages from the root of the graph to the package with the problem, inclusive
+    pub path: Vec<String>,
+}
+
+/// Walk the resolved dependency graph of a crate, from its root package, and flag the dependencies that are known
+/// to cause trouble.
+///
+/// Only normal dependencies end up in the binary, so those are the only edges followed.  The build dependencies of
+/// each of those packages are checked for tools like `pkg-config`, but what the tools themselves link to only matters
+/// to the build environment
+pub(crate) fn lint(metadata: &Metadata) -> Vec<Finding> {
+    let resolve = match &metadata.resolve {
+        Some(resolve) => resolve,
+        None => return Vec::new(),
+    };
+    let root = match &resolve.root {
+        Some(root) => root,
+        None => return Vec::new(),
+    };
+    let packages = metadata
+        .packages
+        .iter()
+        .map(|package| (&package.id, package))
+        .collect::<HashMap<_, _>>();
+    let nodes = resolve
+        .nodes
+        .iter()
+        .map(|node| (&node.id, node))
+        .collect::<HashMap<_, _>>();
+
+    // Breadth first, so the path to each package is one of the shortest
+    let mut parents: HashMap<&PackageId, Option<&PackageId>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    parents.insert(root, None);
+    queue.push_back(root);
+
+    let mut findings = Vec::new();
+    while let Some(id) = queue.pop_front() {
+        let (package, node) = match (packages.get(id), nodes.get(id)) {
+            (Some(package), Some(node)) => (*package, *node),
+            _ => continue,
+        };
+
+        let build_deps = node
+            .deps
+            .iter()
+            .filter(|dep| {
+                dep.dep_kinds
+                    .iter()
+                    .any(|info| info.kind == DependencyKind::Build)
+            })
+            .filter_map(|dep| packages.get(&dep.pkg))
+            .map(|dep| dep.name.as_str())
+            .collect::<Vec<_>>();
+
+        let problems = problems(package, &node.features, &build_deps);
+        if !problems.is_empty() {
+            let path = path_to(id, &parents, &packages);
+            for problem in problems {
+                findings.push(Finding {
+                    package: format!("{} v{}", package.name, package.version),
+                    problem,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        for dep in &node.deps {
+            let is_normal = dep.dep_kinds.is_empty()
+                || dep
+                    .dep_kinds
+                    .iter()
+                    .any(|info| info.kind == DependencyKind::Normal);
+            if is_normal && !parents.contains_key(&dep.pkg) {
+                parents.insert(&dep.pkg, Some(id));
+                queue.push_back(&dep.pkg);
+            }
+        }
+    }
+
+    findings
+}
+
+/// The known problems with a single package
+fn problems(package: &Package, features: &[String], build_deps: &[&str]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Some(links) = &package.links {
+        problems.push(format!("links to the native library `{}`", links));
+    }
+
+    if package.name == "openssl-sys" && !features.iter().any(|feature| feature == "vendored") {
+        problems.push(
+            "doesn't have the `vendored` feature, so it links to the OpenSSL in the build environment"
+                .to_string(),
+        );
+    }
+
+    if package.name == "cpp" {
+        problems.push(
+            "uses the `cpp` crate, which embeds C++ that needs a static libstdc++".to_string(),
+        );
+    }
+
+    for (tool, problem) in BUILD_TOOL_CRATES {
+        if build_deps.contains(tool) {
+            problems.push(problem.to_string());
+        }
+    }
+
+    if build_deps.contains(&"cc") && build_script_compiles_cpp(package) {
+        problems.push("compiles C++ with `cc`, so it needs a static libstdc++".to_string());
+    }
+
+    problems
+}
+
+/// Whether a package's build script tells the `cc` crate to compile C++
+fn build_script_compiles_cpp(package: &Package) -> bool {
+    package
+        .targets
+        .iter()
+        .filter(|target| target.kind.iter().any(|kind| kind == "custom-build"))
+        .filter_map(|target| std::fs::read_to_string(&target.src_path).ok())
+        .any(|source| source.contains(".cpp(true)"))
+}
+
+fn path_to(
+    id: &PackageId,
+    parents: &HashMap<&PackageId, Option<&PackageId>>,
+    packages: &HashMap<&PackageId, &Package>,
+) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut next = Some(id);
+    while let Some(id) = next {
+        path.push(packages[id].name.clone());
+        next = parents.get(id).copied().flatten();
+    }
+    path.reverse();
+
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cargo_metadata::MetadataCommand;
+    use std::path::Path;
+
+    /// Write a crate with a `Cargo.toml` made of `package` (the lines after the name) and `deps`, and a build script
+    /// if `build_rs` is set
+    fn write_crate(dir: &Path, name: &str, package: &str, deps: &str, build_rs: Option<&str>) {
+        let crate_dir = dir.join(name);
+        std::fs::create_dir_all(crate_dir.join("src")).unwrap();
+        std::fs::write(
+            crate_dir.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2018\"\n{}\n\n{}\n",
+                name, package, deps
+            ),
+        )
+        .unwrap();
+        std::fs::write(crate_dir.join("src/lib.rs"), "").unwrap();
+        if let Some(build_rs) = build_rs {
+            std::fs::write(crate_dir.join("build.rs"), build_rs).unwrap();
+        }
+    }
+
+    #[test]
+    fn known_problems_are_flagged_with_their_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_crate(dir, "pkg-config", "", "", None);
+        write_crate(dir, "cc", "", "", None);
+        write_crate(dir, "tester", "", "", None);
+        write_crate(
+            dir,
+            "openssl-sys",
+            "links = \"openssl\"",
+            "[features]\nvendored = []\n\n[build-dependencies]\npkg-config = { path = \"../pkg-config\" }",
+            Some("fn main() {}"),
+        );
+        write_crate(
+            dir,
+            "openssl",
+            "",
+            "[dependencies]\nopenssl-sys = { path = \"../openssl-sys\" }",
+            None,
+        );
+        write_crate(
+            dir,
+            "uses-cpp",
+            "",
+            "[build-dependencies]\ncc = { path = \"../cc\" }",
+            Some("fn main() { cc::Build::new().cpp(true).file(\"a.cpp\").compile(\"a\"); }"),
+        );
+        write_crate(
+            dir,
+            "root",
+            "",
+            "[dependencies]\nopenssl = { path = \"../openssl\" }\nuses-cpp = { path = \"../uses-cpp\" }\n\n\
+             [dev-dependencies]\ntester = { path = \"../tester\" }",
+            None,
+        );
+
+        let metadata = MetadataCommand::new()
+            .manifest_path(dir.join("root/Cargo.toml"))
+            .exec()
+            .unwrap();
+        let findings = lint(&metadata)
+            .into_iter()
+            .map(|finding| (finding.problem, finding.path.join(" -> ")))
+            .collect::<Vec<_>>();
+
+        let openssl_sys_path = "root -> openssl -> openssl-sys".to_string();
+        assert_eq!(
+            vec![
+                (
+                    "compiles C++ with `cc`, so it needs a static libstdc++".to_string(),
+                    "root -> uses-cpp".to_string()
+                ),
+                (
+                    "links to the native library `openssl`".to_string(),
+                    openssl_sys_path.clone()
+                ),
+                (
+                    "doesn't have the `vendored` feature, so it links to the OpenSSL in the build environment"
+                        .to_string(),
+                    openssl_sys_path.clone()
+                ),
+                (BUILD_TOOL_CRATES[0].1.to_string(), openssl_sys_path),
+            ],
+            findings
+        );
+    }
+}
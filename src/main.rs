@@ -1,30 +1,207 @@
+mod assertions;
+mod bisect;
 mod docker;
+mod dynamic_deps;
+mod elf;
 mod environments;
+mod exec;
+#[cfg(test)]
+mod fake_docker;
+mod host;
+mod inventory;
+mod libs;
+mod link_errors;
+mod linkers;
+mod lint;
+mod probe;
+mod profiles;
+mod progress;
+mod runlog;
+mod scaffold;
+mod schema;
+mod sys_crates;
 mod tests;
+mod timeouts;
+mod tui;
 
-use crate::environments::Environment;
+use crate::docker::ContainerDaemon;
+use crate::environments::{Environment, Platform, Target, TargetKind};
 use color_eyre::{
-    eyre::Context,
     eyre::{eyre, WrapErr},
     Report, Result,
 };
-use std::{path::PathBuf, process::exit};
+use profiles::BuildProfile;
+use progress::{
+    Cancel, CellId, CellState, PlainProgress, Progress, QuietProgress, UiMode, Verdict,
+};
+use runlog::RunLog;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
 use structopt::StructOpt;
-use tests::{TestCrate, TestResult};
+use tests::{RunContext, TestResult};
+use timeouts::Timeouts;
 use tracing::*;
 
 #[derive(StructOpt)]
 struct Args {
     /// Specify the environment or environments to test
     ///
-    /// Default is to use all environments
+    /// Default is to use all of the Docker environments; the `host` environment, which runs tests
+    /// directly on this machine without Docker, must be specified explicitly
     #[structopt(long = "environment", possible_values = environments::all_environment_names(), number_of_values = 1)]
     envs: Vec<String>,
 
+    /// Specify the kind of target or targets to build for
+    ///
+    /// Default is every target that each environment supports
+    #[structopt(long = "target", possible_values = TargetKind::NAMES, number_of_values = 1)]
+    targets: Vec<TargetKind>,
+
+    /// Specify the build profile or profiles to build with
+    ///
+    /// Each profile is a separate column of the matrix for every target.  Default is `debug` only
+    #[structopt(long = "profile", possible_values = BuildProfile::NAMES, number_of_values = 1)]
+    profiles: Vec<BuildProfile>,
+
     /// Specific tests to run by name.
     ///
     /// Default is to run all tests
     tests: Vec<String>,
+
+    /// How to show progress while the tests run.
+    ///
+    /// `plain` echoes the output of every command; `tui` shows a live grid of tests and environments, where
+    /// the output of any cell can be viewed by selecting it with the arrow keys and pressing Enter; `quiet`
+    /// prints only a line for each change in status, and writes each cell's output to its own log file
+    #[structopt(long = "ui", default_value = "plain", possible_values = UiMode::NAMES)]
+    ui: UiMode,
+
+    /// Directory in which each run creates its own timestamped log dir, with the output of every step of every test
+    /// and a `run.json` index of the results.
+    ///
+    /// Default is `runs` in the cache dir under the system temp dir
+    #[structopt(long = "log-dir", parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    timeouts: Timeouts,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Things to do other than running the test matrix
+#[derive(StructOpt)]
+enum Command {
+    /// Check the dependency graph of each test for dependencies that are known to get in the way of a static
+    /// binary, without building anything or starting any containers
+    Lint {
+        /// Specific tests to lint by name.
+        ///
+        /// Default is to lint all tests
+        tests: Vec<String>,
+    },
+
+    /// Find which of a crate's dependencies make its binary non-static or fail to link, by building reduced crates
+    /// which depend on subsets of them
+    Bisect {
+        /// The root dir of the crate, where its `Cargo.toml` is
+        #[structopt(parse(from_os_str))]
+        crate_path: PathBuf,
+
+        /// The environment to build in
+        #[structopt(long = "environment", possible_values = environments::all_environment_names())]
+        env: String,
+
+        /// The kind of target to build for.
+        ///
+        /// Default is the first target the environment supports
+        #[structopt(long = "target", possible_values = TargetKind::NAMES)]
+        target: Option<TargetKind>,
+
+        /// The build profile to build with
+        #[structopt(long = "profile", default_value = "debug", possible_values = BuildProfile::NAMES)]
+        profile: BuildProfile,
+    },
+
+    /// Print the env vars to build a crate with in an environment, and the features that could help, from what
+    /// `sys-crates.json` knows about the `-sys` crates in its dependency graph
+    Suggest {
+        /// The root dir of the crate, where its `Cargo.toml` is
+        #[structopt(parse(from_os_str))]
+        crate_path: PathBuf,
+
+        /// The environment the crate will be built in
+        #[structopt(long = "environment", possible_values = environments::all_environment_names())]
+        env: String,
+
+        /// The kind of target the crate will be built for.
+        ///
+        /// Default is the first target the environment supports
+        #[structopt(long = "target", possible_values = TargetKind::NAMES)]
+        target: Option<TargetKind>,
+
+        /// Also add the env vars for every environment to the crate's `Cargo.toml`, as a new
+        /// `[package.metadata.test-crate.env]` table.  This fails if the crate already has test crate metadata
+        #[structopt(long)]
+        write: bool,
+    },
+
+    /// Generate a new test crate in the `crates` dir, with a `main.rs` that links each dependency and the test crate
+    /// metadata, and check that it loads
+    NewTest {
+        /// The name of the crate, like `with-libudev`
+        name: String,
+
+        /// A dependency of the crate, as `crate[@version][[feature,...]]`, like `openssl@0.10[vendored]`
+        #[structopt(long = "dep", number_of_values = 1)]
+        deps: Vec<scaffold::DepArg>,
+
+        /// An env var to build and run the test with, as `NAME=VALUE`
+        #[structopt(long = "env", number_of_values = 1)]
+        env_vars: Vec<String>,
+    },
+
+    /// Check the `[package.metadata.test-crate]` of every test crate without building anything, and report every
+    /// invalid crate with the line of its `Cargo.toml` that has the problem
+    Validate,
+
+    /// Probe each environment and print what's installed in it, and what's missing for each of its targets.
+    ///
+    /// This is the same probe that runs before the tests, which is cached by image ID
+    Doctor {
+        /// Specify the environment or environments to probe
+        ///
+        /// Default is all of the Docker environments, like the test matrix
+        #[structopt(long = "environment", possible_values = environments::all_environment_names(), number_of_values = 1)]
+        envs: Vec<String>,
+
+        /// Probe the Docker environments again, even if their images haven't changed since they were last probed
+        #[structopt(long)]
+        refresh: bool,
+    },
+
+    /// Scan each environment for static libraries and `pkg-config` files, and compare which environments have each
+    /// library.  Useful when a build fails with `cannot find -lfoo`
+    Libs {
+        /// Libraries to look for, by the name they're linked with, like `ssl`.  The `-lssl` form works too, after
+        /// `--`.
+        ///
+        /// Default is every library found in any environment
+        libs: Vec<String>,
+
+        /// Specify the environment or environments to scan
+        ///
+        /// Default is all of the Docker environments, like the test matrix
+        #[structopt(long = "environment", possible_values = environments::all_environment_names(), number_of_values = 1)]
+        envs: Vec<String>,
+
+        /// Scan the Docker environments again, even if their images haven't changed since they were last scanned
+        #[structopt(long)]
+        refresh: bool,
+    },
 }
 
 #[tokio::main]
@@ -32,12 +209,32 @@ async fn main() {
     use tracing_subscriber::EnvFilter;
 
     let args = Args::from_args();
-    tracing_subscriber::fmt()
-        .pretty()
-        .with_env_filter(EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "hyper=warn,debug".to_string()),
-        ))
-        .init();
+    let filter = EnvFilter::new(
+        std::env::var("RUST_LOG").unwrap_or_else(|_| "hyper=warn,debug".to_string()),
+    );
+
+    if args.ui == UiMode::Tui {
+        // The terminal belongs to the UI, so log messages go to a file instead
+        let log_path = std::env::temp_dir().join("rust-static-link-sandbox.log");
+        let log_file = match std::fs::File::create(&log_path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Error creating log file {}: {}", log_path.display(), e);
+                exit(-1);
+            }
+        };
+
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || log_file.try_clone().expect("log file can be cloned"))
+            .with_env_filter(filter)
+            .init();
+    } else {
+        tracing_subscriber::fmt()
+            .pretty()
+            .with_env_filter(filter)
+            .init();
+    }
 
     if let Err(e) = run(args).await {
         error!("{:#?}", e);
@@ -45,9 +242,491 @@ async fn main() {
     }
 }
 
-async fn run(args: Args) -> Result<()> {
+async fn run(mut args: Args) -> Result<()> {
     color_eyre::install()?;
 
+    match args.command.take() {
+        Some(Command::Lint { tests }) => lint_tests(tests),
+        Some(Command::Bisect {
+            crate_path,
+            env,
+            target,
+            profile,
+        }) => bisect_crate(args, crate_path, &env, target, profile).await,
+        Some(Command::Suggest {
+            crate_path,
+            env,
+            target,
+            write,
+        }) => suggest_env(&crate_path, &env, target, write),
+        Some(Command::NewTest {
+            name,
+            deps,
+            env_vars,
+        }) => {
+            let path = scaffold::new_test(&tests::crates_dir(), &name, &deps, &env_vars)?;
+            println!("Created the test crate {}", path.display());
+
+            Ok(())
+        }
+        Some(Command::Validate) => validate_tests(),
+        Some(Command::Doctor { envs, refresh }) => doctor(&envs, refresh).await,
+        Some(Command::Libs {
+            libs,
+            envs,
+            refresh,
+        }) => compare_libs(libs, &envs, refresh).await,
+        None => run_matrix(args).await,
+    }
+}
+
+/// Load every test crate and print whether it's valid, failing if any isn't
+fn validate_tests() -> Result<()> {
+    let results = tests::validate_all_tests()?;
+
+    let mut invalid = 0;
+    for (dir, result) in &results {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        match result {
+            Ok(tests) => println!("{}: ok ({} tests)", name, tests.len()),
+            Err(e) => {
+                invalid += 1;
+                println!("{}: {:#}", name, e);
+            }
+        }
+    }
+
+    if invalid > 0 {
+        return Err(eyre!(
+            "{} of {} test crates are invalid",
+            invalid,
+            results.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Lint every test and print what was found, with the path to each problem dependency
+fn lint_tests(test_names: Vec<String>) -> Result<()> {
+    let tests = if !test_names.is_empty() {
+        tests::load_tests(test_names)?
+    } else {
+        tests::load_all_tests()?
+    };
+
+    for test in &tests {
+        let findings = lint::lint(&test.resolved_metadata()?);
+        if findings.is_empty() {
+            println!("{}: no known problems", test.name());
+            continue;
+        }
+
+        println!("{}:", test.name());
+        for finding in findings {
+            println!("  {}: {}", finding.package, finding.problem);
+            println!("    via {}", finding.path.join(" -> "));
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the suggested env vars and features for a crate, and optionally add the env vars to its `Cargo.toml`
+fn suggest_env(
+    crate_path: &Path,
+    env_name: &str,
+    target: Option<TargetKind>,
+    write: bool,
+) -> Result<()> {
+    let (_, target) = find_target(env_name, target)?;
+
+    let manifest_path = crate_path.join("Cargo.toml");
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(&manifest_path)
+        .other_options(tests::filter_platform())
+        .exec()
+        .wrap_err_with(|| eyre!("Error getting crate metadata for {}", crate_path.display()))?;
+    let suggestion =
+        sys_crates::Suggestion::for_graph(&metadata, &sys_crates::knowledge_base()?, target.kind());
+
+    if suggestion.crates.is_empty() {
+        println!("# None of the crates in sys-crates.json are in the dependency graph");
+        return Ok(());
+    }
+
+    for known in &suggestion.crates {
+        println!("# {}: {}", known.name, known.notes);
+        for hint in &known.features {
+            println!(
+                "#   Consider the `{}` feature of {}, which {}",
+                hint.feature, hint.crate_name, hint.notes
+            );
+        }
+    }
+    for var in suggestion.env_vars(env_name) {
+        println!("{}", var);
+    }
+
+    if write {
+        let manifest = std::fs::read_to_string(&manifest_path)?;
+        if manifest.contains("[package.metadata.test-crate") {
+            return Err(eyre!(
+                "{} already has test crate metadata; add the env vars to it by hand",
+                manifest_path.display()
+            ));
+        }
+
+        let separator = if manifest.ends_with('\n') {
+            "\n"
+        } else {
+            "\n\n"
+        };
+        std::fs::write(
+            &manifest_path,
+            format!("{}{}{}", manifest, separator, suggestion.env_table()),
+        )?;
+        println!("# Added the env vars to {}", manifest_path.display());
+    }
+
+    Ok(())
+}
+
+/// Bisect the dependencies of a crate in one environment, and print what was found
+async fn bisect_crate(
+    args: Args,
+    crate_path: PathBuf,
+    env_name: &str,
+    target: Option<TargetKind>,
+    profile: BuildProfile,
+) -> Result<()> {
+    let (env, target) = find_target(env_name, target)?;
+    let platform = Platform {
+        env,
+        target,
+        profile,
+    };
+
+    let docker = connect_for(&[env]).await?;
+    let cache_dir = cache_dir()?;
+    let capabilities =
+        probe_environments(&[env], docker.as_ref().map(|d| d as _), &cache_dir).await;
+
+    let log = Arc::new(RunLog::create(
+        &args.log_dir.unwrap_or_else(|| cache_dir.join("runs")),
+    )?);
+    info!(log_dir = %log.dir().display(), "Logging this bisection");
+
+    let progress = QuietProgress::new(log.clone());
+    let ctx = RunContext {
+        daemon: docker.as_ref().map(|d| d as _),
+        cache_dir: &cache_dir,
+        timeouts: &args.timeouts,
+        progress: &progress,
+        log: &log,
+        capabilities: &capabilities,
+        cancel: &Cancel::default(),
+    };
+
+    let bisection = bisect::bisect(&ctx, platform, &crate_path).await?;
+    match bisection.symptom {
+        None => println!(
+            "A crate with all of the direct dependencies of {} builds a static binary in {}, so the problem isn't \
+             in the dependencies",
+            crate_path.display(),
+            platform.name()
+        ),
+        Some(symptom) => {
+            println!("In {} the binary {}", platform.name(), symptom);
+            println!(
+                "The smallest set of direct dependencies that does the same: {}",
+                bisection.minimal.join(", ")
+            );
+            if bisection.sys_crates.is_empty() {
+                println!("None of the -sys crates under them does it on its own");
+            } else {
+                println!(
+                    "The -sys crates that do it on their own: {}",
+                    bisection.sys_crates.join(", ")
+                );
+            }
+        }
+    }
+
+    println!("Logs for this bisection are in {}", log.dir().display());
+
+    Ok(())
+}
+
+/// The environment named `env_name` and its target of the kind `kind`, or its first target if no kind is given
+fn find_target(
+    env_name: &str,
+    kind: Option<TargetKind>,
+) -> Result<(&'static Environment, &'static Target)> {
+    let env = Environment::from_name(env_name)
+        .ok_or_else(|| eyre!("Environment name '{}' not valid", env_name))?;
+    let target = env
+        .targets()
+        .iter()
+        .find(|t| kind.is_none_or(|kind| t.kind() == kind))
+        .ok_or_else(|| eyre!("Environment '{}' doesn't support that target", env_name))?;
+
+    Ok((env, target))
+}
+
+/// Connect to Docker if any of the environments need it, so that host-only runs work without Docker
+async fn connect_for(environments: &[&Environment]) -> Result<Option<docker::DockerDaemon>> {
+    if environments.iter().any(|env| env.uses_docker()) {
+        Ok(Some(docker::connect_docker().await?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Where the cargo caches, probe results and run logs are kept from one run to the next
+fn cache_dir() -> Result<PathBuf> {
+    let cache_dir = std::env::temp_dir().join("rust-static-link-sandbox");
+    std::fs::create_dir_all(&cache_dir)?;
+
+    Ok(cache_dir)
+}
+
+/// The environments named on the command line, or the default environments if there are none
+fn select_environments(env_names: &[String]) -> Result<Vec<&'static Environment>> {
+    if env_names.is_empty() {
+        return Ok(environments::default_environments().collect());
+    }
+
+    env_names
+        .iter()
+        .map(|env_name| {
+            Environment::from_name(env_name)
+                .ok_or_else(|| eyre!("Environment name '{}' not valid", env_name))
+        })
+        .collect()
+}
+
+/// Probe each environment before running any tests in it.  An environment that can't be probed is left out, so its
+/// tests run without a preflight check and fail however they fail
+async fn probe_environments(
+    environments: &[&Environment],
+    daemon: Option<&dyn ContainerDaemon>,
+    cache_dir: &Path,
+) -> BTreeMap<String, probe::Capabilities> {
+    let mut capabilities = BTreeMap::new();
+    for env in environments {
+        match probe::probe(env, daemon, cache_dir, false).await {
+            Ok((_, found)) => {
+                debug!(env = env.name(), ?found, "Probed environment");
+                capabilities.insert(env.name().to_string(), found);
+            }
+            Err(e) => warn!(env = env.name(), "Error probing environment: {:?}", e),
+        }
+    }
+
+    capabilities
+}
+
+/// Scan each environment for static libraries, returning the scans and the environments that couldn't be scanned
+async fn scan_libs(
+    environments: &[&'static Environment],
+    daemon: Option<&dyn ContainerDaemon>,
+    cache_dir: &Path,
+    refresh: bool,
+) -> (
+    Vec<(&'static Environment, libs::LibScan)>,
+    Vec<(&'static str, Report)>,
+) {
+    let mut scans = Vec::new();
+    let mut failures = Vec::new();
+    for env in environments {
+        let scanned = libs::LibScan::scan()
+            .run(env, daemon, cache_dir, refresh, libs::LibScan::parse)
+            .await;
+        match scanned {
+            Ok((_, scan)) => scans.push((*env, scan)),
+            Err(e) => failures.push((env.name(), e)),
+        }
+    }
+
+    (scans, failures)
+}
+
+/// Probe environments and print what was found, failing if any of them couldn't be probed
+async fn doctor(env_names: &[String], refresh: bool) -> Result<()> {
+    let environments = select_environments(env_names)?;
+    let docker = connect_for(&environments).await?;
+    let cache_dir = cache_dir()?;
+
+    let mut failed = 0;
+    for env in &environments {
+        let (image_id, capabilities) =
+            match probe::probe(env, docker.as_ref().map(|d| d as _), &cache_dir, refresh).await {
+                Ok(probed) => probed,
+                Err(e) => {
+                    failed += 1;
+                    println!("{}: {:#}\n", env.name(), e);
+                    continue;
+                }
+            };
+
+        match image_id {
+            Some(image_id) => println!("{} (image {})", env.name(), image_id),
+            None => println!("{}", env.name()),
+        }
+
+        let none = || "-".to_string();
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                none()
+            } else {
+                items.join(", ")
+            }
+        };
+        let programs = probe::PROGRAMS
+            .iter()
+            .map(|program| match capabilities.programs.get(*program) {
+                Some(path) => format!("{} ({})", program, path),
+                None => format!("{} (missing)", program),
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "  {:<18}{}",
+            "rustc",
+            capabilities.rustc.clone().unwrap_or_else(none)
+        );
+        println!(
+            "  {:<18}{}",
+            "cargo",
+            capabilities.cargo.clone().unwrap_or_else(none)
+        );
+        println!("  {:<18}{}", "targets", list(&capabilities.targets));
+        println!(
+            "  {:<18}{}",
+            "libc",
+            capabilities.libc.clone().unwrap_or_else(none)
+        );
+        println!(
+            "  {:<18}{}",
+            "pkg-config path",
+            list(&capabilities.pkg_config_path)
+        );
+        println!(
+            "  {:<18}{}",
+            "/opt/musl/lib",
+            list(&capabilities.opt_musl_libs)
+        );
+        println!(
+            "  {:<18}{}",
+            "libclang",
+            capabilities.libclang.clone().unwrap_or_else(none)
+        );
+        println!("  {:<18}{}", "programs", programs.join(", "));
+
+        // What a test with no special requirements would be missing for each target
+        for target in env.targets() {
+            let requirements = probe::Requirements {
+                triple: target.triple().to_string(),
+                programs: std::iter::once("readelf")
+                    .chain(target.runner())
+                    .map(str::to_string)
+                    .collect(),
+                libclang: false,
+                unsupported: Vec::new(),
+            };
+            let missing = capabilities.missing(&requirements);
+            let status = if missing.is_empty() {
+                "ok".to_string()
+            } else {
+                missing.join("; ")
+            };
+            println!("  {:<18}{}", target.kind().name(), status);
+        }
+        println!();
+    }
+
+    if failed > 0 {
+        return Err(eyre!(
+            "{} of {} environments couldn't be probed",
+            failed,
+            environments.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Scan environments for static libraries and `.pc` files and print how they compare, failing if any of them
+/// couldn't be scanned
+async fn compare_libs(libs: Vec<String>, env_names: &[String], refresh: bool) -> Result<()> {
+    let libs = libs
+        .into_iter()
+        .map(|lib| lib.strip_prefix("-l").map(str::to_string).unwrap_or(lib))
+        .collect::<Vec<_>>();
+
+    let environments = select_environments(env_names)?;
+    let docker = connect_for(&environments).await?;
+    let cache_dir = cache_dir()?;
+
+    let (scans, failures) = scan_libs(
+        &environments,
+        docker.as_ref().map(|d| d as _),
+        &cache_dir,
+        refresh,
+    )
+    .await;
+    for (env_name, e) in &failures {
+        println!("{}: {:#}\n", env_name, e);
+    }
+    let scans = scans
+        .into_iter()
+        .map(|(env, scan)| (env.name(), scan))
+        .collect::<Vec<_>>();
+
+    print!("{}", libs::comparison_table(&scans, &libs));
+    println!();
+    println!(
+        "a: there's a static library; pc: a .pc file links it; pc!: the .pc file's libdir is under /usr, so the \
+         pkg-config crate links it dynamically"
+    );
+
+    // Where exactly each of the requested libraries is
+    for lib in &libs {
+        println!();
+        println!("{}:", lib);
+        for (env_name, scan) in &scans {
+            let mut found = scan
+                .archives_of(lib)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            found.extend(scan.pc_files_of(lib).into_iter().map(|pc_file| {
+                format!(
+                    "{} (libdir {})",
+                    pc_file.path,
+                    pc_file.libdir.as_deref().unwrap_or("-")
+                )
+            }));
+            if found.is_empty() {
+                println!("  {:<22}-", env_name);
+            } else {
+                println!("  {:<22}{}", env_name, found.join(", "));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(eyre!(
+            "{} of {} environments couldn't be scanned",
+            failures.len(),
+            environments.len()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn run_matrix(args: Args) -> Result<()> {
     let tests = if !args.tests.is_empty() {
         // caller specified some tests by name so only run those (if they're valid)
         tests::load_tests(args.tests)?
@@ -55,51 +734,266 @@ async fn run(args: Args) -> Result<()> {
         tests::load_all_tests()?
     };
 
-    let environments: Vec<&Environment> = if !args.envs.is_empty() {
-        args.envs
-            .iter()
-            .map(|env_name| {
-                Environment::from_name(env_name)
-                    .ok_or_else(|| eyre!("Environment name '{}' not valid", env_name))
-            })
-            .collect::<Result<Vec<_>>>()?
+    let environments = select_environments(&args.envs)?;
+
+    // The columns of the test matrix are every requested kind of target in every environment that supports it, built
+    // with each requested profile
+    let targets = &args.targets;
+    let profiles = if args.profiles.is_empty() {
+        vec![BuildProfile::Debug]
     } else {
-        environments::all_environments().iter().collect()
+        args.profiles.clone()
     };
+    let profiles = &profiles;
+    let platforms = environments
+        .iter()
+        .flat_map(|env| {
+            env.targets()
+                .iter()
+                .filter(move |target| targets.is_empty() || targets.contains(&target.kind()))
+                .flat_map(move |target| {
+                    profiles.iter().map(move |&profile| Platform {
+                        env,
+                        target,
+                        profile,
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+    if platforms.is_empty() {
+        return Err(eyre!(
+            "None of the environments support any of the requested targets"
+        ));
+    }
 
-    let docker = docker::connect_docker().await?;
-    let cache_dir = std::env::temp_dir().join("rust-static-link-sandbox");
-    std::fs::create_dir_all(&cache_dir)?;
+    let docker = connect_for(&environments).await?;
+    let cache_dir = cache_dir()?;
+    let capabilities =
+        probe_environments(&environments, docker.as_ref().map(|d| d as _), &cache_dir).await;
+
+    let log = Arc::new(RunLog::create(
+        &args.log_dir.unwrap_or_else(|| cache_dir.join("runs")),
+    )?);
+    info!(log_dir = %log.dir().display(), "Logging this run");
 
-    for test in tests {
+    // Aborting stops the cell that's running, but only once its container or processes are cleaned up.  The TUI gets
+    // Ctrl-C as a key press rather than a signal, so it cancels the run itself
+    let cancel = Cancel::default();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        }
+    });
+
+    let progress: Box<dyn Progress> = match args.ui {
+        UiMode::Plain => Box::new(PlainProgress),
+        UiMode::Quiet => Box::new(QuietProgress::new(log.clone())),
+        UiMode::Tui => Box::new(tui::TuiProgress::start(
+            tests.iter().map(|test| test.name().to_string()).collect(),
+            platforms.iter().map(|platform| platform.name()).collect(),
+            cancel.clone(),
+        )?),
+    };
+
+    for test in &tests {
+        for platform in &platforms {
+            progress.state(
+                &CellId::new(test.name(), &platform.name()),
+                CellState::Queued,
+            );
+        }
+    }
+
+    let ctx = RunContext {
+        daemon: docker.as_ref().map(|d| d as _),
+        cache_dir: &cache_dir,
+        timeouts: &args.timeouts,
+        progress: progress.as_ref(),
+        log: &log,
+        capabilities: &capabilities,
+        cancel: &cancel,
+    };
+
+    // The static libraries in each environment of this run, which are only scanned once a binary needs a shared
+    // library that there's no static archive of in its own environment
+    let mut lib_scans = None;
+
+    'tests: for test in &tests {
         let span = info_span!("test case", test = test.name());
         let _guard = span.enter();
 
         info!("Starting tests");
 
-        for env in &environments {
-            let span = info_span!("env", env = env.name());
+        for platform in &platforms {
+            let span = info_span!("env",
+                env = platform.env.name(),
+                target = %platform.target.kind(),
+                profile = %platform.profile);
             let _guard = span.enter();
 
-            match test.run_test(&docker, &cache_dir, env).await {
+            let cell = CellId::new(test.name(), &platform.name());
+            let mut result = test.run_test(&ctx, *platform).await;
+            if let Ok(TestResult::NonStaticBinary { deps }) = &mut result {
+                if deps.iter().any(|dep| dep.static_alternative.is_none()) {
+                    if lib_scans.is_none() {
+                        let (scans, failures) = scan_libs(
+                            &environments,
+                            docker.as_ref().map(|d| d as _),
+                            &cache_dir,
+                            false,
+                        )
+                        .await;
+                        for (env_name, e) in failures {
+                            warn!(env = env_name, "Error scanning environment: {:?}", e);
+                        }
+                        lib_scans = Some(scans);
+                    }
+                    if let Some(scans) = &lib_scans {
+                        dynamic_deps::suggest_other_environments(
+                            deps,
+                            platform.target.triple(),
+                            scans,
+                        );
+                    }
+                }
+            }
+            log.finish_cell(&cell, &result);
+            progress.state(&cell, CellState::Done(Verdict::of(&result)));
+
+            match result {
                 Ok(TestResult::StaticBinary) => {
                     info!("Yay!  Resulting binary is static!");
                 }
+                Ok(TestResult::StaticWithCaveats { caveats }) => {
+                    warn!(
+                        "Resulting binary is static, but still needs shared libraries at runtime: \n * {}",
+                        caveats.join("\n * ")
+                    );
+                }
                 Ok(TestResult::NonStaticBinary { deps }) => {
+                    let deps = deps.iter().map(|dep| dep.to_string()).collect::<Vec<_>>();
                     warn!(
                         "Meh.  Resulting binary is not static: \n * {}",
                         deps.join("\n * ")
                     );
                 }
-                Ok(TestResult::Failed { output }) => {
-                    error!("Build failed: \n{}", output);
+                Ok(TestResult::Misbehaves { problems, log }) => {
+                    error!(
+                        "The binary runs, but misbehaves: \n * {}\nSee the log at {}",
+                        problems.join("\n * "),
+                        log.display()
+                    );
+                }
+                Ok(TestResult::Failed { step, message, log }) => {
+                    error!(
+                        "Failed in the {} step: {}\nSee the log at {}",
+                        step,
+                        message,
+                        log.display()
+                    );
+                }
+                Ok(TestResult::LinkFailed { errors, log }) => {
+                    let errors = errors
+                        .iter()
+                        .map(|error| error.to_string())
+                        .collect::<Vec<_>>();
+                    error!(
+                        "Failed to link, because: \n * {}\nSee the log at {}",
+                        errors.join("\n * "),
+                        log.display()
+                    );
+                }
+                Ok(TestResult::TimedOut { step, after, log }) => {
+                    error!(
+                        "Timed out in the {} step after {:?}\nSee the log at {}",
+                        step,
+                        after,
+                        log.display()
+                    );
+                }
+                Ok(TestResult::MissingRequirements { missing }) => {
+                    error!(
+                        "Not attempted, because the environment is missing what the test needs: \n * {}",
+                        missing.join("\n * ")
+                    );
                 }
                 Err(e) => {
                     error!("Couldn't attempt the build: \n{:?}", e)
                 }
             }
+
+            if cancel.is_cancelled() {
+                break 'tests;
+            }
         }
     }
 
+    progress.finish();
+
+    if cancel.is_cancelled() {
+        eprintln!("Aborted");
+        exit(130);
+    }
+
+    // Size and build time vary a lot between profiles, so summarize them for every cell that got that far
+    println!(
+        "{:<30} {:<48} {:>12} {:>10}",
+        "TEST", "PLATFORM", "BINARY SIZE", "BUILD TIME"
+    );
+    for test in &tests {
+        for platform in &platforms {
+            let cell = CellId::new(test.name(), &platform.name());
+            let (build_time, binary_size) = log.build_stats(&cell);
+            if build_time.is_none() && binary_size.is_none() {
+                continue;
+            }
+
+            println!(
+                "{:<30} {:<48} {:>12} {:>10}",
+                cell.test,
+                cell.env,
+                binary_size
+                    .map(|size| size.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                build_time
+                    .map(|time| format!("{:.1}s", time.as_secs_f64()))
+                    .unwrap_or_else(|| "-".to_string()),
+            );
+        }
+    }
+
+    // Where each native library came from, and whether it's embedded in the binary or needed at runtime
+    let mut printed_header = false;
+    for test in &tests {
+        for platform in &platforms {
+            let cell = CellId::new(test.name(), &platform.name());
+            for lib in log.native_libs(&cell) {
+                if !printed_header {
+                    println!();
+                    println!(
+                        "{:<30} {:<48} {:<16} {:<20} {:<28} LINKED",
+                        "TEST", "PLATFORM", "NATIVE LIB", "PACKAGE", "FROM"
+                    );
+                    printed_header = true;
+                }
+
+                println!(
+                    "{:<30} {:<48} {:<16} {:<20} {:<28} {}",
+                    cell.test,
+                    cell.env,
+                    lib.name,
+                    lib.package,
+                    lib.source.to_string(),
+                    lib.linkage
+                );
+            }
+        }
+    }
+
+    println!("Logs for this run are in {}", log.dir().display());
+
     Ok(())
 }
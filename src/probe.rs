@@ -0,0 +1,470 @@
+use crate::docker::{ContainerDaemon, ContainerSpec};
+use crate::environments::{Backend, Environment};
+use crate::exec::{ExecOutput, ExecRequest, OutputSink};
+use crate::host::HostSandbox;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::*;
+
+/// The programs a probe looks for: the C compiler and linker drivers a test can be built with, the runners for
+/// emulated targets, the tools some `-sys` crates run, and `readelf` for the analyze step
+pub(crate) const PROGRAMS: &[&str] = &[
+    "cc",
+    "musl-gcc",
+    "clang",
+    "ld.lld",
+    "mold",
+    "pkg-config",
+    "qemu-aarch64",
+    "readelf",
+];
+
+/// How long the probe script may run
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What's installed in an environment, as far as it matters for building static binaries
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Capabilities {
+    /// `rustc --version`, if there's a `rustc` at all
+    pub rustc: Option<String>,
+
+    /// `cargo --version`
+    pub cargo: Option<String>,
+
+    /// The targets with a standard library in the sysroot.  This is what `rustup target list --installed` says,
+    /// but it works for a distro's Rust without rustup too
+    pub targets: Vec<String>,
+
+    /// The libc that `ldd` belongs to, like `glibc 2.31` or `musl 1.2.2`
+    pub libc: Option<String>,
+
+    /// Where `pkg-config` looks for `.pc` files: `PKG_CONFIG_PATH`, then its built-in path
+    pub pkg_config_path: Vec<String>,
+
+    /// The static libraries in `/opt/musl/lib`, where the `debian-static-libs` image builds its libraries from source
+    pub opt_musl_libs: Vec<String>,
+
+    /// The `libclang` shared library that `bindgen` would load, if there is one
+    pub libclang: Option<String>,
+
+    /// The path of each of [`PROGRAMS`] that was found
+    pub programs: BTreeMap<String, String>,
+}
+
+/// What a test needs from an environment to have any chance of building and running on a platform
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Requirements {
+    /// The target triple to build for
+    pub triple: String,
+
+    /// Programs the build and the test steps run
+    pub programs: Vec<String>,
+
+    /// A crate in the dependency graph runs `bindgen`, which loads `libclang`
+    pub libclang: bool,
+
+    /// Why the test can't be built for the target no matter what the environment has, like a linker setup that
+    /// can't link for it
+    pub unsupported: Vec<String>,
+}
+
+impl Capabilities {
+    /// What's missing for `requirements`, each described for a person.  Only things the probe looks for can be
+    /// missing; a program that isn't one of [`PROGRAMS`] is assumed to be there
+    pub fn missing(&self, requirements: &Requirements) -> Vec<String> {
+        let mut missing = requirements.unsupported.clone();
+
+        if self.rustc.is_none() {
+            missing.push("rustc isn't installed".to_string());
+        } else if !self.targets.contains(&requirements.triple) {
+            missing.push(format!(
+                "the {} target isn't installed",
+                requirements.triple
+            ));
+        }
+
+        for program in &requirements.programs {
+            if PROGRAMS.contains(&program.as_str()) && !self.programs.contains_key(program) {
+                missing.push(format!("`{}` isn't installed", program));
+            }
+        }
+
+        if requirements.libclang && self.libclang.is_none() {
+            missing.push("bindgen needs libclang, which isn't installed".to_string());
+        }
+
+        missing
+    }
+
+    /// The script that prints everything a probe looks for, in sections that [`Capabilities::parse`] understands
+    fn script() -> String {
+        format!(
+            r#"echo '== rustc'; rustc --version 2>/dev/null
+echo '== cargo'; cargo --version 2>/dev/null
+echo '== targets'; sysroot=$(rustc --print sysroot 2>/dev/null) && for lib in "$sysroot"/lib/rustlib/*/lib; do basename "$(dirname "$lib")"; done
+echo '== libc'; ldd --version 2>&1 | head -n 2
+echo '== pkg-config-path'; {{ echo "$PKG_CONFIG_PATH"; pkg-config --variable pc_path pkg-config 2>/dev/null; }} | tr ':' '\n'
+echo '== opt-musl-libs'; ls /opt/musl/lib 2>/dev/null | grep '\.a$'
+echo '== libclang'; for dir in ${{LIBCLANG_PATH:-/nonexistent}} /usr/lib /usr/lib/llvm*/lib /usr/lib/*-linux-* /usr/local/lib; do ls "$dir"/libclang.so* "$dir"/libclang-*.so* 2>/dev/null; done | grep -v libclang-cpp
+echo '== programs'; for program in {}; do path=$(command -v "$program") && echo "$program $path"; done
+true"#,
+            PROGRAMS.join(" ")
+        )
+    }
+
+    /// Parse the output of [`Capabilities::script`]
+    pub fn parse(output: &str) -> Self {
+        let mut capabilities = Self::default();
+
+        let mut section = "";
+        for line in output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            if let Some(name) = line.strip_prefix("== ") {
+                section = name;
+                continue;
+            }
+
+            match section {
+                "rustc" => capabilities.rustc = Some(line.to_string()),
+                "cargo" => capabilities.cargo = Some(line.to_string()),
+                "targets" => capabilities.targets.push(line.to_string()),
+                "libc" => {
+                    capabilities.libc = Some(match capabilities.libc.take() {
+                        None => parse_libc(line),
+                        // musl's `ldd` has its version on the second line
+                        Some(libc) => match line.strip_prefix("Version ") {
+                            Some(version) if libc == "musl" => format!("musl {}", version),
+                            _ => libc,
+                        },
+                    })
+                }
+                "pkg-config-path" => capabilities.pkg_config_path.push(line.to_string()),
+                "opt-musl-libs" => capabilities.opt_musl_libs.push(line.to_string()),
+                "libclang" => {
+                    capabilities
+                        .libclang
+                        .get_or_insert_with(|| line.to_string());
+                }
+                "programs" => {
+                    if let Some((program, path)) = line.split_once(' ') {
+                        capabilities
+                            .programs
+                            .insert(program.to_string(), path.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        capabilities
+    }
+}
+
+/// The libc from the first line of `ldd --version`
+fn parse_libc(line: &str) -> String {
+    if line.contains("musl") {
+        "musl".to_string()
+    } else if line.contains("GLIBC") || line.contains("GNU libc") {
+        // Like `ldd (Debian GLIBC 2.31-13+deb11u5) 2.31`
+        let version = line.split_whitespace().last().unwrap_or_default();
+        format!("glibc {}", version)
+    } else {
+        line.to_string()
+    }
+}
+
+/// Discards the output of the probe script as it runs; only the complete output matters
+struct Discard;
+
+impl OutputSink for Discard {
+    fn stdout(&self, _chunk: &[u8]) {}
+
+    fn stderr(&self, _chunk: &[u8]) {}
+}
+
+/// Probe an environment.  The probe of a Docker environment runs in a container of its own, and is cached in
+/// `cache_dir` by image ID, so it only runs again when the image is rebuilt or `refresh` is set.  The host is
+/// probed every time, since there's no telling what's changed.
+///
+/// Returns the image ID for a Docker environment along with what was found
+pub(crate) async fn probe(
+    env: &Environment,
+    daemon: Option<&dyn ContainerDaemon>,
+    cache_dir: &Path,
+    refresh: bool,
+) -> Result<(Option<String>, Capabilities)> {
+    let scan = Scan {
+        kind: "probes",
+        script: Capabilities::script(),
+        timeout: PROBE_TIMEOUT,
+    };
+
+    scan.run(env, daemon, cache_dir, refresh, Capabilities::parse)
+        .await
+}
+
+/// A script that finds out something about an environment without building anything, like the probe
+pub(crate) struct Scan {
+    /// What the script finds out, which names the cache dir for its results
+    pub kind: &'static str,
+
+    pub script: String,
+
+    pub timeout: Duration,
+}
+
+impl Scan {
+    /// Run the script in an environment and `parse` its output.  The results for a Docker environment are cached in
+    /// `cache_dir` by image ID, unless `refresh` is set; the host always runs the script again.
+    ///
+    /// Returns the image ID for a Docker environment along with the results
+    pub async fn run<T, F>(
+        &self,
+        env: &Environment,
+        daemon: Option<&dyn ContainerDaemon>,
+        cache_dir: &Path,
+        refresh: bool,
+        parse: F,
+    ) -> Result<(Option<String>, T)>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce(&str) -> T,
+    {
+        let request = ExecRequest::shell(self.script.clone()).timeout(self.timeout);
+
+        let output = match env.backend() {
+            Backend::Docker { .. } => {
+                let daemon = daemon.ok_or_else(|| {
+                    eyre!(
+                        "Environment '{}' requires Docker but there is no Docker connection",
+                        env.name()
+                    )
+                })?;
+                let image_id = daemon.find_image_by_label(env.name()).await?;
+
+                let cache_path = cache_dir
+                    .join(self.kind)
+                    .join(format!("{}.json", image_id.replace(':', "-")));
+                if !refresh {
+                    if let Some(results) = std::fs::read_to_string(&cache_path)
+                        .ok()
+                        .and_then(|json| serde_json::from_str(&json).ok())
+                    {
+                        debug!(env = env.name(), %image_id, kind = self.kind, "Using cached results");
+                        return Ok((Some(image_id), results));
+                    }
+                }
+
+                let output = run_in_container(daemon, &image_id, &request).await?;
+                let results = parse(&checked(env, output)?);
+
+                std::fs::create_dir_all(cache_path.parent().unwrap())?;
+                std::fs::write(&cache_path, serde_json::to_string_pretty(&results)?)
+                    .wrap_err_with(|| eyre!("Error writing {}", cache_path.display()))?;
+
+                return Ok((Some(image_id), results));
+            }
+            Backend::Host => {
+                let target_dir = cache_dir.join("host-target").join(self.kind);
+                let sandbox = HostSandbox::new(&std::env::temp_dir(), target_dir, &[] as &[&str])?;
+                sandbox.exec(&request, &Discard).await?
+            }
+        };
+
+        Ok((None, parse(&checked(env, output)?)))
+    }
+}
+
+/// Run a script in a new container from an image, which is always deleted afterwards
+async fn run_in_container(
+    daemon: &dyn ContainerDaemon,
+    image_id: &str,
+    request: &ExecRequest,
+) -> Result<ExecOutput> {
+    let spec = ContainerSpec {
+        working_dir: "/".to_string(),
+        ..Default::default()
+    };
+    let container_id = daemon.launch_container(image_id, &spec).await?;
+
+    let output = daemon.exec(&container_id, request, &Discard).await;
+
+    let _ = daemon.stop_container(&container_id).await.map_err(|e| {
+        error!(%container_id, "Error stopping container: {}\nStop and delete this container manually", e);
+    });
+    let _ = daemon.delete_container(&container_id).await.map_err(|e| {
+        error!(%container_id, "Error deleting container: {}\nDelete this container manually", e);
+    });
+
+    output
+}
+
+/// The stdout of a script, if it ran to completion
+fn checked(env: &Environment, output: ExecOutput) -> Result<String> {
+    if output.succeeded() {
+        Ok(output.stdout)
+    } else if output.timed_out {
+        Err(eyre!("Scanning '{}' timed out", env.name()))
+    } else {
+        Err(eyre!(
+            "Scanning '{}' failed with exit code {}:\n{}",
+            env.name(),
+            output.exit_code,
+            output.combined
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake_docker::FakeDaemon;
+
+    const DEBIAN_STATIC_LIBS: &str = "== rustc\n\
+                                      rustc 1.56.0 (09c42c458 2021-10-18)\n\
+                                      == cargo\n\
+                                      cargo 1.56.0 (4ed5d137b 2021-10-04)\n\
+                                      == targets\n\
+                                      x86_64-unknown-linux-gnu\n\
+                                      x86_64-unknown-linux-musl\n\
+                                      == libc\n\
+                                      ldd (Debian GLIBC 2.31-13+deb11u2) 2.31\n\
+                                      Copyright (C) 2020 Free Software Foundation, Inc.\n\
+                                      == pkg-config-path\n\
+                                      \n\
+                                      /usr/local/lib/x86_64-linux-gnu/pkgconfig\n\
+                                      /usr/lib/pkgconfig\n\
+                                      == opt-musl-libs\n\
+                                      libcrypto.a\n\
+                                      libssl.a\n\
+                                      == libclang\n\
+                                      == programs\n\
+                                      cc /usr/bin/cc\n\
+                                      musl-gcc /usr/bin/musl-gcc\n\
+                                      readelf /usr/bin/readelf\n";
+
+    #[test]
+    fn probe_output_is_parsed() {
+        let capabilities = Capabilities::parse(DEBIAN_STATIC_LIBS);
+
+        assert_eq!(
+            Some("rustc 1.56.0 (09c42c458 2021-10-18)"),
+            capabilities.rustc.as_deref()
+        );
+        assert_eq!(
+            vec!["x86_64-unknown-linux-gnu", "x86_64-unknown-linux-musl"],
+            capabilities.targets
+        );
+        assert_eq!(Some("glibc 2.31"), capabilities.libc.as_deref());
+        assert_eq!(
+            vec![
+                "/usr/local/lib/x86_64-linux-gnu/pkgconfig",
+                "/usr/lib/pkgconfig"
+            ],
+            capabilities.pkg_config_path
+        );
+        assert_eq!(vec!["libcrypto.a", "libssl.a"], capabilities.opt_musl_libs);
+        assert_eq!(None, capabilities.libclang);
+        assert_eq!(
+            Some("/usr/bin/musl-gcc"),
+            capabilities.programs.get("musl-gcc").map(|p| p.as_str())
+        );
+
+        let alpine = Capabilities::parse(
+            "== libc\nmusl libc (x86_64)\nVersion 1.2.2\n== libclang\n/usr/lib/libclang.so.12\n",
+        );
+        assert_eq!(Some("musl 1.2.2"), alpine.libc.as_deref());
+        assert_eq!(Some("/usr/lib/libclang.so.12"), alpine.libclang.as_deref());
+    }
+
+    #[test]
+    fn missing_requirements_are_described() {
+        let capabilities = Capabilities::parse(DEBIAN_STATIC_LIBS);
+        let requirements = |triple: &str, programs: &[&str], libclang| Requirements {
+            triple: triple.to_string(),
+            programs: programs.iter().map(|p| p.to_string()).collect(),
+            libclang,
+            unsupported: Vec::new(),
+        };
+
+        assert!(capabilities
+            .missing(&requirements(
+                "x86_64-unknown-linux-musl",
+                &["musl-gcc", "readelf", "bash"],
+                false
+            ))
+            .is_empty());
+        assert_eq!(
+            vec![
+                "the aarch64-unknown-linux-musl target isn't installed",
+                "`qemu-aarch64` isn't installed",
+                "bindgen needs libclang, which isn't installed",
+            ],
+            capabilities.missing(&requirements(
+                "aarch64-unknown-linux-musl",
+                &["qemu-aarch64"],
+                true
+            ))
+        );
+        assert_eq!(
+            vec!["rustc isn't installed"],
+            Capabilities::default().missing(&requirements("x86_64-unknown-linux-musl", &[], false))
+        );
+    }
+
+    #[tokio::test]
+    async fn host_is_probed() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let env = Environment::from_name("host").unwrap();
+
+        let (image_id, capabilities) = probe(env, None, cache_dir.path(), false).await.unwrap();
+
+        // Whatever else is installed, the toolchain running these tests is
+        assert_eq!(None, image_id);
+        assert!(capabilities.rustc.is_some());
+        assert!(capabilities.cargo.is_some());
+        assert!(capabilities
+            .targets
+            .contains(&"x86_64-unknown-linux-gnu".to_string()));
+    }
+
+    #[tokio::test]
+    async fn docker_probes_are_cached_by_image() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let env = Environment::from_name("debian-static-libs").unwrap();
+        let daemon = FakeDaemon::new().with_image("debian-static-libs").on_exec(
+            &["bash"],
+            0,
+            DEBIAN_STATIC_LIBS,
+        );
+
+        let (image_id, first) = probe(env, Some(&daemon), cache_dir.path(), false)
+            .await
+            .unwrap();
+        let (_, second) = probe(env, Some(&daemon), cache_dir.path(), false)
+            .await
+            .unwrap();
+
+        assert!(image_id.is_some());
+        assert_eq!(first, second);
+        assert_eq!(Capabilities::parse(DEBIAN_STATIC_LIBS), second);
+        let calls = daemon.calls();
+        assert_eq!(1, calls.launched.len());
+        assert_eq!(calls.launched.len(), calls.deleted.len());
+
+        probe(env, Some(&daemon), cache_dir.path(), true)
+            .await
+            .unwrap();
+        assert_eq!(2, daemon.calls().launched.len());
+    }
+}
@@ -0,0 +1,77 @@
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// The cargo build profiles a test can be built with.
+///
+/// Optimized builds go down different paths in the linker than debug builds, so problems like duplicate symbols
+/// between C libraries can show up only in some of them.  The profile settings are passed with cargo's
+/// `CARGO_PROFILE_RELEASE_*` env vars, so they apply without any changes to the test crates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BuildProfile {
+    /// A plain `cargo build`
+    Debug,
+
+    /// `cargo build --release`
+    Release,
+
+    /// A release build with fat LTO and a single codegen unit, which is how we build what we ship
+    ReleaseLto,
+
+    /// A release build which aborts on panic instead of unwinding
+    PanicAbort,
+}
+
+impl BuildProfile {
+    pub const NAMES: &'static [&'static str] = &["debug", "release", "release-lto", "panic-abort"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildProfile::Debug => "debug",
+            BuildProfile::Release => "release",
+            BuildProfile::ReleaseLto => "release-lto",
+            BuildProfile::PanicAbort => "panic-abort",
+        }
+    }
+
+    /// Args for `cargo build` and `cargo run` to use this profile
+    pub fn cargo_args(&self) -> &'static [&'static str] {
+        match self {
+            BuildProfile::Debug => &[],
+            _ => &["--release"],
+        }
+    }
+
+    /// Env vars which override the settings of the release profile, in `NAME=VALUE` form
+    pub fn env_vars(&self) -> &'static [&'static str] {
+        match self {
+            BuildProfile::Debug | BuildProfile::Release => &[],
+            BuildProfile::ReleaseLto => &[
+                "CARGO_PROFILE_RELEASE_LTO=fat",
+                "CARGO_PROFILE_RELEASE_CODEGEN_UNITS=1",
+            ],
+            BuildProfile::PanicAbort => &["CARGO_PROFILE_RELEASE_PANIC=abort"],
+        }
+    }
+}
+
+impl fmt::Display for BuildProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BuildProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(BuildProfile::Debug),
+            "release" => Ok(BuildProfile::Release),
+            "release-lto" => Ok(BuildProfile::ReleaseLto),
+            "panic-abort" => Ok(BuildProfile::PanicAbort),
+            other => Err(format!("'{}' is not a valid profile", other)),
+        }
+    }
+}
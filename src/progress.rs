@@ -0,0 +1,304 @@
+use crate::exec::{EchoOutput, OutputSink};
+use crate::runlog::RunLog;
+use crate::tests::TestResult;
+use crate::timeouts::Step;
+use color_eyre::Result;
+use serde::Serialize;
+#[cfg(test)]
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tracing::*;
+
+/// Identifies one cell of the test matrix: one test in one environment
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CellId {
+    pub test: String,
+    pub env: String,
+}
+
+impl CellId {
+    pub fn new(test: &str, env: &str) -> Self {
+        Self {
+            test: test.to_string(),
+            env: env.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CellId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {}", self.test, self.env)
+    }
+}
+
+/// Where a cell is in its lifecycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CellState {
+    /// Waiting for its turn
+    Queued,
+
+    /// Preparing the environment, such as launching the container
+    Starting,
+
+    /// Running one of the steps of the test
+    InStep(Step),
+
+    /// Finished, one way or another
+    Done(Verdict),
+}
+
+impl CellState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CellState::Queued => "queued",
+            CellState::Starting => "starting",
+            CellState::InStep(Step::Clean) => "cleaning",
+            CellState::InStep(Step::Build) => "building",
+            CellState::InStep(Step::Run) => "running",
+            CellState::InStep(Step::Analyze) => "analyzing",
+            CellState::Done(verdict) => verdict.label(),
+        }
+    }
+}
+
+/// The short summary of how a finished cell turned out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Verdict {
+    Static,
+    StaticWithCaveats,
+    NonStatic,
+    Misbehaves,
+    Failed,
+    TimedOut,
+
+    /// The environment doesn't have what the test needs, so it wasn't attempted
+    Unsupported,
+
+    /// The test couldn't even be attempted
+    Error,
+}
+
+impl Verdict {
+    pub fn of(result: &Result<TestResult>) -> Self {
+        match result {
+            Ok(TestResult::StaticBinary) => Verdict::Static,
+            Ok(TestResult::StaticWithCaveats { .. }) => Verdict::StaticWithCaveats,
+            Ok(TestResult::NonStaticBinary { .. }) => Verdict::NonStatic,
+            Ok(TestResult::Misbehaves { .. }) => Verdict::Misbehaves,
+            Ok(TestResult::Failed { .. }) | Ok(TestResult::LinkFailed { .. }) => Verdict::Failed,
+            Ok(TestResult::TimedOut { .. }) => Verdict::TimedOut,
+            Ok(TestResult::MissingRequirements { .. }) => Verdict::Unsupported,
+            Err(_) => Verdict::Error,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Verdict::Static => "static",
+            Verdict::StaticWithCaveats => "static (caveats)",
+            Verdict::NonStatic => "NOT static",
+            Verdict::Misbehaves => "MISBEHAVES",
+            Verdict::Failed => "FAILED",
+            Verdict::TimedOut => "TIMED OUT",
+            Verdict::Unsupported => "UNSUPPORTED",
+            Verdict::Error => "ERROR",
+        }
+    }
+}
+
+/// Receives updates about the progress of the test matrix as it runs, including the live output of each cell's
+/// commands
+pub(crate) trait Progress: Send + Sync {
+    fn state(&self, cell: &CellId, state: CellState);
+
+    fn stdout(&self, cell: &CellId, chunk: &[u8]);
+
+    fn stderr(&self, cell: &CellId, chunk: &[u8]);
+
+    /// All cells are done
+    fn finish(&self) {}
+}
+
+/// An [`OutputSink`] that passes the output of a cell's commands to a [`Progress`]
+pub(crate) struct CellOutput<'a> {
+    pub progress: &'a dyn Progress,
+    pub cell: &'a CellId,
+}
+
+impl<'a> OutputSink for CellOutput<'a> {
+    fn stdout(&self, chunk: &[u8]) {
+        self.progress.stdout(self.cell, chunk)
+    }
+
+    fn stderr(&self, chunk: &[u8]) {
+        self.progress.stderr(self.cell, chunk)
+    }
+}
+
+/// Asks a run to stop, like when the user quits the UI.  The command that's running is stopped, so its container or
+/// processes get cleaned up the usual way, and no more cells are started
+#[derive(Clone)]
+pub(crate) struct Cancel {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Cancel {
+    fn default() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Wait until the run is cancelled
+    pub async fn cancelled(&self) {
+        // The sender lives as long as `self`, so this can only return once the run is cancelled
+        let _ = self
+            .sender
+            .subscribe()
+            .wait_for(|cancelled| *cancelled)
+            .await;
+    }
+}
+
+/// How progress is shown while the tests run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UiMode {
+    /// The output of every command is echoed verbatim, interleaved with log messages
+    Plain,
+
+    /// A live grid of the test matrix, with the log of any one cell available on demand
+    Tui,
+
+    /// A line for each change in a cell's state; the output of each cell is only in the run's log dir
+    Quiet,
+}
+
+impl UiMode {
+    pub const NAMES: &'static [&'static str] = &["plain", "tui", "quiet"];
+}
+
+impl FromStr for UiMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(UiMode::Plain),
+            "tui" => Ok(UiMode::Tui),
+            "quiet" => Ok(UiMode::Quiet),
+            other => Err(format!("'{}' is not a valid UI mode", other)),
+        }
+    }
+}
+
+/// The original, plain progress reporting: all output is echoed to this process' stdout/stderr
+pub(crate) struct PlainProgress;
+
+impl Progress for PlainProgress {
+    fn state(&self, cell: &CellId, state: CellState) {
+        debug!(%cell, state = state.label(), "Cell state changed");
+    }
+
+    fn stdout(&self, _cell: &CellId, chunk: &[u8]) {
+        EchoOutput.stdout(chunk)
+    }
+
+    fn stderr(&self, _cell: &CellId, chunk: &[u8]) {
+        EchoOutput.stderr(chunk)
+    }
+}
+
+/// Progress reporting which prints only state changes.  The output of each cell's steps is still in the run log
+pub(crate) struct QuietProgress {
+    log: Arc<RunLog>,
+}
+
+impl QuietProgress {
+    pub fn new(log: Arc<RunLog>) -> Self {
+        Self { log }
+    }
+}
+
+impl Progress for QuietProgress {
+    fn state(&self, cell: &CellId, state: CellState) {
+        match state {
+            CellState::Queued => {}
+            CellState::Done(_) => {
+                println!(
+                    "{:<30} {:<48} {:<10} (logs: {})",
+                    cell.test,
+                    cell.env,
+                    state.label(),
+                    self.log.cell_dir(cell).display()
+                );
+            }
+            _ => {
+                println!("{:<30} {:<48} {}", cell.test, cell.env, state.label());
+            }
+        }
+    }
+
+    fn stdout(&self, _cell: &CellId, _chunk: &[u8]) {}
+
+    fn stderr(&self, _cell: &CellId, _chunk: &[u8]) {}
+}
+
+/// A [`Progress`] which just remembers everything it's told, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct RecordingProgress {
+    states: Mutex<Vec<(CellId, CellState)>>,
+    output: Mutex<HashMap<CellId, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl RecordingProgress {
+    pub fn states(&self) -> Vec<(CellId, CellState)> {
+        self.states.lock().unwrap().clone()
+    }
+
+    /// All of the output for a cell, stdout and stderr combined
+    pub fn output(&self, cell: &CellId) -> String {
+        self.output
+            .lock()
+            .unwrap()
+            .get(cell)
+            .map(|output| String::from_utf8_lossy(output).to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+impl Progress for RecordingProgress {
+    fn state(&self, cell: &CellId, state: CellState) {
+        self.states.lock().unwrap().push((cell.clone(), state));
+    }
+
+    fn stdout(&self, cell: &CellId, chunk: &[u8]) {
+        self.output
+            .lock()
+            .unwrap()
+            .entry(cell.clone())
+            .or_default()
+            .extend_from_slice(chunk);
+    }
+
+    fn stderr(&self, cell: &CellId, chunk: &[u8]) {
+        self.stdout(cell, chunk)
+    }
+}
@@ -0,0 +1,493 @@
+use crate::dynamic_deps::DynamicDep;
+use crate::exec::{ExecOutput, ExecRequest, OutputSink};
+use crate::inventory::NativeLib;
+use crate::link_errors::LinkError;
+use crate::linkers::Linker;
+use crate::progress::{CellId, Verdict};
+use crate::tests::TestResult;
+use crate::timeouts::Step;
+use chrono::{DateTime, Local};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::*;
+
+/// The name of the index file in each run's log dir
+const INDEX_FILE: &str = "run.json";
+
+/// The log directory for a single run of the sandbox.
+///
+/// Every run gets its own timestamped directory, with a subdirectory for each test and environment holding one log
+/// file per step (`clean.log`, `build.log`, `run.log`, `analyze.log`).  The `run.json` file in the root of the
+/// directory is an index of every cell, its result, and the commands that were run in each step.  It's rewritten as
+/// each cell finishes, so it's useful even if the run is interrupted
+pub(crate) struct RunLog {
+    dir: PathBuf,
+    index: Mutex<RunIndex>,
+}
+
+/// The contents of `run.json`
+#[derive(Clone, Debug, Serialize)]
+struct RunIndex {
+    started: DateTime<Local>,
+    cells: Vec<CellRecord>,
+}
+
+/// What happened in one cell of the test matrix
+#[derive(Clone, Debug, Serialize)]
+struct CellRecord {
+    test: String,
+    env: String,
+
+    /// Not set until the cell is finished
+    result: Option<Verdict>,
+
+    /// The program the test links with.  Not set if the test wasn't attempted because the environment is missing
+    /// something it needs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linker: Option<String>,
+
+    /// The linker setup the test was configured with
+    linker_setup: Linker,
+
+    /// If the test's container had more access to the host than a default container, what it had
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elevated_access: Option<String>,
+
+    /// The env vars the test was run with, after merging the common and per-environment env vars
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    env_vars: Vec<String>,
+
+    /// The dynamic dependencies of the binary, if it's not static
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deps: Vec<DynamicDep>,
+
+    /// The linker's warnings about what a static binary still needs at runtime
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    caveats: Vec<String>,
+
+    /// The run assertions that failed, if the binary misbehaves
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<String>,
+
+    /// The root causes of a link failure
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    link_errors: Vec<LinkError>,
+
+    /// What the environment is missing, if the test wasn't attempted because of it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<String>,
+
+    /// The step that failed or timed out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_step: Option<Step>,
+
+    /// Why that step failed, or how long it ran before it was killed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+
+    /// If the test couldn't be attempted, the reason why
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+
+    /// How long `cargo build` took, if it finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_secs: Option<f64>,
+
+    /// The size of the binary in bytes, if it was built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary_size: Option<u64>,
+
+    /// The native libraries the build scripts linked, and whether each one ended up in the binary
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    native_libs: Vec<NativeLib>,
+
+    steps: Vec<StepRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct StepRecord {
+    step: Step,
+
+    /// The path of the step's log file, relative to the run's log dir
+    log: PathBuf,
+
+    commands: Vec<CommandRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct CommandRecord {
+    command: String,
+    exit_code: i64,
+    timed_out: bool,
+    duration_secs: f64,
+}
+
+impl RunLog {
+    /// Create a new, empty log dir for this run under `parent_dir`, named for the current time
+    pub fn create(parent_dir: &Path) -> Result<Self> {
+        let started = Local::now();
+        let mut dir = parent_dir.join(started.format("%Y%m%d-%H%M%S").to_string());
+
+        // In the unlikely event two runs start in the same second, don't mix their logs
+        let mut suffix = 1;
+        while dir.exists() {
+            suffix += 1;
+            dir = parent_dir.join(format!("{}-{}", started.format("%Y%m%d-%H%M%S"), suffix));
+        }
+
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| eyre!("Error creating log dir {}", dir.display()))?;
+
+        let log = Self {
+            dir,
+            index: Mutex::new(RunIndex {
+                started,
+                cells: Vec::new(),
+            }),
+        };
+        log.write_index()?;
+
+        Ok(log)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The directory that holds the logs for each step of a cell
+    pub fn cell_dir(&self, cell: &CellId) -> PathBuf {
+        self.dir.join(&cell.test).join(&cell.env)
+    }
+
+    /// The log file for one step of a cell
+    pub fn step_log_path(&self, cell: &CellId, step: Step) -> PathBuf {
+        self.cell_dir(cell).join(format!("{}.log", step))
+    }
+
+    /// Write a file that was generated for a cell into the cell's log dir, returning its path
+    pub fn write_cell_file(&self, cell: &CellId, name: &str, contents: &str) -> Result<PathBuf> {
+        let path = self.cell_dir(cell).join(name);
+        std::fs::create_dir_all(self.cell_dir(cell))
+            .and_then(|_| std::fs::write(&path, contents))
+            .wrap_err_with(|| eyre!("Error writing {}", path.display()))?;
+
+        Ok(path)
+    }
+
+    /// Open the log file for a step in preparation for running `request`, returning a sink that writes the
+    /// command's output to the log and also passes it on to `next`.
+    ///
+    /// Some steps run more than one command, so the output of each command is appended to the log after a line
+    /// with the command itself
+    pub fn open_step_log<'a>(
+        &self,
+        cell: &CellId,
+        step: Step,
+        request: &ExecRequest,
+        next: &'a dyn OutputSink,
+    ) -> Result<LogFileOutput<'a>> {
+        let path = self.step_log_path(cell, step);
+        std::fs::create_dir_all(self.cell_dir(cell))
+            .wrap_err_with(|| eyre!("Error creating log dir for {}", cell))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .wrap_err_with(|| eyre!("Error opening log file {}", path.display()))?;
+        writeln!(file, "$ {}", request.display_cmd())?;
+
+        Ok(LogFileOutput {
+            file: Mutex::new(file),
+            next,
+        })
+    }
+
+    /// Record the env vars that a cell's commands run with
+    pub fn record_env(&self, cell: &CellId, env_vars: &[String]) {
+        self.index.lock().unwrap().cell_mut(cell).env_vars = env_vars.to_vec();
+    }
+
+    /// Record the linker setup a cell is built with, and the program that setup actually links with
+    pub fn record_linker(&self, cell: &CellId, setup: Linker, program: &str) {
+        let mut index = self.index.lock().unwrap();
+        let record = index.cell_mut(cell);
+        record.linker = Some(program.to_string());
+        record.linker_setup = setup;
+    }
+
+    /// Record that a cell ran in a container with more access to the host than usual
+    pub fn record_elevated_access(&self, cell: &CellId, access: &str) {
+        self.index.lock().unwrap().cell_mut(cell).elevated_access = Some(access.to_string());
+    }
+
+    /// Record how long the build of a cell took
+    pub fn record_build_time(&self, cell: &CellId, build_time: Duration) {
+        self.index.lock().unwrap().cell_mut(cell).build_secs = Some(build_time.as_secs_f64());
+    }
+
+    /// Record the size of the binary that a cell built
+    pub fn record_binary_size(&self, cell: &CellId, binary_size: u64) {
+        self.index.lock().unwrap().cell_mut(cell).binary_size = Some(binary_size);
+    }
+
+    /// Record the native libraries that a cell's binary was linked with
+    pub fn record_native_libs(&self, cell: &CellId, libs: Vec<NativeLib>) {
+        self.index.lock().unwrap().cell_mut(cell).native_libs = libs;
+    }
+
+    /// The native libraries a cell's binary was linked with, if it got that far
+    pub fn native_libs(&self, cell: &CellId) -> Vec<NativeLib> {
+        let index = self.index.lock().unwrap();
+        index
+            .cells
+            .iter()
+            .find(|record| record.test == cell.test && record.env == cell.env)
+            .map(|record| record.native_libs.clone())
+            .unwrap_or_default()
+    }
+
+    /// The build time and binary size of a cell, whichever are known
+    pub fn build_stats(&self, cell: &CellId) -> (Option<Duration>, Option<u64>) {
+        let index = self.index.lock().unwrap();
+        match index
+            .cells
+            .iter()
+            .find(|record| record.test == cell.test && record.env == cell.env)
+        {
+            Some(record) => (
+                record.build_secs.map(Duration::from_secs_f64),
+                record.binary_size,
+            ),
+            None => (None, None),
+        }
+    }
+
+    /// Record a command that was run as part of a step in the index
+    pub fn record_command(
+        &self,
+        cell: &CellId,
+        step: Step,
+        request: &ExecRequest,
+        output: &ExecOutput,
+    ) {
+        let log = self
+            .step_log_path(cell, step)
+            .strip_prefix(&self.dir)
+            .expect("step logs are in the run's log dir")
+            .to_owned();
+        let command = CommandRecord {
+            command: request.display_cmd(),
+            exit_code: output.exit_code,
+            timed_out: output.timed_out,
+            duration_secs: output.duration.as_secs_f64(),
+        };
+
+        let mut index = self.index.lock().unwrap();
+        let record = index.cell_mut(cell);
+        match record.steps.iter_mut().find(|record| record.step == step) {
+            Some(record) => record.commands.push(command),
+            None => record.steps.push(StepRecord {
+                step,
+                log,
+                commands: vec![command],
+            }),
+        }
+    }
+
+    /// Record the final result of a cell, and update `run.json` on disk
+    pub fn finish_cell(&self, cell: &CellId, result: &Result<TestResult>) {
+        {
+            let mut index = self.index.lock().unwrap();
+            let record = index.cell_mut(cell);
+            record.result = Some(Verdict::of(result));
+            match result {
+                Ok(TestResult::NonStaticBinary { deps }) => record.deps = deps.clone(),
+                Ok(TestResult::StaticWithCaveats { caveats }) => record.caveats = caveats.clone(),
+                Ok(TestResult::Misbehaves { problems, .. }) => record.problems = problems.clone(),
+                Ok(TestResult::MissingRequirements { missing }) => record.missing = missing.clone(),
+                Ok(TestResult::LinkFailed { errors, .. }) => record.link_errors = errors.clone(),
+                Ok(TestResult::Failed { step, message, .. }) => {
+                    record.failed_step = Some(*step);
+                    record.message = Some(message.clone());
+                }
+                Ok(TestResult::TimedOut { step, after, .. }) => {
+                    record.failed_step = Some(*step);
+                    record.message = Some(format!("timed out after {:?}", after));
+                }
+                Err(e) => record.error = Some(format!("{:#}", e)),
+                Ok(_) => {}
+            }
+        }
+
+        if let Err(e) = self.write_index() {
+            error!("Error updating the run index: {:?}", e);
+        }
+    }
+
+    fn write_index(&self) -> Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let json = serde_json::to_string_pretty(&*self.index.lock().unwrap())?;
+
+        std::fs::write(&path, json).wrap_err_with(|| eyre!("Error writing {}", path.display()))
+    }
+}
+
+impl RunIndex {
+    fn cell_mut(&mut self, cell: &CellId) -> &mut CellRecord {
+        let position = self
+            .cells
+            .iter()
+            .position(|record| record.test == cell.test && record.env == cell.env);
+
+        match position {
+            Some(position) => &mut self.cells[position],
+            None => {
+                self.cells.push(CellRecord {
+                    test: cell.test.clone(),
+                    env: cell.env.clone(),
+                    result: None,
+                    linker: None,
+                    linker_setup: Linker::Default,
+                    elevated_access: None,
+                    env_vars: Vec::new(),
+                    deps: Vec::new(),
+                    caveats: Vec::new(),
+                    problems: Vec::new(),
+                    link_errors: Vec::new(),
+                    missing: Vec::new(),
+                    failed_step: None,
+                    message: None,
+                    error: None,
+                    build_secs: None,
+                    binary_size: None,
+                    native_libs: Vec::new(),
+                    steps: Vec::new(),
+                });
+                self.cells.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// An [`OutputSink`] which writes everything to a log file, and passes it on to another sink
+pub(crate) struct LogFileOutput<'a> {
+    file: Mutex<File>,
+    next: &'a dyn OutputSink,
+}
+
+impl<'a> LogFileOutput<'a> {
+    fn write(&self, chunk: &[u8]) {
+        let _ = self.file.lock().unwrap().write_all(chunk);
+    }
+}
+
+impl<'a> OutputSink for LogFileOutput<'a> {
+    fn stdout(&self, chunk: &[u8]) {
+        self.write(chunk);
+        self.next.stdout(chunk);
+    }
+
+    fn stderr(&self, chunk: &[u8]) {
+        self.write(chunk);
+        self.next.stderr(chunk);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exec::EchoOutput;
+
+    #[test]
+    fn index_lists_each_step_of_each_cell() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log = RunLog::create(temp_dir.path()).unwrap();
+        let cell = CellId::new("hello-world", "debian-rust");
+
+        for (step, cmd) in [(Step::Build, "build"), (Step::Run, "run")] {
+            let request = ExecRequest::new(vec!["cargo", cmd]);
+            let sink = log
+                .open_step_log(&cell, step, &request, &EchoOutput)
+                .unwrap();
+            sink.stdout(b"output\n");
+            log.record_command(
+                &cell,
+                step,
+                &request,
+                &ExecOutput {
+                    exit_code: 101,
+                    duration: Duration::from_secs(2),
+                    ..Default::default()
+                },
+            );
+        }
+        log.finish_cell(&cell, &Err(eyre!("no such image")));
+
+        assert_eq!(
+            "$ cargo build\noutput\n",
+            std::fs::read_to_string(log.step_log_path(&cell, Step::Build)).unwrap()
+        );
+
+        let index: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(log.dir().join(INDEX_FILE)).unwrap())
+                .unwrap();
+        let cells = index["cells"].as_array().unwrap();
+        assert_eq!(1, cells.len());
+        assert_eq!("error", cells[0]["result"]);
+        assert!(cells[0].get("linker").is_none());
+        assert_eq!("default", cells[0]["linker_setup"]);
+        assert_eq!("no such image", cells[0]["error"]);
+        assert_eq!("build", cells[0]["steps"][0]["step"]);
+        assert_eq!(
+            "hello-world/debian-rust/build.log",
+            cells[0]["steps"][0]["log"]
+        );
+        assert_eq!("cargo run", cells[0]["steps"][1]["commands"][0]["command"]);
+        assert_eq!(101, cells[0]["steps"][1]["commands"][0]["exit_code"]);
+    }
+
+    #[test]
+    fn index_records_the_failed_step() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log = RunLog::create(temp_dir.path()).unwrap();
+        let failed = CellId::new("hello-world", "debian-rust");
+        let timed_out = CellId::new("hello-world", "host");
+
+        log.finish_cell(
+            &failed,
+            &Ok(TestResult::Failed {
+                step: Step::Build,
+                message: "cargo build exited with code 101".to_string(),
+                log: log.step_log_path(&failed, Step::Build),
+            }),
+        );
+        log.finish_cell(
+            &timed_out,
+            &Ok(TestResult::TimedOut {
+                step: Step::Run,
+                after: Duration::from_secs(30),
+                log: log.step_log_path(&timed_out, Step::Run),
+            }),
+        );
+
+        let index: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(log.dir().join(INDEX_FILE)).unwrap())
+                .unwrap();
+        let cells = index["cells"].as_array().unwrap();
+        assert_eq!("failed", cells[0]["result"]);
+        assert_eq!("build", cells[0]["failed_step"]);
+        assert_eq!("cargo build exited with code 101", cells[0]["message"]);
+        assert_eq!("timed-out", cells[1]["result"]);
+        assert_eq!("run", cells[1]["failed_step"]);
+        assert_eq!("timed out after 30s", cells[1]["message"]);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::schema::check_env_var;
+use crate::tests::{self, TestCrate};
+use cargo_metadata::MetadataCommand;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A dependency of a new test crate, from the command line as `crate[@version][[feature,...]]`, like
+/// `openssl@0.10[vendored]`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DepArg {
+    pub name: String,
+
+    /// The version requirement, which is any version if it's not given
+    pub version: Option<String>,
+
+    pub features: Vec<String>,
+}
+
+impl DepArg {
+    /// The line for this dependency in `[dependencies]`
+    fn toml(&self) -> String {
+        let version = self.version.as_deref().unwrap_or("*");
+        if self.features.is_empty() {
+            format!("{} = {}", self.name, tests::toml_string(version))
+        } else {
+            format!(
+                "{} = {{ version = {}, features = {} }}",
+                self.name,
+                tests::toml_string(version),
+                tests::toml_array(&self.features)
+            )
+        }
+    }
+}
+
+impl FromStr for DepArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (spec, features) = match s.split_once('[') {
+            Some((spec, features)) => {
+                let features = features
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("'{}' is missing the closing ']'", s))?;
+                let features = features
+                    .split(',')
+                    .map(|feature| feature.trim().to_string())
+                    .filter(|feature| !feature.is_empty())
+                    .collect();
+                (spec, features)
+            }
+            None => (s, Vec::new()),
+        };
+
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version.to_string())),
+            None => (spec, None),
+        };
+        if !is_crate_name(name) {
+            return Err(format!("'{}' is not a valid crate name", name));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            version,
+            features,
+        })
+    }
+}
+
+fn is_crate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Marks what's left to do in a generated `main.rs`.  `validate` fails until they're all gone
+const TODO: &str = "TODO(new-test)";
+
+/// Generate a new test crate named `name` in `crates_dir`, which depends on `deps` and is tested with `env_vars`, and
+/// make sure it loads like any other test crate.  If it doesn't, the crate is removed again
+pub(crate) fn new_test(
+    crates_dir: &Path,
+    name: &str,
+    deps: &[DepArg],
+    env_vars: &[String],
+) -> Result<PathBuf> {
+    if !is_crate_name(name) {
+        return Err(eyre!("'{}' is not a valid crate name", name));
+    }
+    for var in env_vars {
+        check_env_var(var).map_err(|e| eyre!(e))?;
+    }
+
+    let path = crates_dir.join(name);
+    if path.exists() {
+        return Err(eyre!("{} already exists", path.display()));
+    }
+
+    let result = write_crate(&path, name, deps, env_vars)
+        .and_then(|()| TestCrate::load(path.clone()).map(drop));
+    match result {
+        Ok(()) => Ok(path),
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&path);
+            Err(e).wrap_err_with(|| eyre!("The new test crate '{}' doesn't load", name))
+        }
+    }
+}
+
+/// Make sure the `main.rs` of the test crate at `path` isn't still what `new_test` generated
+pub(crate) fn check_finished(path: &Path) -> Result<()> {
+    let main_path = path.join("src/main.rs");
+    let main = std::fs::read_to_string(&main_path)
+        .wrap_err_with(|| eyre!("Error reading {}", main_path.display()))?;
+
+    match main.lines().position(|line| line.contains(TODO)) {
+        Some(index) => Err(eyre!(
+            "src/main.rs:{}: replace the {} with code that calls into the dependency",
+            index + 1,
+            TODO
+        )),
+        None => Ok(()),
+    }
+}
+
+fn write_crate(path: &Path, name: &str, deps: &[DepArg], env_vars: &[String]) -> Result<()> {
+    std::fs::create_dir_all(path.join("src"))?;
+
+    let description = if deps.is_empty() {
+        "A binary Rust crate with no dependencies".to_string()
+    } else {
+        let names = deps
+            .iter()
+            .map(|dep| format!("`{}`", dep.name))
+            .collect::<Vec<_>>();
+        format!("A binary Rust crate which depends on {}", names.join(", "))
+    };
+
+    let mut manifest = format!(
+        "[package]\nname = \"{}\"\ndescription = \"\"\"\n{}\n\"\"\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+         # See more keys and their definitions at https://doc.rust-lang.org/cargo/reference/manifest.html\n\n\
+         [dependencies]\n",
+        name, description
+    );
+    for dep in deps {
+        manifest.push_str(&dep.toml());
+        manifest.push('\n');
+    }
+    manifest.push_str(&format!(
+        "\n[package.metadata.test-crate]\nenv = {}\n",
+        tests::toml_array(env_vars)
+    ));
+
+    let hello = "fn main() {\n    println!(\"Hello, world!\");\n}\n";
+    std::fs::write(path.join("Cargo.toml"), manifest)
+        .wrap_err_with(|| eyre!("Error writing {}", path.display()))?;
+    std::fs::write(path.join("src/main.rs"), hello)
+        .wrap_err_with(|| eyre!("Error writing {}", path.display()))?;
+    if deps.is_empty() {
+        return Ok(());
+    }
+
+    // The name a dependency has in code is the name of its lib target, which doesn't have to be the package's
+    let lib_names = lib_names(path, deps)?;
+    let mut main = String::from(
+        "// These only make sure each dependency builds.  The linker drops a native library that nothing calls into, so\n\
+         // until the code calls into each dependency a static binary doesn't show that it links statically\n",
+    );
+    for lib_name in &lib_names {
+        main.push_str(&format!("extern crate {} as _;\n", lib_name));
+    }
+    main.push_str("\nfn main() {\n");
+    for dep in deps {
+        main.push_str(&format!("    // {}: call into `{}`\n", TODO, dep.name));
+    }
+    main.push_str("    println!(\"Hello, world!\");\n}\n");
+
+    std::fs::write(path.join("src/main.rs"), main)
+        .wrap_err_with(|| eyre!("Error writing {}", path.display()))?;
+
+    Ok(())
+}
+
+/// The lib target name of each of `deps`, from the resolved metadata of the crate at `path`
+fn lib_names(path: &Path, deps: &[DepArg]) -> Result<Vec<String>> {
+    let metadata = MetadataCommand::new()
+        .manifest_path(path.join("Cargo.toml"))
+        .other_options(tests::filter_platform())
+        .exec()
+        .wrap_err_with(|| eyre!("Error getting crate metadata for {}", path.display()))?;
+    let root = metadata
+        .resolve
+        .as_ref()
+        .and_then(|resolve| resolve.root.as_ref())
+        .and_then(|root| {
+            metadata
+                .resolve
+                .iter()
+                .flat_map(|resolve| &resolve.nodes)
+                .find(|node| &node.id == root)
+        })
+        .ok_or_else(|| eyre!("{} didn't resolve", path.display()))?;
+
+    deps.iter()
+        .map(|dep| {
+            // A dependency is only listed with the name of its lib target if it has one
+            root.deps
+                .iter()
+                .find(|node_dep| metadata[&node_dep.pkg].name == dep.name)
+                .map(|node_dep| node_dep.name.clone())
+                .ok_or_else(|| eyre!("`{}` doesn't have a library to call into", dep.name))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dep_args_are_parsed() {
+        assert_eq!(
+            DepArg {
+                name: "openssl".to_string(),
+                version: Some("0.10".to_string()),
+                features: vec!["vendored".to_string(), "v111".to_string()],
+            },
+            "openssl@0.10[vendored, v111]".parse().unwrap()
+        );
+        assert_eq!(
+            "libudev = \"*\"",
+            "libudev".parse::<DepArg>().unwrap().toml()
+        );
+        assert!("openssl[vendored".parse::<DepArg>().is_err());
+        assert!("open ssl".parse::<DepArg>().is_err());
+    }
+
+    #[test]
+    fn new_test_crate_loads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let path = new_test(
+            temp_dir.path(),
+            "with-nothing",
+            &[],
+            &["OPENSSL_STATIC=1".to_string()],
+        )
+        .unwrap();
+
+        let test = TestCrate::load(path).unwrap();
+        assert_eq!("with-nothing", test.name());
+        assert!(new_test(temp_dir.path(), "with-nothing", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn new_test_crate_refers_to_each_lib_by_its_target_name() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lib_dir = temp_dir.path().join("some-lib");
+        std::fs::create_dir_all(lib_dir.join("src")).unwrap();
+        std::fs::write(
+            lib_dir.join("Cargo.toml"),
+            "[package]\nname = \"some-lib\"\nversion = \"0.1.0\"\n\n[lib]\nname = \"other_name\"\n",
+        )
+        .unwrap();
+        std::fs::write(lib_dir.join("src/lib.rs"), "").unwrap();
+
+        // A path dependency can't be given on the command line, so write the manifest by hand
+        let path = temp_dir.path().join("with-some-lib");
+        let dep = DepArg {
+            name: "some-lib".to_string(),
+            version: None,
+            features: Vec::new(),
+        };
+        std::fs::create_dir_all(path.join("src")).unwrap();
+        std::fs::write(
+            path.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"with-some-lib\"\nversion = \"0.1.0\"\n\n[workspace]\n\n\
+                 [dependencies]\nsome-lib = {{ path = {} }}\n",
+                tests::toml_string(&lib_dir.display().to_string())
+            ),
+        )
+        .unwrap();
+        std::fs::write(path.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        assert_eq!(
+            vec!["other_name"],
+            lib_names(&path, std::slice::from_ref(&dep)).unwrap()
+        );
+    }
+
+    #[test]
+    fn new_test_crate_is_unfinished_until_the_todos_are_done() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("with-openssl");
+        std::fs::create_dir_all(path.join("src")).unwrap();
+        std::fs::write(
+            path.join("src/main.rs"),
+            "extern crate openssl as _;\n\nfn main() {\n    // TODO(new-test): call into `openssl`\n}\n",
+        )
+        .unwrap();
+
+        let error = check_finished(&path).unwrap_err();
+        assert!(error.to_string().starts_with("src/main.rs:4: "));
+
+        std::fs::write(
+            path.join("src/main.rs"),
+            "fn main() {\n    openssl::init();\n}\n",
+        )
+        .unwrap();
+        check_finished(&path).unwrap();
+    }
+
+    #[test]
+    fn new_test_crate_that_doesnt_load_is_removed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dep = DepArg {
+            name: "no-such-crate-anywhere".to_string(),
+            version: Some("not a version".to_string()),
+            features: Vec::new(),
+        };
+
+        assert!(new_test(temp_dir.path(), "with-broken", &[dep], &[]).is_err());
+        assert!(!temp_dir.path().join("with-broken").exists());
+    }
+}
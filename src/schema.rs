@@ -0,0 +1,195 @@
+use color_eyre::eyre::{eyre, Report};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+/// The version of the `[package.metadata.test-crate]` schema this sandbox understands.  A crate can declare the
+/// version it was written for with `schema-version`, so a crate written for a newer sandbox fails with a clear error
+/// rather than with whatever the first unknown key happens to be
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+/// The header of the test crate metadata table in `Cargo.toml`
+const TABLE: &str = "[package.metadata.test-crate";
+
+/// What to look for to find the line an error is about
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Needle<'a> {
+    /// A key, either on the left of `=` or as the last part of a table header
+    Key(&'a str),
+
+    /// A string value
+    Value(&'a str),
+}
+
+/// A test crate's `Cargo.toml`, for pointing an error in its metadata at the line it's on.
+///
+/// cargo reports the metadata as JSON without any positions, so the line is found by searching the text of the
+/// test crate metadata tables
+pub(crate) struct Manifest {
+    path: PathBuf,
+    text: String,
+}
+
+impl Manifest {
+    /// Read the manifest at `path`.  If it can't be read, errors just don't have line numbers
+    pub fn read(path: PathBuf) -> Self {
+        let text = std::fs::read_to_string(&path).unwrap_or_default();
+
+        Self { path, text }
+    }
+
+    /// The 1-based number of the first line in the test crate metadata which has `needle`, or of the first test
+    /// crate metadata table if there's no such line
+    pub fn line_of(&self, needle: Option<Needle<'_>>) -> Option<usize> {
+        let lines = self
+            .text
+            .lines()
+            .enumerate()
+            .skip_while(|(_, line)| !line.trim_start().starts_with(TABLE))
+            .filter(|(_, line)| !line.trim_start().starts_with('#'))
+            .collect::<Vec<_>>();
+
+        let found = needle.and_then(|needle| {
+            lines
+                .iter()
+                .find(|(_, line)| matches(line, needle))
+                .map(|(number, _)| *number)
+        });
+
+        found
+            .or_else(|| lines.first().map(|(number, _)| *number))
+            .map(|number| number + 1)
+    }
+
+    /// An error about the test crate metadata, starting with the path and line of `needle` like a compiler error
+    pub fn error(&self, needle: Option<Needle<'_>>, message: impl Display) -> Report {
+        match self.line_of(needle) {
+            Some(line) => eyre!("{}:{}: {}", self.path.display(), line, message),
+            None => eyre!("{}: {}", self.path.display(), message),
+        }
+    }
+}
+
+fn matches(line: &str, needle: Needle<'_>) -> bool {
+    let line = line.trim();
+
+    match needle {
+        Needle::Key(key) => {
+            let is_assignment = line
+                .strip_prefix(key)
+                .map(|rest| rest.trim_start().starts_with('='))
+                .unwrap_or(false);
+            let is_header = line.starts_with('[')
+                && (line.contains(&format!(".{}]", key)) || line.contains(&format!(".{}.", key)));
+
+            is_assignment || is_header
+        }
+        Needle::Value(value) => line.contains(&format!("{:?}", value)),
+    }
+}
+
+/// What an `env` is expected to be, which serde puts at the end of an error about one that's neither
+pub(crate) const ENV_EXPECTED: &str = "a list of env vars, or a table of them by environment";
+
+/// The start of an error about one of the lists of env vars in an `env`
+const ENV_ERROR: &str = "invalid env vars for";
+
+/// An error about the list of env vars under `key` in an `env`, which is `env` itself if it's a plain list
+pub(crate) fn env_error(key: &str, error: impl Display) -> String {
+    format!("{} `{}`: {}", ENV_ERROR, key, error)
+}
+
+/// What an error from deserializing the metadata is about, if serde says: the key of an unknown field, the value of
+/// an unknown variant, or the key of a list of env vars that isn't one
+pub(crate) fn needle_in_error(message: &str) -> Option<Needle<'_>> {
+    let quoted = || message.split('`').nth(1);
+
+    if message.starts_with("unknown field") || message.starts_with(ENV_ERROR) {
+        quoted().map(Needle::Key)
+    } else if message.starts_with("unknown variant") {
+        quoted().map(Needle::Value)
+    } else if message.ends_with(ENV_EXPECTED) {
+        Some(Needle::Key("env"))
+    } else {
+        None
+    }
+}
+
+/// Check that an env var is in `NAME=VALUE` form, with a name that a shell would accept.  Anything else would be
+/// passed on to Docker or the host as is, where it'd be ignored or, without the `=`, unset the variable
+pub(crate) fn check_env_var(var: &str) -> Result<(), String> {
+    let (name, _value) = var
+        .split_once('=')
+        .ok_or_else(|| format!("env var '{}' has no value; it should be NAME=VALUE", var))?;
+
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false)
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("'{}' is not a valid env var name", name));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = "[package]\n\
+                            name = \"with-typos\"\n\
+                            \n\
+                            [package.metadata.test-crate]\n\
+                            # envs are set in every environment\n\
+                            envs = [\"OPENSSL_STATIC\"]\n\
+                            \n\
+                            [package.metadata.test-crate.variants.fast]\n\
+                            linker = \"mould\"\n";
+
+    fn manifest() -> Manifest {
+        Manifest {
+            path: PathBuf::from("crates/with-typos/Cargo.toml"),
+            text: MANIFEST.to_string(),
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        let manifest = manifest();
+
+        assert_eq!(Some(6), manifest.line_of(Some(Needle::Key("envs"))));
+        assert_eq!(
+            Some(6),
+            manifest.line_of(Some(Needle::Value("OPENSSL_STATIC")))
+        );
+        assert_eq!(Some(8), manifest.line_of(Some(Needle::Key("fast"))));
+        assert_eq!(
+            Some(9),
+            manifest.line_of(needle_in_error(
+                "unknown variant `mould`, expected one of `default`, `musl-gcc`, `clang-lld`, `mold`"
+            ))
+        );
+        assert_eq!(Some(4), manifest.line_of(Some(Needle::Key("timeouts"))));
+        assert_eq!(
+            "crates/with-typos/Cargo.toml:6: unknown field `envs`",
+            manifest
+                .error(
+                    needle_in_error("unknown field `envs`"),
+                    "unknown field `envs`"
+                )
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn env_vars_need_a_name_and_value() {
+        assert!(check_env_var("OPENSSL_STATIC=1").is_ok());
+        assert!(check_env_var("RUSTFLAGS=").is_ok());
+        assert!(check_env_var("OPENSSL_STATIC").is_err());
+        assert!(check_env_var("=1").is_err());
+        assert!(check_env_var("1ST=1").is_err());
+        assert!(check_env_var("OPENSSL STATIC=1").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::environments::TargetKind;
+use crate::tests::EnvVarsMetadata;
+use cargo_metadata::{DependencyKind, Metadata, PackageId};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+/// The knowledge base of `-sys` crates, from `sys-crates.json` in the root of the repo
+const KNOWLEDGE_BASE: &str = include_str!("../sys-crates.json");
+
+/// What we know about getting a `-sys` crate (or a crate like `pkg-config` that they use) to link statically
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SysCrate {
+    /// The name of the crate
+    pub name: String,
+
+    /// Why the env vars and features are needed, and what they do
+    pub notes: String,
+
+    /// The env vars which make the crate link statically, in the same form as the `env` of a test crate
+    #[serde(default)]
+    pub env: EnvVarsMetadata,
+
+    /// Features which make the crate build its native library from source
+    #[serde(default)]
+    pub features: Vec<FeatureHint>,
+
+    /// The kinds of target this entry is for, or every kind if it's empty.  A crate can have an entry for some kinds
+    /// of target as well as one for all of them
+    #[serde(default)]
+    pub targets: Vec<TargetKind>,
+}
+
+impl SysCrate {
+    fn applies_to(&self, kind: TargetKind) -> bool {
+        self.targets.is_empty() || self.targets.contains(&kind)
+    }
+}
+
+/// A feature of a crate that affects how a `-sys` crate links its native library
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct FeatureHint {
+    /// The crate with the feature, which is often the safe wrapper around the `-sys` crate rather than the `-sys`
+    /// crate itself
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+
+    pub feature: String,
+
+    pub notes: String,
+}
+
+/// The whole knowledge base
+pub(crate) fn knowledge_base() -> Result<Vec<SysCrate>> {
+    serde_json::from_str(KNOWLEDGE_BASE).wrap_err_with(|| eyre!("sys-crates.json is invalid"))
+}
+
+/// The knowledge base entries for the crates in a crate's dependency graph, which is what to set up to build it for
+/// one kind of target
+#[derive(Clone, Debug)]
+pub(crate) struct Suggestion {
+    /// The kind of target the entries are for
+    pub target: TargetKind,
+
+    /// The entries, in the order their crates are found walking the graph from the root
+    pub crates: Vec<SysCrate>,
+}
+
+impl Suggestion {
+    /// Find the crates in the knowledge base among the normal and build dependencies of the root of `metadata`, and
+    /// their entries for `target`.  Build dependencies are included because that's where `pkg-config` is
+    pub fn for_graph(metadata: &Metadata, knowledge_base: &[SysCrate], target: TargetKind) -> Self {
+        let mut crates = Vec::new();
+        let mut found = HashSet::new();
+
+        let root = metadata.resolve.as_ref().and_then(|r| r.root.as_ref());
+        let mut seen: HashSet<&PackageId> = root.into_iter().collect();
+        let mut queue = root.into_iter().collect::<VecDeque<_>>();
+        while let Some(id) = queue.pop_front() {
+            // There can be more than one version of a crate in the graph, but its entries only need to be there once
+            if let Some(package) = metadata.packages.iter().find(|p| &p.id == id) {
+                if found.insert(package.name.as_str()) {
+                    crates.extend(
+                        knowledge_base
+                            .iter()
+                            .filter(|known| known.name == package.name && known.applies_to(target))
+                            .cloned(),
+                    );
+                }
+            }
+
+            let node = metadata
+                .resolve
+                .iter()
+                .flat_map(|resolve| &resolve.nodes)
+                .find(|node| &node.id == id);
+            for dep in node.into_iter().flat_map(|node| &node.deps) {
+                let in_binary_or_build = dep.dep_kinds.is_empty()
+                    || dep
+                        .dep_kinds
+                        .iter()
+                        .any(|info| info.kind != DependencyKind::Development);
+                if in_binary_or_build && seen.insert(&dep.pkg) {
+                    queue.push_back(&dep.pkg);
+                }
+            }
+        }
+
+        Self { target, crates }
+    }
+
+    /// The env vars to build with in an environment, each in `NAME=VALUE` form
+    pub fn env_vars(&self, env_name: &str) -> Vec<String> {
+        let mut env_vars = Vec::new();
+        for known in &self.crates {
+            known.env.merge_into(&mut env_vars, env_name);
+        }
+
+        env_vars
+    }
+
+    /// The env vars for every environment, combined into the `env` table of a test crate's
+    /// `[package.metadata.test-crate]`.  An environment's list only has what it doesn't already get from `common`
+    pub fn env_table(&self) -> String {
+        // There's no environment with an empty name, so this is only the common env vars
+        let common = self.env_vars("");
+        let environments = self
+            .crates
+            .iter()
+            .flat_map(|known| known.env.environment_names())
+            .collect::<BTreeSet<_>>();
+
+        let mut names = Vec::new();
+        for known in &self.crates {
+            if !names.contains(&known.name.as_str()) {
+                names.push(known.name.as_str());
+            }
+        }
+        let mut table = format!(
+            "[package.metadata.test-crate.env]\n# Suggested by sys-crates.json for {} targets: {}\ncommon = {:?}\n",
+            self.target,
+            names.join(", "),
+            common
+        );
+        for env_name in environments {
+            let specific = self
+                .env_vars(env_name)
+                .into_iter()
+                .filter(|var| !common.contains(var))
+                .collect::<Vec<_>>();
+            if !specific.is_empty() {
+                table.push_str(&format!("{} = {:?}\n", env_name, specific));
+            }
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environments::Environment;
+    use cargo_metadata::MetadataCommand;
+    use std::path::PathBuf;
+
+    #[test]
+    fn knowledge_base_is_valid() {
+        for known in knowledge_base().unwrap() {
+            for env_name in known.env.environment_names() {
+                assert!(
+                    Environment::from_name(env_name).is_some(),
+                    "{} has env vars for unknown environment {}",
+                    known.name,
+                    env_name
+                );
+            }
+        }
+    }
+
+    fn with_open_ssl(target: TargetKind) -> Suggestion {
+        let metadata = MetadataCommand::new()
+            .manifest_path(
+                PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("crates/with-open-ssl/Cargo.toml"),
+            )
+            .exec()
+            .unwrap();
+
+        Suggestion::for_graph(&metadata, &knowledge_base().unwrap(), target)
+    }
+
+    #[test]
+    fn openssl_gets_env_for_openssl_and_pkg_config() {
+        let suggestion = with_open_ssl(TargetKind::Musl);
+
+        assert_eq!(
+            vec![
+                "OPENSSL_STATIC=1",
+                "OPENSSL_DIR=/usr",
+                "PKG_CONFIG_ALLOW_CROSS=1",
+                "PKG_CONFIG_ALL_STATIC=1"
+            ],
+            suggestion.env_vars("alpine-official-rust")
+        );
+        assert_eq!(
+            "[package.metadata.test-crate.env]\n\
+             # Suggested by sys-crates.json for musl targets: openssl-sys, pkg-config\n\
+             common = [\"OPENSSL_STATIC=1\", \"PKG_CONFIG_ALLOW_CROSS=1\", \"PKG_CONFIG_ALL_STATIC=1\"]\n\
+             alpine-custom-rust = [\"OPENSSL_DIR=/usr\"]\n\
+             alpine-official-rust = [\"OPENSSL_DIR=/usr\"]\n",
+            suggestion.env_table()
+        );
+    }
+
+    #[test]
+    fn entries_are_only_for_their_targets() {
+        let suggestion = with_open_ssl(TargetKind::GnuStatic);
+
+        assert_eq!(
+            vec!["OPENSSL_STATIC=1", "PKG_CONFIG_ALL_STATIC=1"],
+            suggestion.env_vars("debian-static-libs")
+        );
+    }
+
+    #[test]
+    fn env_table_has_each_env_var_once() {
+        let known = |name: &str, env: serde_json::Value| SysCrate {
+            name: name.to_string(),
+            notes: String::new(),
+            env: serde_json::from_value(env).unwrap(),
+            features: Vec::new(),
+            targets: Vec::new(),
+        };
+        let suggestion = Suggestion {
+            target: TargetKind::Musl,
+            crates: vec![
+                known(
+                    "openssl-sys",
+                    serde_json::json!({
+                        "common": ["OPENSSL_STATIC=1"],
+                        "alpine-official-rust": ["OPENSSL_DIR=/usr", "OPENSSL_STATIC=1"],
+                    }),
+                ),
+                known(
+                    "curl-sys",
+                    serde_json::json!({
+                        "alpine-official-rust": ["OPENSSL_DIR=/usr", "CURL_STATIC=1"],
+                    }),
+                ),
+            ],
+        };
+
+        assert_eq!(
+            "[package.metadata.test-crate.env]\n\
+             # Suggested by sys-crates.json for musl targets: openssl-sys, curl-sys\n\
+             common = [\"OPENSSL_STATIC=1\"]\n\
+             alpine-official-rust = [\"OPENSSL_DIR=/usr\", \"CURL_STATIC=1\"]\n",
+            suggestion.env_table()
+        );
+    }
+}
@@ -1,23 +1,394 @@
-use crate::docker;
+use crate::assertions::RunAssertions;
+use crate::docker::{ContainerAccess, ContainerDaemon};
+use crate::dynamic_deps::DynamicDep;
+use crate::elf::{machine_for_triple, ElfInfo};
+use crate::environments::{Backend, Platform};
+use crate::exec::{ExecOutput, ExecRequest, OutputSink};
+use crate::host::HostSandbox;
+use crate::inventory::{self, inventory, BuildScriptOutput};
+use crate::link_errors::LinkError;
+use crate::linkers::Linker;
+use crate::probe::{Capabilities, Requirements};
+use crate::progress::{Cancel, CellId, CellOutput, CellState, Progress};
+use crate::runlog::RunLog;
+use crate::scaffold;
+use crate::schema::{
+    check_env_var, env_error, needle_in_error, Manifest, Needle, ENV_EXPECTED, SCHEMA_VERSION,
+};
+use crate::timeouts::{Step, TimeoutOverrides, Timeouts};
 use crate::Environment;
-use cargo_metadata::{Metadata, MetadataCommand};
+use cargo_metadata::{CargoOpt, Metadata, MetadataCommand, Package};
 use color_eyre::{
-    eyre::Context,
     eyre::{eyre, WrapErr},
-    Report, Result,
+    Result,
 };
-use futures::StreamExt;
-use serde::{Deserialize, Serialize};
-use shiplift::{tty::TtyChunk, Container, Docker, Exec, ExecContainerOptions};
-use std::io::{self, Read, Write};
+use serde::de::{self, value::SeqAccessDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tracing::*;
 
 /// Struct which maps to the TOML we expect to find in each test crate's `Cargo.toml` file
-/// at `[package.metadata.test-crate]`
+/// at `[package.metadata.test-crate]`.
+///
+/// Unknown keys are errors, so a typo like `envs` doesn't silently leave the test without its env vars
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct CargoTomlPackageMetadata {
-    env: Vec<String>,
+    /// The version of the schema the metadata was written for; see [`schema::SCHEMA_VERSION`]
+    #[serde(default = "default_schema_version", rename = "schema-version")]
+    schema_version: u32,
+
+    env: EnvVarsMetadata,
+
+    /// Overrides for the global timeouts, for tests that are known to be slow (or known to hang)
+    #[serde(default)]
+    timeouts: TimeoutOverrides,
+
+    /// How the static linking flags are passed to cargo
+    #[serde(default, rename = "rustflags-mode")]
+    rustflags_mode: RustflagsMode,
+
+    /// The linker setup to build with
+    #[serde(default)]
+    linker: Linker,
+
+    /// What the binary is expected to do when it runs
+    #[serde(default)]
+    run: Option<RunAssertions>,
+
+    /// Extra access to the host for the test's container, beyond what a default container has
+    #[serde(default)]
+    container: ContainerAccess,
+
+    /// Other configurations of the same crate to test, from the `[package.metadata.test-crate.variants.<name>]`
+    /// tables.  Each one is tested separately, in addition to the crate's default configuration
+    #[serde(default)]
+    variants: BTreeMap<String, VariantMetadata>,
+}
+
+impl CargoTomlPackageMetadata {
+    /// Check what serde can't: the schema version, the form of each env var, the names of the environments with
+    /// their own env vars, and the run assertions.  Errors point at the line in `manifest` with the problem
+    fn validate(&self, manifest: &Manifest) -> Result<()> {
+        if self.schema_version > SCHEMA_VERSION {
+            return Err(manifest.error(
+                Some(Needle::Key("schema-version")),
+                format!(
+                    "schema version {} is newer than this sandbox supports ({})",
+                    self.schema_version, SCHEMA_VERSION
+                ),
+            ));
+        } else if self.schema_version != SCHEMA_VERSION {
+            return Err(manifest.error(
+                Some(Needle::Key("schema-version")),
+                format!(
+                    "there is no schema version {}; the current version is {}",
+                    self.schema_version, SCHEMA_VERSION
+                ),
+            ));
+        }
+
+        let envs =
+            std::iter::once(&self.env).chain(self.variants.values().map(|variant| &variant.env));
+        for env in envs {
+            for var in env.all_vars() {
+                check_env_var(var).map_err(|e| manifest.error(Some(Needle::Value(var)), e))?;
+            }
+
+            // Catch typos in environment names, which would otherwise silently do nothing
+            for env_name in env.environment_names() {
+                if Environment::from_name(env_name).is_none() {
+                    return Err(manifest.error(
+                        Some(Needle::Key(env_name)),
+                        format!(
+                            "env vars for '{}', which is not a valid environment name",
+                            env_name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let assertions = self.run.iter().chain(
+            self.variants
+                .values()
+                .filter_map(|variant| variant.run.as_ref()),
+        );
+        for assertions in assertions {
+            assertions.validate().map_err(|e| {
+                manifest.error(
+                    Some(Needle::Key("run")),
+                    format!("invalid run assertion: {:#}", e),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Metadata without a `schema-version` was written for the first version
+fn default_schema_version() -> u32 {
+    1
+}
+
+/// A variant of a test crate, built with different features, env vars or flags.
+///
+/// Everything here is in addition to what's set for the crate as a whole
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct VariantMetadata {
+    /// Cargo features to enable, passed to `cargo` with `--features`
+    #[serde(default)]
+    features: Vec<String>,
+
+    /// Pass `--no-default-features` to `cargo`
+    #[serde(default)]
+    no_default_features: bool,
+
+    /// Additional env vars in `NAME=VALUE` form.  These replace any of the crate's env vars of the same name
+    #[serde(default)]
+    env: EnvVarsMetadata,
+
+    /// Additional args passed to `cargo build` and `cargo run`
+    #[serde(default)]
+    cargo_args: Vec<String>,
+
+    /// Additional flags for `RUSTFLAGS`
+    #[serde(default)]
+    rustflags: Vec<String>,
+
+    /// How the static linking flags are passed to cargo, if not the same as for the crate
+    #[serde(default)]
+    rustflags_mode: Option<RustflagsMode>,
+
+    /// The linker setup to build with, if not the same as for the crate
+    #[serde(default)]
+    linker: Option<Linker>,
+
+    /// What the binary is expected to do when it runs, if not the same as for the crate.  This replaces the crate's
+    /// assertions entirely
+    #[serde(default)]
+    run: Option<RunAssertions>,
+
+    /// Extra access to the host for the test's container, if not the same as for the crate.  This replaces the
+    /// crate's access entirely
+    #[serde(default)]
+    container: Option<ContainerAccess>,
+}
+
+/// How the flags that make the binary static (`-C target-feature=+crt-static`, plus any flags from a variant) are
+/// passed to cargo
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RustflagsMode {
+    /// In the `RUSTFLAGS` env var.  These flags apply to everything rustc compiles, including build scripts and proc
+    /// macros which are built for the host, not the target
+    #[default]
+    Global,
+
+    /// In the `CARGO_TARGET_<TRIPLE>_RUSTFLAGS` env var, so they only apply to code built for the target
+    TargetEnv,
+
+    /// In a generated cargo config file with a `[target.<triple>]` section, which is also only for the target.
+    /// This is how the flags are usually set in a real project
+    CargoConfig,
+}
+
+/// The env vars for a test crate, each in `NAME=VALUE` form.
+///
+/// This is either a single list which applies to every environment:
+///
+/// ```toml
+/// env = ["OPENSSL_STATIC=1"]
+/// ```
+///
+/// or a table with a `common` list, and a list for any environment which needs something more:
+///
+/// ```toml
+/// [package.metadata.test-crate.env]
+/// common = ["OPENSSL_STATIC=1"]
+/// alpine-official-rust = ["OPENSSL_DIR=/usr"]
+/// ```
+///
+/// It's deserialized by hand rather than as an untagged enum, so an error says which list it's in instead of just
+/// that the value didn't match either form
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum EnvVarsMetadata {
+    Common(Vec<String>),
+    PerEnvironment {
+        #[serde(default)]
+        common: Vec<String>,
+
+        /// Env vars for specific environments, by environment name
+        #[serde(flatten)]
+        environments: BTreeMap<String, Vec<String>>,
+    },
+}
+
+impl<'de> Deserialize<'de> for EnvVarsMetadata {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(EnvVarsVisitor)
+    }
+}
+
+struct EnvVarsVisitor;
+
+impl<'de> Visitor<'de> for EnvVarsVisitor {
+    type Value = EnvVarsMetadata;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(ENV_EXPECTED)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> std::result::Result<Self::Value, A::Error> {
+        let env_vars = Vec::<String>::deserialize(SeqAccessDeserializer::new(seq))
+            .map_err(|e| de::Error::custom(env_error("env", e)))?;
+
+        Ok(EnvVarsMetadata::Common(env_vars))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut common = Vec::new();
+        let mut environments = BTreeMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            let env_vars = map
+                .next_value::<Vec<String>>()
+                .map_err(|e| de::Error::custom(env_error(&key, e)))?;
+
+            if key == "common" {
+                common = env_vars;
+            } else {
+                environments.insert(key, env_vars);
+            }
+        }
+
+        Ok(EnvVarsMetadata::PerEnvironment {
+            common,
+            environments,
+        })
+    }
+}
+
+impl Default for EnvVarsMetadata {
+    fn default() -> Self {
+        EnvVarsMetadata::Common(Vec::new())
+    }
+}
+
+impl EnvVarsMetadata {
+    /// Merge the env vars for an environment into `env_vars`: the common ones first, then the ones specific to
+    /// the environment.  Each replaces any existing env var of the same name
+    pub fn merge_into(&self, env_vars: &mut Vec<String>, env_name: &str) {
+        let (common, specific) = match self {
+            EnvVarsMetadata::Common(common) => (common, None),
+            EnvVarsMetadata::PerEnvironment {
+                common,
+                environments,
+            } => (common, environments.get(env_name)),
+        };
+
+        for var in common.iter().chain(specific.into_iter().flatten()) {
+            let name = var.split('=').next().unwrap();
+            env_vars.retain(|v| v.split('=').next().unwrap() != name);
+            env_vars.push(var.clone());
+        }
+    }
+
+    /// Every env var, for every environment
+    pub fn all_vars(&self) -> Vec<&str> {
+        match self {
+            EnvVarsMetadata::Common(common) => common.iter().map(|var| var.as_str()).collect(),
+            EnvVarsMetadata::PerEnvironment {
+                common,
+                environments,
+            } => common
+                .iter()
+                .chain(environments.values().flatten())
+                .map(|var| var.as_str())
+                .collect(),
+        }
+    }
+
+    /// The names of the environments which have their own env vars
+    pub fn environment_names(&self) -> Vec<&str> {
+        match self {
+            EnvVarsMetadata::Common(_) => Vec::new(),
+            EnvVarsMetadata::PerEnvironment { environments, .. } => {
+                environments.keys().map(|name| name.as_str()).collect()
+            }
+        }
+    }
+}
+
+/// The env vars that the `[package.metadata.test-crate]` of any crate, not just one of the test crates, sets for an
+/// environment.  A crate without that metadata has none
+pub(crate) fn package_env_vars(package: &Package, env_name: &str) -> Result<Vec<String>> {
+    let mut env_vars = Vec::new();
+
+    if let Some(env) = package
+        .metadata
+        .get("test-crate")
+        .and_then(|metadata| metadata.get("env"))
+    {
+        let env: EnvVarsMetadata = serde_json::from_value(env.clone()).wrap_err_with(|| {
+            eyre!(
+                "Crate '{}' has invalid env vars in [package.metadata.test-crate]",
+                package.name
+            )
+        })?;
+        env.merge_into(&mut env_vars, env_name);
+    }
+
+    Ok(env_vars)
+}
+
+/// Every environment builds for Linux, so the dependency graph is resolved for Linux only.  Otherwise it has the
+/// dependencies for every other platform too, like the ones `wasm-bindgen` brings in for `wasm32`
+pub(crate) fn filter_platform() -> Vec<String> {
+    vec![
+        "--filter-platform".to_string(),
+        "x86_64-unknown-linux-gnu".to_string(),
+    ]
+}
+
+/// `value` as a quoted TOML basic string.  Rust's `{:?}` looks close, but writes escapes like `\u{1b}` that TOML
+/// rejects
+pub(crate) fn toml_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// `values` as a TOML array of strings
+pub(crate) fn toml_array<S: AsRef<str>>(values: &[S]) -> String {
+    let values = values
+        .iter()
+        .map(|value| toml_string(value.as_ref()))
+        .collect::<Vec<_>>();
+
+    format!("[{}]", values.join(", "))
 }
 
 /// Describe a test crate in the `crates` directory which makes up a test
@@ -26,30 +397,37 @@ pub(crate) struct TestCrate {
     /// The path to the root directory of the crate, where `Cargo.toml` is located
     path: PathBuf,
 
-    /// The name of the crate (and thus, the test)
+    /// The name of the test.  This is the name of the crate, followed by `/<variant>` for a variant
     name: String,
 
+    /// The name of the crate, which is also the name of the binary it produces
+    crate_name: String,
+
+    /// The variant of the crate that this test builds, or `None` for the crate's default configuration
+    variant: Option<VariantMetadata>,
+
     /// The metadata about this crate as reported by cargo
     cargo_metadata: Metadata,
 
     /// The metadata we place in the crate's Cargo.toml to customize the test behavior
     package_metadata: CargoTomlPackageMetadata,
+
+    /// Host directories that the crate refers to, like those of path dependencies, which are mounted read-only at the
+    /// same path in the test's container
+    host_dirs: Vec<PathBuf>,
 }
 
 impl TestCrate {
-    pub fn path(&self) -> &Path {
-        &self.path
-    }
-
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    fn load(path: PathBuf) -> Result<Self> {
+    pub(crate) fn load(path: PathBuf) -> Result<Self> {
         debug!(path = %path.display(),
             "Loading test crate");
         let metadata = MetadataCommand::new()
             .manifest_path(path.join("Cargo.toml"))
+            .other_options(filter_platform())
             .exec()
             .wrap_err_with(|| eyre!("Error getting crate metadata for {}", path.display()))?;
 
@@ -66,14 +444,16 @@ impl TestCrate {
             )
         })?;
 
+        let manifest = Manifest::read(path.join("Cargo.toml"));
         let package_metadata: CargoTomlPackageMetadata =
-            serde_json::from_value(test_crate_metadata_value.to_owned()).wrap_err_with(|| {
-                eyre!(
-                    "Test crate '{}' has invalid [package.metadata.test-crate] contents: {:#?}",
-                    path.display(),
-                    test_crate_metadata_value
+            serde_json::from_value(test_crate_metadata_value.to_owned()).map_err(|e| {
+                let message = e.to_string();
+                manifest.error(
+                    needle_in_error(&message),
+                    format!("invalid [package.metadata.test-crate]: {}", message),
                 )
             })?;
+        package_metadata.validate(&manifest)?;
 
         Ok(Self {
             path: root
@@ -83,42 +463,203 @@ impl TestCrate {
                 .unwrap()
                 .to_owned(),
             name: root.name.clone(),
+            crate_name: root.name.clone(),
+            variant: None,
             cargo_metadata: metadata,
             package_metadata,
+            host_dirs: Vec::new(),
+        })
+    }
+
+    /// Mount these host directories read-only in the test's container, at the same path.  A path dependency is
+    /// found at the same path in the container as on the host, where the crate is loaded
+    pub(crate) fn mount_host_dirs(&mut self, dirs: Vec<PathBuf>) {
+        self.host_dirs = dirs;
+    }
+
+    /// This test crate in its default configuration, followed by each of its variants as a separate test
+    fn with_variants(self) -> Vec<Self> {
+        let variants = self
+            .package_metadata
+            .variants
+            .iter()
+            .map(|(name, variant)| Self {
+                name: format!("{}/{}", self.crate_name, name),
+                variant: Some(variant.clone()),
+                ..self.clone()
+            })
+            .collect::<Vec<_>>();
+
+        std::iter::once(self).chain(variants).collect()
+    }
+
+    /// The crate's metadata with the dependency graph resolved for this test's features.  For the default
+    /// configuration that's what was loaded with the crate; a variant with its own features has to be resolved again
+    pub fn resolved_metadata(&self) -> Result<Metadata> {
+        let variant = match &self.variant {
+            Some(variant) if !variant.features.is_empty() || variant.no_default_features => variant,
+            _ => return Ok(self.cargo_metadata.clone()),
+        };
+
+        let mut command = MetadataCommand::new();
+        command
+            .manifest_path(self.path.join("Cargo.toml"))
+            .other_options(filter_platform());
+        if variant.no_default_features {
+            command.features(CargoOpt::NoDefaultFeatures);
+        }
+        if !variant.features.is_empty() {
+            command.features(CargoOpt::SomeFeatures(variant.features.clone()));
+        }
+
+        command
+            .exec()
+            .wrap_err_with(|| eyre!("Error getting crate metadata for {}", self.name))
+    }
+
+    /// Run this test for a given target in a given environment, returning the result of the test
+    pub async fn run_test(
+        &self,
+        ctx: &RunContext<'_>,
+        platform: Platform<'_>,
+    ) -> Result<TestResult> {
+        let timeouts = ctx.timeouts.with_overrides(&self.package_metadata.timeouts);
+        let cell = CellId::new(self.name(), &platform.name());
+        let env = platform.env;
+
+        ctx.progress.state(&cell, CellState::Starting);
+
+        // Don't spend ten minutes building something that can't possibly work in this environment.  Without a
+        // probe of the environment, only what can't work in any environment is known
+        let missing = match ctx.capabilities.get(env.name()) {
+            Some(capabilities) => capabilities.missing(&self.requirements(platform)?),
+            None => self.unsupported(platform),
+        };
+        if !missing.is_empty() {
+            return Ok(TestResult::MissingRequirements { missing });
+        }
+
+        let env_vars = self.env_vars(platform);
+        let linker_program = self.linker_program(platform, &env_vars);
+        info!(?env_vars, rustflags_mode = ?self.rustflags_mode(), linker = %self.linker(), %linker_program, "Resolved env vars");
+        ctx.log.record_env(&cell, &env_vars);
+        ctx.log.record_linker(&cell, self.linker(), &linker_program);
+
+        match env.backend() {
+            Backend::Docker { cargo_home } => {
+                let daemon = ctx.daemon.ok_or_else(|| {
+                    eyre!(
+                        "Environment '{}' requires Docker but there is no Docker connection",
+                        env.name()
+                    )
+                })?;
+
+                self.run_test_in_docker(ctx, daemon, platform, cargo_home, &cell, &timeouts)
+                    .await
+            }
+            Backend::Host => self.run_test_on_host(ctx, platform, &cell, &timeouts).await,
+        }
+    }
+
+    /// What this test needs from the environment to build and run on a platform
+    fn requirements(&self, platform: Platform<'_>) -> Result<Requirements> {
+        let mut programs = vec!["readelf".to_string()];
+        programs.extend(
+            self.linker()
+                .required_programs()
+                .iter()
+                .map(|program| program.to_string()),
+        );
+        programs.extend(platform.target.runner().map(str::to_string));
+
+        let metadata = self.resolved_metadata()?;
+        let has_package = |name: &str| metadata.packages.iter().any(|p| p.name == name);
+        if has_package("pkg-config") {
+            programs.push("pkg-config".to_string());
+        }
+
+        // bindgen finds libclang with `LIBCLANG_PATH` if it's set, which the probe doesn't see
+        let libclang = has_package("bindgen")
+            && !self
+                .env_vars(platform)
+                .iter()
+                .any(|var| var.starts_with("LIBCLANG_PATH="));
+
+        Ok(Requirements {
+            triple: platform.target.triple().to_string(),
+            programs,
+            libclang,
+            unsupported: self.unsupported(platform),
         })
     }
 
-    /// Run this test in a given environment, returning the result of the test
-    pub async fn run_test<'docker>(
+    /// Why this test can't be built for the platform's target in any environment
+    fn unsupported(&self, platform: Platform<'_>) -> Vec<String> {
+        self.linker()
+            .incompatibility(platform.target)
+            .into_iter()
+            .collect()
+    }
+
+    /// Run this test in a new Docker container for the given environment
+    async fn run_test_in_docker(
         &self,
-        docker: &'docker Docker,
-        cache_dir: &Path,
-        env: &Environment,
+        ctx: &RunContext<'_>,
+        daemon: &dyn ContainerDaemon,
+        platform: Platform<'_>,
+        cargo_home: &str,
+        cell: &CellId,
+        timeouts: &Timeouts,
     ) -> Result<TestResult> {
         // Prepare a new container for the test run
-        let env_vars = self.env_vars();
-        let volumes = self.volumes(cache_dir, env);
+        let env_vars = self.env_vars(platform);
+        let mut volumes = self.volumes(ctx.cache_dir, cargo_home);
 
-        let container = env.launch_container(docker, env_vars, volumes).await?;
+        // The working dir is `/build`, so cargo finds a config file in `/.cargo`
+        if let Some(cargo_config) = self.write_cargo_config(ctx, platform, cell)? {
+            volumes.push(format!("{}:/.cargo/config.toml:ro", cargo_config.display()));
+        }
 
-        let result = self.run_test_in_container(docker, env, &container).await;
+        let access = self.container_access();
+        if let Some(elevated) = access.describe() {
+            warn!(access = %elevated, "Running with elevated access to the host");
+            ctx.log.record_elevated_access(cell, &elevated);
+        }
 
-        // let _ = io::stdin().read(&mut [0u8]).unwrap();
+        let container_id = platform
+            .env
+            .launch_container(daemon, env_vars, volumes, access)
+            .await?;
 
-        // Whether the test succeeded or failed, always terminate the container
-        debug!(container_id = container.id(), "Stopping container");
+        let killed = AtomicBool::new(false);
+        let executor = Executor::Container {
+            daemon,
+            container_id: &container_id,
+            killed: &killed,
+        };
+        let result = self
+            .run_test_pipeline(ctx, platform, &executor, cell, timeouts)
+            .await;
 
-        let _ = container.stop(None).await.map_err(|e| {
-            error!(
-                container_id = container.id(),
-                "Error stopping container: {}\nStop and delete this container manually", e
-            );
-        });
-        let _ = container.delete().await.map_err(|e| {
+        // Whether the test succeeded or failed, always terminate the container.  If a command timed out the container
+        // was already killed, and there's nothing left to stop
+        if killed.load(Ordering::SeqCst) {
+            debug!(%container_id, "Container was killed after a timeout");
+        } else {
+            debug!(%container_id, "Stopping container");
+
+            let _ = daemon.stop_container(&container_id).await.map_err(|e| {
+                error!(
+                    %container_id,
+                    "Error stopping container: {}\nStop and delete this container manually", e
+                );
+            });
+        }
+        let _ = daemon.delete_container(&container_id).await.map_err(|e| {
             // This is bad; there's not really anything we can do about it.  This container needs to be terminated
             // manually
             error!(
-                container_id = container.id(),
+                %container_id,
                 "Error deleting container: {}\nDelete this container manually", e
             );
         });
@@ -126,212 +667,2065 @@ impl TestCrate {
         result
     }
 
-    /// Once the Docker container is launched, run the actual test
-    async fn run_test_in_container<'docker>(
+    /// Run this test directly on this host, with a scratch target dir under the cache dir
+    async fn run_test_on_host(
+        &self,
+        ctx: &RunContext<'_>,
+        platform: Platform<'_>,
+        cell: &CellId,
+        timeouts: &Timeouts,
+    ) -> Result<TestResult> {
+        let target_dir = self.host_target_dir(ctx.cache_dir, platform);
+        let sandbox = HostSandbox::new(&self.path, target_dir, &self.env_vars(platform))?;
+        let executor = Executor::Host {
+            sandbox: &sandbox,
+            cargo_config: self.write_cargo_config(ctx, platform, cell)?,
+        };
+
+        self.run_test_pipeline(ctx, platform, &executor, cell, timeouts)
+            .await
+    }
+
+    /// The scratch target dir for a test on the host.  Each target and profile gets its own, so cells don't build over
+    /// each other's output
+    fn host_target_dir(&self, cache_dir: &Path, platform: Platform<'_>) -> PathBuf {
+        cache_dir
+            .join("host-target")
+            .join(self.name())
+            .join(platform.target.kind().name())
+            .join(platform.profile.name())
+    }
+
+    /// If the rustflags go in a cargo config file, generate the file in the cell's log dir and return its path
+    fn write_cargo_config(
+        &self,
+        ctx: &RunContext<'_>,
+        platform: Platform<'_>,
+        cell: &CellId,
+    ) -> Result<Option<PathBuf>> {
+        if self.rustflags_mode() != RustflagsMode::CargoConfig {
+            return Ok(None);
+        }
+
+        let rustflags = self
+            .rustflags(platform)
+            .iter()
+            .map(|flag| toml_string(flag))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let contents = format!(
+            "[target.{}]\nrustflags = [{}]\n",
+            platform.target.triple(),
+            rustflags
+        );
+
+        ctx.log
+            .write_cell_file(cell, "cargo-config.toml", &contents)
+            .map(Some)
+    }
+
+    /// Once the environment is ready, run the actual test
+    async fn run_test_pipeline(
         &self,
-        docker: &'docker Docker,
-        env: &Environment,
-        container: &Container<'docker>,
+        ctx: &RunContext<'_>,
+        platform: Platform<'_>,
+        executor: &Executor<'_>,
+        cell: &CellId,
+        timeouts: &Timeouts,
     ) -> Result<TestResult> {
+        let steps = StepRunner {
+            executor,
+            timeouts,
+            log: ctx.log,
+            deadline: timeouts.cell.map(|cell| Instant::now() + cell),
+            output: CellOutput {
+                progress: ctx.progress,
+                cell,
+            },
+            cancel: ctx.cancel,
+        };
+
         // Always start with a clean target dir.  We don't want a prior test run to interfere
-        let (exec_details, output) =
-            docker::exec_in_container(docker, container, vec!["cargo", "clean"]).await?;
-
-        let exit_code = exec_details
-            .exit_code
-            .expect("Non-running process must have exit code");
-        if exit_code != 0 {
-            return Ok(TestResult::Failed {
-                output: format!(
-                    "`cargo clean` terminated with exit code {}: \n{}",
-                    exit_code, output
-                ),
-            });
+        let output = steps
+            .exec(Step::Clean, ExecRequest::new(vec!["cargo", "clean"]))
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Clean, "cargo clean", &output) {
+            return Ok(failure);
         }
 
         // Build the binary first; if there are any problems related to the build env or linker they will appear here
-        let (exec_details, output) = docker::exec_in_container(
-            docker,
-            container,
-            vec!["cargo", "build", "--target", env.musl_target()],
-        )
-        .await?;
-
-        let exit_code = exec_details
-            .exit_code
-            .expect("Non-running process must have exit code");
-        if exit_code != 0 {
-            return Ok(TestResult::Failed {
-                output: format!(
-                    "`cargo build` terminated with exit code {}: \n{}",
-                    exit_code, output
-                ),
+        let output = steps
+            .exec(
+                Step::Build,
+                ExecRequest::new(executor.cargo_args(self.cargo_command("build", platform))),
+            )
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Build, "cargo build", &output) {
+            // A link failure buries the linker's errors in thousands of lines of output, so pick them out
+            let errors = if matches!(failure, TestResult::Failed { .. }) {
+                LinkError::parse(&output.combined)
+            } else {
+                Vec::new()
+            };
+            if errors.is_empty() {
+                return Ok(failure);
+            }
+
+            // Undefined symbols usually come from a library that isn't linked, so find which archives define them.
+            // If that doesn't work out, the symbols alone still say what the problem is
+            let symbols = LinkError::undefined_symbols(&errors);
+            let errors = if symbols.is_empty() {
+                errors
+            } else {
+                let output = steps
+                    .exec(
+                        Step::Analyze,
+                        ExecRequest::new(LinkError::symbol_search_command(
+                            platform.target,
+                            &symbols,
+                        )),
+                    )
+                    .await?;
+                let output = if output.succeeded() {
+                    output.stdout
+                } else {
+                    String::new()
+                };
+
+                LinkError::attribute_undefined(errors, &output)
+            };
+
+            return Ok(TestResult::LinkFailed {
+                errors,
+                log: ctx.log.step_log_path(cell, Step::Build),
             });
         }
 
+        ctx.log.record_build_time(cell, output.duration);
+
+        // The build can succeed with warnings from the linker that the binary isn't as static as it seems
+        let caveats = runtime_caveats(&output.combined);
+
         // Now run the binary.  This is to detect problems on startup, like mixed C or C++ runtimes or missing library deps
-        let (exec_details, output) = docker::exec_in_container(
-            docker,
-            container,
-            vec!["cargo", "run", "--target", env.musl_target()],
-        )
-        .await?;
-
-        let exit_code = exec_details
-            .exit_code
-            .expect("Non-running process must have exit code");
-        if exit_code != 0 {
-            return Ok(TestResult::Failed {
-                output: format!(
-                    "`cargo run` terminated with exit code {}: \n{}",
-                    exit_code, output
-                ),
-            });
+        let assertions = self.run_assertions();
+        let mut cmd = executor.cargo_args(self.cargo_command("run", platform));
+        let mut request = match assertions {
+            Some(assertions) if !assertions.args.is_empty() => {
+                cmd.push("--".to_string());
+                cmd.extend(assertions.args.iter().cloned());
+                ExecRequest::new(cmd)
+            }
+            _ => ExecRequest::new(cmd),
+        };
+        if let Some(stdin) = assertions.and_then(|assertions| assertions.stdin.as_ref()) {
+            request = request.stdin(stdin.as_bytes());
         }
+        let output = steps.exec(Step::Run, request).await?;
 
-        // Now find the binary itself so we can run ldd on it.
-        let (exec_details, output) = docker::exec_in_container(
-            docker,
-            container,
-            vec!["find", "target", "-name", self.name()],
-        )
-        .await?;
-
-        let exit_code = exec_details
-            .exit_code
-            .expect("Non-running process must have exit code");
-        if exit_code != 0 {
-            return Ok(TestResult::Failed {
-                output: format!(
-                    "`find` terminated with exit code {}: \n{}",
-                    exit_code, output
-                ),
-            });
+        // If the crate expects a particular exit code, any other code is a misbehavior rather than a failure to run
+        if output.timed_out || assertions.and_then(|a| a.exit_code).is_none() {
+            if let Some(failure) = steps.failure(Step::Run, "cargo run", &output) {
+                return Ok(failure);
+            }
+        }
+
+        if let Some(assertions) = assertions {
+            let problems = assertions.check(&output)?;
+            if !problems.is_empty() {
+                return Ok(TestResult::Misbehaves {
+                    problems,
+                    log: ctx.log.step_log_path(cell, Step::Run),
+                });
+            }
+        }
+
+        // Now find the binary itself so we can analyze it.
+        let target_dir = executor.target_dir();
+        let output = steps
+            .exec(
+                Step::Analyze,
+                ExecRequest::new(vec![
+                    "find",
+                    &target_dir,
+                    "-type",
+                    "f",
+                    "-name",
+                    &self.crate_name,
+                ]),
+            )
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Analyze, "find", &output) {
+            return Ok(failure);
         }
 
-        // The output should be a single line with the path relative to the working directory
-        let binary_path = output.trim().to_string();
+        // stdout should be a single line with the path relative to the working directory
+        let binary_path = output.stdout.trim().to_string();
         debug!(binary_path = %binary_path,
             "Checking binary for dynamic lib dependencies");
 
-        let (exec_details, output) =
-            docker::exec_in_container(docker, container, vec!["ldd", &binary_path]).await?;
-
-        let exit_code = exec_details
-            .exit_code
-            .expect("Non-running process must have exit code");
-        if exit_code != 0 {
-            return Ok(TestResult::Failed {
-                output: format!(
-                    "`ldd` terminated with exit code {}: \n{}",
-                    exit_code, output
-                ),
+        let output = steps
+            .exec(
+                Step::Analyze,
+                ExecRequest::new(vec!["stat", "-c", "%s", &binary_path]),
+            )
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Analyze, "stat", &output) {
+            return Ok(failure);
+        }
+
+        let binary_size = output
+            .stdout
+            .trim()
+            .parse::<u64>()
+            .wrap_err_with(|| eyre!("`stat` reported an invalid size for {}", binary_path))?;
+        ctx.log.record_binary_size(cell, binary_size);
+
+        // Read the ELF headers rather than asking `ldd`, which can't load a binary for another architecture
+        let output = steps
+            .exec(
+                Step::Analyze,
+                ExecRequest::new(ElfInfo::readelf_command(&binary_path)),
+            )
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Analyze, "readelf", &output) {
+            return Ok(failure);
+        }
+
+        let elf = ElfInfo::parse(&output.stdout)?;
+
+        // A binary for the wrong machine could only have run at all if the runner wasn't emulating anything
+        if let Some(expected) = machine_for_triple(platform.target.triple()) {
+            if elf.machine != expected {
+                return Ok(TestResult::Failed {
+                    step: Step::Analyze,
+                    message: format!(
+                        "The binary is for {} but the target {} is for {}",
+                        elf.machine,
+                        platform.target.triple(),
+                        expected
+                    ),
+                    log: ctx.log.step_log_path(cell, Step::Analyze),
+                });
+            }
+        }
+
+        // Take stock of the native libraries the build scripts linked, and which of them the binary needs at runtime
+        let output = steps
+            .exec(
+                Step::Analyze,
+                ExecRequest::new(BuildScriptOutput::find_command(
+                    &target_dir,
+                    platform.target.triple(),
+                )),
+            )
+            .await?;
+
+        if let Some(failure) = steps.failure(Step::Analyze, "find", &output) {
+            return Ok(failure);
+        }
+
+        let outputs = BuildScriptOutput::parse(&output.stdout);
+        if !outputs.is_empty() {
+            // The env vars that tell a build script where a library is can come from the image as well as the test
+            let mut env_vars = self.env_vars(platform);
+            let output = steps
+                .exec(Step::Analyze, ExecRequest::new(vec!["env"]))
+                .await?;
+            if output.succeeded() {
+                env_vars.extend(inventory::parse_env(&output.stdout));
+            }
+
+            let libs = inventory(
+                &self.resolved_metadata()?,
+                &outputs,
+                &env_vars,
+                platform.target.triple(),
+                &elf,
+            );
+            debug!(?libs, "Native libraries");
+            ctx.log.record_native_libs(cell, libs);
+        }
+
+        if !elf.is_static() {
+            // Find where each dependency comes from, and whether there's a static archive that could replace it.  If
+            // that doesn't work out, the sonames alone still say what the problem is
+            let deps = elf.dynamic_deps();
+            let output = steps
+                .exec(
+                    Step::Analyze,
+                    ExecRequest::new(DynamicDep::resolve_command(platform.target, &deps)),
+                )
+                .await?;
+            let output = if output.succeeded() {
+                output.stdout
+            } else {
+                String::new()
+            };
+
+            return Ok(TestResult::NonStaticBinary {
+                deps: DynamicDep::parse(&deps, platform.env.name(), &output),
             });
         }
 
-        if output.contains("not a dynamic executable") || output.contains("statically linked") {
-            // Yay!
-            Ok(TestResult::StaticBinary)
-        } else {
-            Ok(TestResult::NonStaticBinary {
-                deps: output.lines().map(|l| l.to_string()).collect(),
-            })
+        if !caveats.is_empty() {
+            return Ok(TestResult::StaticWithCaveats { caveats });
+        }
+
+        // Yay!
+        Ok(TestResult::StaticBinary)
+    }
+
+    /// The `cargo` command line for a subcommand that builds the crate, like `build` or `run`, for the platform's
+    /// target and profile, including the variant's features and args if any
+    fn cargo_command(&self, subcommand: &str, platform: Platform<'_>) -> Vec<String> {
+        let mut cmd = vec![
+            "cargo".to_string(),
+            subcommand.to_string(),
+            "--target".to_string(),
+            platform.target.triple().to_string(),
+        ];
+        cmd.extend(
+            platform
+                .profile
+                .cargo_args()
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+
+        if let Some(variant) = &self.variant {
+            if !variant.features.is_empty() {
+                cmd.push("--features".to_string());
+                cmd.push(variant.features.join(","));
+            }
+            if variant.no_default_features {
+                cmd.push("--no-default-features".to_string());
+            }
+            cmd.extend(variant.cargo_args.iter().cloned());
         }
+
+        cmd
     }
 
     /// Get the env vars for this test
     ///
     /// Each env var is a string with a name and an optional value:
-    ///  `NAME[=VALUE]`
+    ///  `NAME=VALUE`
     ///
-    /// This comes from the package metadata
-    fn env_vars(&self) -> Vec<String> {
-        const RUSTFLAGS: &str = "-C target-feature=+crt-static";
+    /// This comes from the package metadata, and the variant's metadata if this is a variant.  In each case the env
+    /// vars for the specific environment are merged on top of the common ones.  Then come the settings of the build
+    /// profile and the runner if the target needs one.  Unless the rustflags go in a cargo config file, they're
+    /// added to the appropriate env var
+    fn env_vars(&self, platform: Platform<'_>) -> Vec<String> {
+        let env = platform.env;
+        let mut env_vars = Vec::new();
+
+        self.package_metadata
+            .env
+            .merge_into(&mut env_vars, env.name());
+
+        if let Some(variant) = &self.variant {
+            variant.env.merge_into(&mut env_vars, env.name());
+        }
+
+        // The profile's settings win over any the crate sets itself, since that's what the profile is testing
+        for var in platform.profile.env_vars() {
+            let name = var.split('=').next().unwrap();
+            env_vars.retain(|v| v.split('=').next().unwrap() != name);
+            env_vars.push(var.to_string());
+        }
+
+        // The linker is always scoped to the target, so build scripts and proc macros are linked the usual way
+        if let Some(program) = self.linker().program() {
+            env_vars.push(format!(
+                "{}={}",
+                platform.target.cargo_env_var("LINKER"),
+                program
+            ));
+        }
+
+        // Binaries for another architecture are run with an emulator
+        if let Some(runner) = platform.target.runner() {
+            env_vars.push(format!(
+                "{}={}",
+                platform.target.cargo_env_var("RUNNER"),
+                runner
+            ));
+        }
 
-        let mut env_vars = self.package_metadata.env.clone();
+        let rustflags_var = match self.rustflags_mode() {
+            RustflagsMode::Global => "RUSTFLAGS".to_string(),
+            RustflagsMode::TargetEnv => platform.target.cargo_env_var("RUSTFLAGS"),
+            RustflagsMode::CargoConfig => return env_vars,
+        };
 
-        // If there's a RUSTFLAGS env in here, combine it with the RUSTFLAGS we always add
-        if let Some(var) = env_vars.iter_mut().find(|v| v.starts_with("RUSTFLAGS=")) {
-            *var = format!("{} {}", var, RUSTFLAGS);
+        // If the env already sets this var, combine it with the rustflags we always add
+        let rustflags = self.rustflags(platform).join(" ");
+        let prefix = format!("{}=", rustflags_var);
+        if let Some(var) = env_vars.iter_mut().find(|v| v.starts_with(&prefix)) {
+            *var = format!("{} {}", var, rustflags);
         } else {
-            env_vars.push(format!("RUSTFLAGS={}", RUSTFLAGS));
+            env_vars.push(format!("{}{}", prefix, rustflags));
+        }
+
+        env_vars
+    }
+
+    /// The flags to pass to rustc when compiling for the target: any from the variant, any for the linker setup, any
+    /// for the kind of target, and the flag that makes the binary static
+    fn rustflags(&self, platform: Platform<'_>) -> Vec<String> {
+        let mut rustflags = Vec::new();
+
+        if let Some(variant) = &self.variant {
+            rustflags.extend(
+                variant
+                    .rustflags
+                    .iter()
+                    .flat_map(|flags| flags.split_whitespace())
+                    .map(|flag| flag.to_string()),
+            );
         }
 
+        rustflags.extend(
+            self.linker()
+                .rustflags()
+                .iter()
+                .map(|flag| flag.to_string()),
+        );
+        rustflags.extend(
+            platform
+                .target
+                .kind()
+                .rustflags()
+                .iter()
+                .map(|flag| flag.to_string()),
+        );
+        rustflags.push("-C".to_string());
+        rustflags.push("target-feature=+crt-static".to_string());
+
+        rustflags
+    }
+
+    /// The program the target is actually linked with: whatever the env vars set the target's linker to, which
+    /// includes the linker setup and anything the crate sets itself, or else the environment's own linker
+    fn linker_program(&self, platform: Platform<'_>, env_vars: &[String]) -> String {
+        let prefix = format!("{}=", platform.target.cargo_env_var("LINKER"));
+
         env_vars
+            .iter()
+            .rev()
+            .find_map(|var| var.strip_prefix(&prefix))
+            .unwrap_or_else(|| platform.target.default_linker())
+            .to_string()
+    }
+
+    fn run_assertions(&self) -> Option<&RunAssertions> {
+        self.variant
+            .as_ref()
+            .and_then(|variant| variant.run.as_ref())
+            .or(self.package_metadata.run.as_ref())
+    }
+
+    fn container_access(&self) -> &ContainerAccess {
+        self.variant
+            .as_ref()
+            .and_then(|variant| variant.container.as_ref())
+            .unwrap_or(&self.package_metadata.container)
+    }
+
+    fn linker(&self) -> Linker {
+        self.variant
+            .as_ref()
+            .and_then(|variant| variant.linker)
+            .unwrap_or(self.package_metadata.linker)
+    }
+
+    fn rustflags_mode(&self) -> RustflagsMode {
+        self.variant
+            .as_ref()
+            .and_then(|variant| variant.rustflags_mode)
+            .unwrap_or(self.package_metadata.rustflags_mode)
     }
 
     /// Get the docker volume mounts for this test
     ///
     /// Each one is in the usual docker format
-    fn volumes(&self, cache_dir: &Path, env: &Environment) -> Vec<String> {
+    fn volumes(&self, cache_dir: &Path, cargo_home: &str) -> Vec<String> {
         // Use dedicated volumes for the cargo cache so repeated tests aren't starting from nothing,
         // and always mount the crate root at /build
-        vec![
-            format!(
-                "{}/registry:{}/registry",
-                cache_dir.display(),
-                env.cargo_home()
-            ),
+        let mut volumes = vec![
+            format!("{}/registry:{}/registry", cache_dir.display(), cargo_home),
             format!(
                 "{}/registry-index:{}/registry/index",
                 cache_dir.display(),
-                env.cargo_home()
+                cargo_home
             ),
             format!(
                 "{}/registry-git:{}/registry/git",
                 cache_dir.display(),
-                env.cargo_home()
+                cargo_home
             ),
-            format!("{}/git-db:{}/git/db", cache_dir.display(), env.cargo_home()),
+            format!("{}/git-db:{}/git/db", cache_dir.display(), cargo_home),
             format!("{}:/build", self.path.display()),
-        ]
+        ];
+        volumes.extend(
+            self.host_dirs
+                .iter()
+                .map(|dir| format!("{0}:{0}:ro", dir.display())),
+        );
+
+        volumes
     }
 }
 
-/// The result of a single build test of a single crate on a single environment
-pub(crate) enum TestResult {
-    /// Great success!  The build succeeded and the binary was static
-    StaticBinary,
+/// Everything a test run needs besides the test and the environment
+pub(crate) struct RunContext<'a> {
+    /// The container daemon, which is only needed (and only used) for environments which run in a Docker container
+    pub daemon: Option<&'a dyn ContainerDaemon>,
 
-    /// Moderate success.  The build succeeded but the resulting binary depends on one or more shared objects
-    NonStaticBinary { deps: Vec<String> },
+    /// Where the cargo caches and other state that is kept between runs are located
+    pub cache_dir: &'a Path,
+
+    /// The global timeouts, to which each test's overrides are applied
+    pub timeouts: &'a Timeouts,
+
+    /// Receives updates as each test runs
+    pub progress: &'a dyn Progress,
 
-    /// The cargo build command failed
-    Failed { output: String },
+    /// Where the output of each step is logged
+    pub log: &'a RunLog,
+
+    /// What probing found in each environment, by environment name.  A test in an environment that wasn't probed
+    /// skips the preflight check and just runs
+    pub capabilities: &'a BTreeMap<String, Capabilities>,
+
+    /// Stops the test partway through if the run is cancelled
+    pub cancel: &'a Cancel,
 }
 
-/// Load specific, named tests from the test crates directory
-pub(crate) fn load_tests(test_names: Vec<String>) -> Result<Vec<TestCrate>> {
-    let test_crates = load_all_tests()?;
+/// Where the commands which make up a test are executed
+enum Executor<'a> {
+    /// In a Docker container that was launched for this test
+    Container {
+        daemon: &'a dyn ContainerDaemon,
+        container_id: &'a str,
 
-    test_names
-        .into_iter()
-        .map(|test_name| {
-            test_crates
-                .iter()
-                .find(|test_crate| test_crate.name == test_name)
-                .cloned()
-                .ok_or_else(|| eyre!("'{}' is not a valid test name", test_name))
-        })
-        .collect::<Result<Vec<_>>>()
+        /// Set once a command times out, since the daemon kills the whole container to stop it
+        killed: &'a AtomicBool,
+    },
+
+    /// Directly on this host
+    Host {
+        sandbox: &'a HostSandbox,
+
+        /// The generated cargo config file, if any.  Unlike in a container there's no directory above the crate
+        /// to put it in, so it's passed to cargo with `--config` instead
+        cargo_config: Option<PathBuf>,
+    },
 }
 
-/// Discover all of the test crates, reading their metadata
-pub(crate) fn load_all_tests() -> Result<Vec<TestCrate>> {
-    let crates_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("crates");
+impl<'a> Executor<'a> {
+    /// Run a command in this executor's environment, passing its output to `output` as it runs
+    async fn exec(&self, request: ExecRequest, output: &dyn OutputSink) -> Result<ExecOutput> {
+        match self {
+            Executor::Container {
+                daemon,
+                container_id,
+                killed,
+            } => {
+                let output = daemon.exec(container_id, &request, output).await?;
+                if output.timed_out {
+                    killed.store(true, Ordering::SeqCst);
+                }
+
+                Ok(output)
+            }
+            Executor::Host { sandbox, .. } => sandbox.exec(&request, output).await,
+        }
+    }
+
+    /// The cargo target dir, in a form that can be passed to commands run by `exec`
+    fn target_dir(&self) -> String {
+        match self {
+            Executor::Container { .. } => "target".to_string(),
+            Executor::Host { sandbox, .. } => sandbox.target_dir().display().to_string(),
+        }
+    }
+
+    /// Add any args this executor needs to a `cargo` command line that builds the crate
+    fn cargo_args(&self, mut cmd: Vec<String>) -> Vec<String> {
+        if let Executor::Host {
+            cargo_config: Some(cargo_config),
+            ..
+        } = self
+        {
+            cmd.push("--config".to_string());
+            cmd.push(cargo_config.display().to_string());
+        }
+
+        cmd
+    }
+}
+
+/// Runs the commands for each step of a test, enforcing the step and cell timeouts
+struct StepRunner<'a> {
+    executor: &'a Executor<'a>,
+    timeouts: &'a Timeouts,
+    log: &'a RunLog,
+
+    /// When the whole test must be finished by, if there's a per-cell timeout
+    deadline: Option<Instant>,
+
+    /// Receives the output of each command, and is told when each step starts
+    output: CellOutput<'a>,
+
+    /// Stops the command that's running if the run is cancelled
+    cancel: &'a Cancel,
+}
+
+impl<'a> StepRunner<'a> {
+    /// Run a command as part of a step, with a timeout of whichever is sooner: the step's timeout or the end of the cell
+    async fn exec(&self, step: Step, request: ExecRequest) -> Result<ExecOutput> {
+        let mut timeout = self.timeouts.for_step(step);
+        if let Some(deadline) = self.deadline {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+
+        let cell = self.output.cell;
+        self.output.progress.state(cell, CellState::InStep(step));
+
+        let request = request.timeout(timeout);
+        let log = self.log.open_step_log(cell, step, &request, &self.output)?;
+        // Dropping the command stops it.  The error then skips the rest of the test, but not the cleanup of the container
+        let output = tokio::select! {
+            output = self.executor.exec(request.clone(), &log) => output?,
+            _ = self.cancel.cancelled() => return Err(eyre!("The run was cancelled during the {} step", step)),
+        };
+        self.log.record_command(cell, step, &request, &output);
+
+        Ok(output)
+    }
+
+    /// If the command for a step didn't succeed, the corresponding test result
+    fn failure(&self, step: Step, description: &str, output: &ExecOutput) -> Option<TestResult> {
+        let log = self.log.step_log_path(self.output.cell, step);
+
+        if output.timed_out {
+            Some(TestResult::TimedOut {
+                step,
+                after: output.duration,
+                log,
+            })
+        } else if !output.succeeded() {
+            Some(TestResult::Failed {
+                step,
+                message: format!(
+                    "`{}` terminated with exit code {}",
+                    description, output.exit_code
+                ),
+                log,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The result of a single build test of a single crate on a single environment
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TestResult {
+    /// Great success!  The build succeeded and the binary was static
+    StaticBinary,
+
+    /// Qualified success.  The binary is static, but the linker warned that some functions it uses still need shared
+    /// libraries at runtime, like glibc's NSS functions.  `caveats` are those warnings
+    StaticWithCaveats { caveats: Vec<String> },
+
+    /// Moderate success.  The build succeeded but the resulting binary depends on one or more shared objects
+    NonStaticBinary { deps: Vec<DynamicDep> },
+
+    /// The binary runs, but doesn't do what the crate's run assertions say it should.  `problems` are the assertions
+    /// that failed, and `log` is the log file of the run step
+    Misbehaves { problems: Vec<String>, log: PathBuf },
+
+    /// One of the steps failed.  `log` is the log file with the output of that step
+    Failed {
+        step: Step,
+        message: String,
+        log: PathBuf,
+    },
+
+    /// The build failed to link.  `errors` are the root causes found in the linker's errors, and `log` is the log
+    /// file of the build step
+    LinkFailed {
+        errors: Vec<LinkError>,
+        log: PathBuf,
+    },
+
+    /// A step didn't finish in time and was killed.  `log` has whatever it produced before then
+    TimedOut {
+        step: Step,
+        after: Duration,
+        log: PathBuf,
+    },
+
+    /// The test wasn't attempted, because probing the environment found that something it needs isn't installed.
+    /// `missing` describes each thing
+    MissingRequirements { missing: Vec<String> },
+}
+
+/// Find the linker warnings in build output which mean a static binary still needs shared libraries at runtime.
+///
+/// glibc's are like "Using 'getaddrinfo' in statically linked applications requires at runtime the shared libraries
+/// from the glibc version used for linking"
+fn runtime_caveats(build_output: &str) -> Vec<String> {
+    let mut caveats = Vec::new();
+    for line in build_output.lines() {
+        if line.contains("statically linked applications requires at runtime") {
+            // The warning can be prefixed with the object file and function it came from
+            let caveat = match line.find("Using '") {
+                Some(start) => &line[start..],
+                None => line.trim(),
+            };
+
+            if !caveats.iter().any(|existing| existing == caveat) {
+                caveats.push(caveat.to_string());
+            }
+        }
+    }
+
+    caveats
+}
+
+/// Load specific, named tests from the test crates directory.
+///
+/// The name of a crate selects that crate and all of its variants; a single variant can be selected with
+/// `crate/variant`
+pub(crate) fn load_tests(test_names: Vec<String>) -> Result<Vec<TestCrate>> {
+    let test_crates = load_all_tests()?;
+
+    let mut tests = Vec::new();
+    for test_name in test_names {
+        let matches = test_crates
+            .iter()
+            .filter(|test_crate| test_crate.name == test_name || test_crate.crate_name == test_name)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if matches.is_empty() {
+            return Err(eyre!("'{}' is not a valid test name", test_name));
+        }
+
+        tests.extend(matches);
+    }
+
+    Ok(tests)
+}
+
+/// The directory with a subdirectory for each test crate
+pub(crate) fn crates_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("crates")
+}
+
+/// The root dir of every test crate, sorted by name
+pub(crate) fn crate_dirs() -> Result<Vec<PathBuf>> {
+    let crates_dir = crates_dir();
 
     debug!(crates_dir = %crates_dir.display(),
         "Enumerating crates");
 
-    let mut test_crates = Vec::new();
+    let mut dirs = Vec::new();
     for entry in crates_dir.read_dir()? {
         let entry = entry?;
         if entry.file_type()?.is_dir() {
-            test_crates.push(TestCrate::load(entry.path())?);
+            dirs.push(entry.path());
         }
     }
+    dirs.sort();
+
+    Ok(dirs)
+}
 
-    test_crates.sort_unstable_by(|lhs, rhs| lhs.name.partial_cmp(&rhs.name).unwrap());
+/// Load every test crate on its own, so one invalid crate doesn't hide the problems with the others, and make sure
+/// none is still the `main.rs` that `new-test` generated.  Each result is the tests of one crate: its default
+/// configuration and its variants
+pub(crate) fn validate_all_tests() -> Result<Vec<(PathBuf, Result<Vec<TestCrate>>)>> {
+    Ok(crate_dirs()?
+        .into_iter()
+        .map(|dir| {
+            let tests = TestCrate::load(dir.clone()).and_then(|test| {
+                scaffold::check_finished(&dir)?;
+                Ok(test.with_variants())
+            });
+            (dir, tests)
+        })
+        .collect())
+}
+
+/// Discover all of the test crates, reading their metadata
+pub(crate) fn load_all_tests() -> Result<Vec<TestCrate>> {
+    let mut test_crates = Vec::new();
+    for dir in crate_dirs()? {
+        test_crates.extend(TestCrate::load(dir)?.with_variants());
+    }
+
+    // Sort by crate, keeping each crate's variants right after it
+    test_crates.sort_by(|lhs, rhs| lhs.crate_name.cmp(&rhs.crate_name));
 
     Ok(test_crates)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dynamic_deps::StaticAlternative;
+    use crate::environments::TargetKind;
+    use crate::fake_docker::FakeDaemon;
+    use crate::inventory::{Linkage, NativeSource};
+    use crate::profiles::BuildProfile;
+    use crate::progress::RecordingProgress;
+    use tempfile::TempDir;
+
+    const BINARY_PATH: &str = "target/x86_64-unknown-linux-musl/debug/hello-world";
+
+    fn hello_world() -> TestCrate {
+        TestCrate::load(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("crates/hello-world"))
+            .unwrap()
+    }
+
+    fn debian_rust() -> Platform<'static> {
+        Platform::find("debian-rust", TargetKind::Musl).unwrap()
+    }
+
+    /// What `readelf` says about a static x86_64 binary
+    const STATIC_X86_64: &str =
+        "ELF Header:\n  Machine:                           Advanced Micro Devices X86-64\n";
+
+    /// A fake daemon where every step of the test succeeds, the binary says hello, and `readelf` reports
+    /// `readelf_output`
+    fn daemon_with_readelf_output(readelf_output: &str) -> FakeDaemon {
+        FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec(&["cargo", "run"], 0, "Hello, world!\n")
+            .on_exec(&["find"], 0, &format!("{}\n", BINARY_PATH))
+            .on_exec(&["stat"], 0, "1234567\n")
+            .on_exec(&["readelf"], 0, readelf_output)
+    }
+
+    /// A run log in a new temp dir, which is deleted along with the `TempDir`
+    fn run_log() -> (TempDir, RunLog) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log = RunLog::create(temp_dir.path()).unwrap();
+
+        (temp_dir, log)
+    }
+
+    async fn run(test: &TestCrate, daemon: &FakeDaemon) -> Result<TestResult> {
+        let (_temp_dir, log) = run_log();
+
+        TestRun::new(daemon, &log).run(test).await
+    }
+
+    /// A test run against the fake daemon.  Whatever a test doesn't set is the usual: a debug build for musl in
+    /// `debian-rust`, the default timeouts, and no probed capabilities, so there's no preflight check
+    struct TestRun<'a> {
+        daemon: &'a FakeDaemon,
+        log: &'a RunLog,
+        platform: Platform<'static>,
+        timeouts: Timeouts,
+        progress: Option<&'a dyn Progress>,
+        capabilities: BTreeMap<String, Capabilities>,
+        cancel: Cancel,
+    }
+
+    impl<'a> TestRun<'a> {
+        fn new(daemon: &'a FakeDaemon, log: &'a RunLog) -> Self {
+            Self {
+                daemon,
+                log,
+                platform: debian_rust(),
+                timeouts: Timeouts::default(),
+                progress: None,
+                capabilities: BTreeMap::new(),
+                cancel: Cancel::default(),
+            }
+        }
+
+        fn platform(mut self, platform: Platform<'static>) -> Self {
+            self.platform = platform;
+            self
+        }
+
+        fn timeouts(mut self, timeouts: Timeouts) -> Self {
+            self.timeouts = timeouts;
+            self
+        }
+
+        fn progress(mut self, progress: &'a dyn Progress) -> Self {
+            self.progress = Some(progress);
+            self
+        }
+
+        /// What probing found in the platform's environment
+        fn capabilities(mut self, capabilities: Capabilities) -> Self {
+            self.capabilities
+                .insert(self.platform.env.name().to_string(), capabilities);
+            self
+        }
+
+        fn cancel(mut self, cancel: Cancel) -> Self {
+            self.cancel = cancel;
+            self
+        }
+
+        async fn run(&self, test: &TestCrate) -> Result<TestResult> {
+            let progress = RecordingProgress::default();
+            let ctx = RunContext {
+                daemon: Some(self.daemon),
+                cache_dir: Path::new("/cache"),
+                timeouts: &self.timeouts,
+                progress: self.progress.unwrap_or(&progress),
+                log: self.log,
+                capabilities: &self.capabilities,
+                cancel: &self.cancel,
+            };
+
+            test.run_test(&ctx, self.platform).await
+        }
+    }
+
+    #[tokio::test]
+    async fn static_binary_is_detected() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+
+        let result = run(&hello_world(), &daemon).await.unwrap();
+
+        assert_eq!(TestResult::StaticBinary, result);
+
+        let commands = daemon
+            .calls()
+            .execs
+            .into_iter()
+            .map(|(_, request)| request.display_cmd())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "cargo clean".to_string(),
+                "cargo build --target x86_64-unknown-linux-musl".to_string(),
+                "cargo run --target x86_64-unknown-linux-musl".to_string(),
+                "find target -type f -name hello-world".to_string(),
+                format!("stat -c '%s' {}", BINARY_PATH),
+                format!(
+                    "readelf --file-header --program-headers --dynamic --wide {}",
+                    BINARY_PATH
+                ),
+                "find target -path '*/x86_64-unknown-linux-musl/*/build/*/output' -type f -print -exec cat '{}' ';'".to_string(),
+            ],
+            commands
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_sees_each_step_and_its_output() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64).on_exec(
+            &["cargo", "run"],
+            0,
+            "Hello, world!\n",
+        );
+        let progress = RecordingProgress::default();
+        let (_temp_dir, log) = run_log();
+
+        TestRun::new(&daemon, &log)
+            .progress(&progress)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        let cell = CellId::new("hello-world", "debian-rust/musl/debug");
+        let states = progress.states();
+        assert_eq!(
+            vec![
+                (cell.clone(), CellState::Starting),
+                (cell.clone(), CellState::InStep(Step::Clean)),
+                (cell.clone(), CellState::InStep(Step::Build)),
+                (cell.clone(), CellState::InStep(Step::Run)),
+                (cell.clone(), CellState::InStep(Step::Analyze)),
+                (cell.clone(), CellState::InStep(Step::Analyze)),
+                (cell.clone(), CellState::InStep(Step::Analyze)),
+                (cell.clone(), CellState::InStep(Step::Analyze)),
+            ],
+            states
+        );
+        assert!(progress.output(&cell).contains("Hello, world!\n"));
+    }
+
+    #[tokio::test]
+    async fn glibc_runtime_warnings_are_caveats() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64).on_exec_with_stderr(
+            &["cargo", "build"],
+            0,
+            "",
+            "warning: linker stderr: /usr/bin/ld: libstd.rlib(std.o): in function `lookup_host':\n\
+             warning: linker stderr: std.rs:(.text+0x1d): warning: Using 'getaddrinfo' in statically linked \
+             applications requires at runtime the shared libraries from the glibc version used for linking\n",
+        );
+        let gnu_static = Platform::find("debian-rust", TargetKind::GnuStatic).unwrap();
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .platform(gnu_static)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            TestResult::StaticWithCaveats {
+                caveats: vec!["Using 'getaddrinfo' in statically linked applications requires at runtime the shared libraries from the glibc version used for linking".to_string()]
+            },
+            result
+        );
+        assert_eq!(
+            "cargo build --target x86_64-unknown-linux-gnu",
+            daemon.calls().execs[1].1.display_cmd()
+        );
+    }
+
+    /// Run hello-world on aarch64, where `readelf` says the binary is for `machine`
+    async fn run_on_aarch64(machine: &str) -> (FakeDaemon, TestResult) {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-aarch64-musl")
+            .on_exec(&["cargo", "run"], 0, "Hello, world!\n")
+            .on_exec(
+                &["find"],
+                0,
+                "target/aarch64-unknown-linux-musl/debug/hello-world\n",
+            )
+            .on_exec(&["stat"], 0, "1234567\n")
+            .on_exec(
+                &["readelf"],
+                0,
+                &format!("ELF Header:\n  Machine:   {}\n", machine),
+            );
+        let aarch64 = Platform::find("debian-aarch64-musl", TargetKind::Musl).unwrap();
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .platform(aarch64)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        (daemon, result)
+    }
+
+    #[tokio::test]
+    async fn aarch64_binaries_run_under_qemu() {
+        let (daemon, result) = run_on_aarch64("AArch64").await;
+
+        assert_eq!(TestResult::StaticBinary, result);
+
+        let calls = daemon.calls();
+        assert!(calls.launched[0]
+            .1
+            .env
+            .contains(&"CARGO_TARGET_AARCH64_UNKNOWN_LINUX_MUSL_RUNNER=qemu-aarch64".to_string()));
+        assert_eq!(
+            "cargo run --target aarch64-unknown-linux-musl",
+            calls.execs[2].1.display_cmd()
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_for_the_wrong_machine_fails() {
+        let (_daemon, result) = run_on_aarch64("Advanced Micro Devices X86-64").await;
+
+        match result {
+            TestResult::Failed { step, message, .. } => {
+                assert_eq!(Step::Analyze, step);
+                assert_eq!(
+                    "The binary is for Advanced Micro Devices X86-64 but the target \
+                     aarch64-unknown-linux-musl is for AArch64",
+                    message
+                );
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn profile_sets_cargo_args_and_env_and_stats_are_logged() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let release_lto = Platform {
+            profile: BuildProfile::ReleaseLto,
+            ..debian_rust()
+        };
+
+        let (_temp_dir, log) = run_log();
+        let mut test = hello_world();
+        test.package_metadata.env =
+            EnvVarsMetadata::Common(vec!["CARGO_PROFILE_RELEASE_LTO=thin".to_string()]);
+        TestRun::new(&daemon, &log)
+            .platform(release_lto)
+            .run(&test)
+            .await
+            .unwrap();
+
+        let calls = daemon.calls();
+        assert_eq!(
+            "cargo build --target x86_64-unknown-linux-musl --release",
+            calls.execs[1].1.display_cmd()
+        );
+        let env = &calls.launched[0].1.env;
+        assert!(env.contains(&"CARGO_PROFILE_RELEASE_LTO=fat".to_string()));
+        assert!(env.contains(&"CARGO_PROFILE_RELEASE_CODEGEN_UNITS=1".to_string()));
+        assert!(!env.contains(&"CARGO_PROFILE_RELEASE_LTO=thin".to_string()));
+
+        let cell = CellId::new("hello-world", "debian-rust/musl/release-lto");
+        let (build_time, binary_size) = log.build_stats(&cell);
+        assert!(build_time.is_some());
+        assert_eq!(Some(1234567), binary_size);
+    }
+
+    #[tokio::test]
+    async fn native_libs_are_compared_with_needed_libs() {
+        let daemon = daemon_with_readelf_output(&format!(
+            "{} 0x0000000000000001 (NEEDED)             Shared library: [libudev.so.1]\n",
+            STATIC_X86_64
+        ))
+        .on_exec(
+            &["find", "target", "-path"],
+            0,
+            "target/x86_64-unknown-linux-musl/debug/build/libudev-sys-0123456789abcdef/output\n\
+             cargo:rustc-link-lib=udev\n\
+             target/x86_64-unknown-linux-musl/debug/build/hello-world-fedcba9876543210/output\n\
+             cargo:rustc-link-search=native=/build/target/x86_64-unknown-linux-musl/debug/build/hello-world-fedcba9876543210/out\n\
+             cargo:rustc-link-lib=static=greeting\n",
+        );
+
+        let (_temp_dir, log) = run_log();
+        TestRun::new(&daemon, &log)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            "find target -path '*/x86_64-unknown-linux-musl/*/build/*/output' -type f -print -exec cat '{}' ';'",
+            daemon.calls().execs[6].1.display_cmd()
+        );
+        let libs = log
+            .native_libs(&CellId::new("hello-world", "debian-rust/musl/debug"))
+            .into_iter()
+            .map(|lib| (lib.name, lib.package, lib.source, lib.linkage))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    "udev".to_string(),
+                    "libudev-sys".to_string(),
+                    NativeSource::System,
+                    Linkage::Dynamic
+                ),
+                (
+                    "greeting".to_string(),
+                    "hello-world".to_string(),
+                    NativeSource::Bundled,
+                    Linkage::Static
+                ),
+            ],
+            libs
+        );
+    }
+
+    #[tokio::test]
+    async fn env_vars_set_by_the_image_are_native_lib_sources() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64)
+            .on_exec(
+                &["find", "target", "-path"],
+                0,
+                "target/x86_64-unknown-linux-musl/debug/build/openssl-sys-0123456789abcdef/output\n\
+                 cargo:rustc-link-search=native=/opt/musl/lib\n\
+                 cargo:rustc-link-lib=static=ssl\n",
+            )
+            .on_exec(
+                &["env"],
+                0,
+                "PATH=/usr/local/cargo/bin:/usr/bin\nX86_64_UNKNOWN_LINUX_MUSL_OPENSSL_DIR=/opt/musl/\n",
+            );
+
+        let (_temp_dir, log) = run_log();
+        TestRun::new(&daemon, &log)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        let libs = log.native_libs(&CellId::new("hello-world", "debian-rust/musl/debug"));
+        assert_eq!(1, libs.len());
+        assert_eq!(
+            NativeSource::EnvVar("X86_64_UNKNOWN_LINUX_MUSL_OPENSSL_DIR".to_string()),
+            libs[0].source
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_that_fails_its_run_assertions_misbehaves() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64).on_exec(
+            &["cargo", "run"],
+            3,
+            "Hello, world!\n",
+        );
+        let mut test = hello_world();
+        test.package_metadata.run = Some(RunAssertions {
+            args: vec!["--list".to_string()],
+            stdin: Some("input".to_string()),
+            exit_code: Some(0),
+            stdout_matches: vec!["^Hello".to_string(), "Found block device".to_string()],
+            ..Default::default()
+        });
+
+        let result = run(&test, &daemon).await.unwrap();
+
+        match result {
+            TestResult::Misbehaves { problems, .. } => assert_eq!(
+                vec![
+                    "Exited with code 3 instead of 0".to_string(),
+                    "stdout doesn't match `Found block device`".to_string(),
+                ],
+                problems
+            ),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let (_, request) = &daemon.calls().execs[2];
+        assert_eq!(
+            "cargo run --target x86_64-unknown-linux-musl -- --list",
+            request.display_cmd()
+        );
+        assert_eq!(Some(b"input".to_vec()), request.stdin);
+    }
+
+    #[tokio::test]
+    async fn binary_that_passes_its_run_assertions_is_analyzed() {
+        let daemon =
+            daemon_with_readelf_output(STATIC_X86_64).on_exec(&["cargo", "run"], 2, "usage\n");
+        let mut test = hello_world();
+        test.package_metadata.run = Some(RunAssertions {
+            exit_code: Some(2),
+            stdout_excludes: vec!["panicked".to_string()],
+            ..Default::default()
+        });
+
+        assert_eq!(TestResult::StaticBinary, run(&test, &daemon).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn dynamic_deps_are_reported() {
+        let daemon = daemon_with_readelf_output(&format!(
+            "{}      [Requesting program interpreter: /lib/ld-musl-x86_64.so.1]\n \
+             0x0000000000000001 (NEEDED)             Shared library: [libc.so]\n",
+            STATIC_X86_64
+        ))
+        .on_exec(
+            &["bash", "-c"],
+            0,
+            "== libc.so\n\
+             path /usr/lib/x86_64-linux-musl/libc.so\n\
+             package musl\n\
+             archive /usr/lib/x86_64-linux-musl/libc.a\n\
+             archive-package musl-dev\n",
+        );
+
+        let result = run(&hello_world(), &daemon).await.unwrap();
+
+        assert_eq!(
+            TestResult::NonStaticBinary {
+                deps: vec![
+                    DynamicDep {
+                        soname: "libc.so".to_string(),
+                        path: Some("/usr/lib/x86_64-linux-musl/libc.so".to_string()),
+                        package: Some("musl".to_string()),
+                        static_alternative: Some(StaticAlternative {
+                            env: "debian-rust".to_string(),
+                            path: "/usr/lib/x86_64-linux-musl/libc.a".to_string(),
+                            package: Some("musl-dev".to_string()),
+                        }),
+                    },
+                    DynamicDep {
+                        soname: "/lib/ld-musl-x86_64.so.1".to_string(),
+                        ..Default::default()
+                    },
+                ]
+            },
+            result
+        );
+
+        // The deps are passed to the script as args, not as part of it, after the dirs to look in
+        let resolve = daemon.calls().execs.last().unwrap().1.cmd.clone();
+        assert_eq!(debian_rust().target.lib_dirs().join(" "), resolve[4]);
+        assert_eq!(
+            vec!["libc.so", "/lib/ld-musl-x86_64.so.1"],
+            resolve[5..].to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_requirements_fail_before_launching_anything() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let (_temp_dir, log) = run_log();
+        let mut test = hello_world();
+        test.package_metadata.linker = Linker::MuslGcc;
+        let capabilities = Capabilities {
+            rustc: Some("rustc 1.56.0".to_string()),
+            targets: vec!["x86_64-unknown-linux-gnu".to_string()],
+            programs: vec![("readelf".to_string(), "/usr/bin/readelf".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+
+        let result = TestRun::new(&daemon, &log)
+            .capabilities(capabilities)
+            .run(&test)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            TestResult::MissingRequirements {
+                missing: vec![
+                    "the x86_64-unknown-linux-musl target isn't installed".to_string(),
+                    "`musl-gcc` isn't installed".to_string(),
+                ]
+            },
+            result
+        );
+        assert!(daemon.calls().launched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn linker_that_cant_link_for_the_target_is_unsupported() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let (_temp_dir, log) = run_log();
+        let mut test = hello_world();
+        test.package_metadata.linker = Linker::MuslGcc;
+
+        let result = TestRun::new(&daemon, &log)
+            .platform(Platform::find("debian-rust", TargetKind::GnuStatic).unwrap())
+            .run(&test)
+            .await
+            .unwrap();
+        assert_eq!(
+            TestResult::MissingRequirements {
+                missing: vec!["the musl-gcc linker only links against musl, not glibc".to_string()]
+            },
+            result
+        );
+
+        test.package_metadata.linker = Linker::ClangLld;
+        let result = TestRun::new(&daemon, &log)
+            .platform(Platform::find("debian-aarch64-musl", TargetKind::Musl).unwrap())
+            .run(&test)
+            .await
+            .unwrap();
+        assert_eq!(
+            TestResult::MissingRequirements {
+                missing: vec![
+                    "the clang-lld linker can't link for aarch64-unknown-linux-musl".to_string()
+                ]
+            },
+            result
+        );
+        assert!(daemon.calls().launched.is_empty());
+    }
+
+    #[test]
+    fn linker_program_is_what_the_target_is_linked_with() {
+        let mut test = hello_world();
+        let aarch64 = Platform::find("debian-aarch64-musl", TargetKind::Musl).unwrap();
+        let linker_program =
+            |test: &TestCrate, platform| test.linker_program(platform, &test.env_vars(platform));
+
+        assert_eq!("cc", linker_program(&test, debian_rust()));
+        assert_eq!("aarch64-linux-musl-gcc", linker_program(&test, aarch64));
+
+        test.package_metadata.env = EnvVarsMetadata::Common(vec![
+            "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_LINKER=clang".to_string(),
+        ]);
+        assert_eq!("clang", linker_program(&test, debian_rust()));
+
+        test.package_metadata.linker = Linker::MuslGcc;
+        assert_eq!("musl-gcc", linker_program(&test, debian_rust()));
+    }
+
+    #[tokio::test]
+    async fn failed_build_stops_the_test() {
+        let daemon = FakeDaemon::new().with_image("debian-rust").on_exec(
+            &["cargo", "build"],
+            101,
+            "error: linking with `cc` failed",
+        );
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        match result {
+            TestResult::Failed { step, message, log } => {
+                assert_eq!(Step::Build, step);
+                assert_eq!("`cargo build` terminated with exit code 101", message);
+
+                let log = std::fs::read_to_string(log).unwrap();
+                assert!(log.contains("$ cargo build --target x86_64-unknown-linux-musl\n"));
+                assert!(log.contains("error: linking with `cc` failed"));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Nothing after the build should have been attempted
+        assert_eq!(2, daemon.calls().execs.len());
+    }
+
+    #[tokio::test]
+    async fn link_failure_is_explained() {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec_with_stderr(
+                &["cargo", "build"],
+                101,
+                "",
+                "error: linking with `cc` failed: exit status: 1\n  \
+                 = note: /usr/bin/ld: mod.rs:(.text+0x1f): undefined reference to `udev_new'\n          \
+                 /usr/bin/ld: cannot find -lz: No such file or directory\n",
+            )
+            .on_exec(
+                &["bash", "-c"],
+                0,
+                "/usr/lib/x86_64-linux-gnu/libudev.a udev_new\n",
+            );
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        match result {
+            TestResult::LinkFailed { errors, log } => {
+                assert_eq!(
+                    vec![
+                        LinkError::UndefinedSymbols {
+                            archive: Some("/usr/lib/x86_64-linux-gnu/libudev.a".to_string()),
+                            symbols: vec!["udev_new".to_string()],
+                        },
+                        LinkError::MissingLibrary {
+                            lib: "z".to_string()
+                        },
+                    ],
+                    errors
+                );
+                assert!(log.ends_with("build.log"));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // The symbols are looked up in the target's archives, and nothing else is attempted
+        let execs = daemon.calls().execs;
+        assert_eq!(3, execs.len());
+        assert_eq!(&["bash", "-c"], &execs[2].1.cmd[..2]);
+        assert_eq!(debian_rust().target.lib_dirs().join(" "), execs[2].1.cmd[4]);
+        assert_eq!(vec!["udev_new"], execs[2].1.cmd[5..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn timed_out_step_is_reported_with_partial_output() {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec_timeout(&["cargo", "run"], "Hello, world!\n");
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        match result {
+            TestResult::TimedOut { step, log, .. } => {
+                assert_eq!(Step::Run, step);
+                assert_eq!(
+                    "$ cargo run --target x86_64-unknown-linux-musl\nHello, world!\n",
+                    std::fs::read_to_string(log).unwrap()
+                );
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // The container was killed by the timeout, so nothing else is run in it, and it's deleted without trying to
+        // stop it first
+        let calls = daemon.calls();
+        assert_eq!(3, calls.execs.len());
+        assert!(calls.stopped.is_empty());
+        assert_eq!(vec!["container-1".to_string()], calls.deleted);
+    }
+
+    #[tokio::test]
+    async fn cancelled_run_stops_the_command_and_cleans_up() {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec_block(&["cargo", "build"]);
+        let cancel = Cancel::default();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+
+        let (_temp_dir, log) = run_log();
+        let result = TestRun::new(&daemon, &log)
+            .cancel(cancel)
+            .run(&hello_world())
+            .await;
+
+        assert_eq!(
+            "The run was cancelled during the build step",
+            result.unwrap_err().to_string()
+        );
+
+        // Nothing else is run, and the container is stopped and deleted as usual
+        let calls = daemon.calls();
+        assert_eq!(2, calls.execs.len());
+        assert_eq!(vec!["container-1".to_string()], calls.stopped);
+        assert_eq!(vec!["container-1".to_string()], calls.deleted);
+    }
+
+    #[tokio::test]
+    async fn step_timeouts_come_from_crate_overrides() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let mut test = hello_world();
+        test.package_metadata.timeouts.run = Some(5);
+
+        run(&test, &daemon).await.unwrap();
+
+        let timeouts = daemon
+            .calls()
+            .execs
+            .into_iter()
+            .map(|(_, request)| request.timeout.unwrap())
+            .collect::<Vec<_>>();
+        let defaults = Timeouts::default();
+        assert_eq!(
+            vec![
+                defaults.clean,
+                defaults.build,
+                Duration::from_secs(5),
+                defaults.analyze,
+                defaults.analyze,
+                defaults.analyze,
+                defaults.analyze,
+            ],
+            timeouts
+        );
+    }
+
+    #[tokio::test]
+    async fn cell_timeout_limits_every_step() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+
+        let timeouts = Timeouts {
+            cell: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let (_temp_dir, log) = run_log();
+        TestRun::new(&daemon, &log)
+            .timeouts(timeouts)
+            .run(&hello_world())
+            .await
+            .unwrap();
+
+        assert!(daemon
+            .calls()
+            .execs
+            .iter()
+            .all(|(_, request)| request.timeout.unwrap() <= Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn container_is_cleaned_up_after_exec_error() {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .on_exec_error(&["cargo", "run"], "connection reset");
+
+        assert!(run(&hello_world(), &daemon).await.is_err());
+
+        let calls = daemon.calls();
+        assert_eq!(vec!["container-1".to_string()], calls.stopped);
+        assert_eq!(vec!["container-1".to_string()], calls.deleted);
+    }
+
+    #[tokio::test]
+    async fn missing_image_launches_nothing() {
+        let daemon = FakeDaemon::new().with_image("alpine-official-rust");
+
+        assert!(run(&hello_world(), &daemon).await.is_err());
+
+        let calls = daemon.calls();
+        assert!(calls.launched.is_empty());
+        assert!(calls.execs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn launch_failure_is_an_error() {
+        let daemon = FakeDaemon::new()
+            .with_image("debian-rust")
+            .fail_launch("no space left on device");
+
+        let error = run(&hello_world(), &daemon).await.unwrap_err();
+
+        assert!(format!("{:?}", error).contains("no space left on device"));
+        assert!(daemon.calls().execs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn container_gets_env_and_volumes() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let test = hello_world();
+
+        run(&test, &daemon).await.unwrap();
+
+        let calls = daemon.calls();
+        let (image_id, spec) = &calls.launched[0];
+        assert_eq!("sha256:debian-rust", image_id);
+        assert_eq!(test.env_vars(debian_rust()), spec.env);
+        assert_eq!("/build", spec.working_dir);
+        assert_eq!(
+            vec![
+                "/cache/registry:/usr/local/cargo/registry".to_string(),
+                "/cache/registry-index:/usr/local/cargo/registry/index".to_string(),
+                "/cache/registry-git:/usr/local/cargo/registry/git".to_string(),
+                "/cache/git-db:/usr/local/cargo/git/db".to_string(),
+                format!("{}:/build", test.path.display()),
+            ],
+            spec.volumes
+        );
+    }
+
+    #[tokio::test]
+    async fn container_gets_the_access_the_crate_asks_for() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let mut test = hello_world();
+        test.package_metadata.container = ContainerAccess {
+            mounts: vec!["/run/udev".to_string()],
+            capabilities: vec!["SYS_RAWIO".to_string()],
+            privileged: true,
+        };
+
+        run(&test, &daemon).await.unwrap();
+
+        let calls = daemon.calls();
+        let (_, spec) = &calls.launched[0];
+        assert_eq!(
+            Some(&"/run/udev:/run/udev:ro".to_string()),
+            spec.volumes.last()
+        );
+        assert_eq!(vec!["SYS_RAWIO".to_string()], spec.capabilities);
+        assert!(spec.privileged);
+    }
+
+    #[tokio::test]
+    async fn variant_container_access_replaces_the_crates() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let mut test = hello_world();
+        test.package_metadata.container = ContainerAccess {
+            capabilities: vec!["SYS_RAWIO".to_string()],
+            ..Default::default()
+        };
+        test.package_metadata.variants.insert(
+            "host-devices".to_string(),
+            VariantMetadata {
+                container: Some(ContainerAccess {
+                    mounts: vec!["/sys".to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        for test in test.with_variants() {
+            run(&test, &daemon).await.unwrap();
+        }
+
+        let calls = daemon.calls();
+        assert_eq!(2, calls.launched.len());
+        assert_eq!(
+            vec!["SYS_RAWIO".to_string()],
+            calls.launched[0].1.capabilities
+        );
+        assert!(!calls.launched[0]
+            .1
+            .volumes
+            .contains(&"/sys:/sys:ro".to_string()));
+        assert!(calls.launched[1].1.capabilities.is_empty());
+        assert_eq!(
+            Some(&"/sys:/sys:ro".to_string()),
+            calls.launched[1].1.volumes.last()
+        );
+    }
+
+    fn vendored_variant() -> VariantMetadata {
+        VariantMetadata {
+            features: vec!["openssl/vendored".to_string(), "extra".to_string()],
+            no_default_features: true,
+            env: EnvVarsMetadata::Common(vec![
+                "OPENSSL_STATIC=0".to_string(),
+                "CC=musl-gcc".to_string(),
+            ]),
+            cargo_args: vec!["--locked".to_string()],
+            rustflags: vec!["-C".to_string(), "link-arg=-s".to_string()],
+            rustflags_mode: None,
+            linker: None,
+            run: None,
+            container: None,
+        }
+    }
+
+    #[test]
+    fn variants_are_separate_tests() {
+        let mut test = hello_world();
+        test.package_metadata
+            .variants
+            .insert("vendored".to_string(), vendored_variant());
+        test.package_metadata
+            .variants
+            .insert("alpha".to_string(), VariantMetadata::default());
+
+        let names = test
+            .with_variants()
+            .iter()
+            .map(|test| test.name().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec!["hello-world", "hello-world/alpha", "hello-world/vendored"],
+            names
+        );
+    }
+
+    #[tokio::test]
+    async fn variant_settings_are_passed_to_cargo() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let mut test = hello_world();
+        test.package_metadata.env = EnvVarsMetadata::Common(vec!["OPENSSL_STATIC=1".to_string()]);
+        test.package_metadata
+            .variants
+            .insert("vendored".to_string(), vendored_variant());
+        let variant = test.with_variants().pop().unwrap();
+
+        run(&variant, &daemon).await.unwrap();
+
+        let calls = daemon.calls();
+        let commands = calls
+            .execs
+            .iter()
+            .map(|(_, request)| request.display_cmd())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "cargo clean".to_string(),
+                "cargo build --target x86_64-unknown-linux-musl --features openssl/vendored,extra --no-default-features --locked".to_string(),
+                "cargo run --target x86_64-unknown-linux-musl --features openssl/vendored,extra --no-default-features --locked".to_string(),
+                "find target -type f -name hello-world".to_string(),
+                format!("stat -c '%s' {}", BINARY_PATH),
+                format!("readelf --file-header --program-headers --dynamic --wide {}", BINARY_PATH),
+                "find target -path '*/x86_64-unknown-linux-musl/*/build/*/output' -type f -print -exec cat '{}' ';'".to_string(),
+            ],
+            commands
+        );
+        assert_eq!(
+            vec![
+                "OPENSSL_STATIC=0".to_string(),
+                "CC=musl-gcc".to_string(),
+                "RUSTFLAGS=-C link-arg=-s -C relocation-model=static -C target-feature=+crt-static"
+                    .to_string(),
+            ],
+            calls.launched[0].1.env
+        );
+    }
+
+    #[test]
+    fn host_cells_get_their_own_target_dirs() {
+        let test = hello_world();
+        let musl = Platform::find("host", TargetKind::Musl).unwrap();
+        let release = Platform {
+            profile: BuildProfile::Release,
+            ..musl
+        };
+        let gnu_static = Platform::find("host", TargetKind::GnuStatic).unwrap();
+
+        let cache_dir = Path::new("/cache");
+        assert_eq!(
+            Path::new("/cache/host-target/hello-world/musl/debug"),
+            test.host_target_dir(cache_dir, musl)
+        );
+        assert_ne!(
+            test.host_target_dir(cache_dir, musl),
+            test.host_target_dir(cache_dir, release)
+        );
+        assert_ne!(
+            test.host_target_dir(cache_dir, musl),
+            test.host_target_dir(cache_dir, gnu_static)
+        );
+    }
+
+    #[test]
+    fn rustflags_are_always_static() {
+        let mut test = hello_world();
+
+        assert_eq!(
+            vec!["RUSTFLAGS=-C relocation-model=static -C target-feature=+crt-static".to_string()],
+            test.env_vars(debian_rust())
+        );
+
+        test.package_metadata.env = EnvVarsMetadata::Common(vec![
+            "OPENSSL_STATIC=1".to_string(),
+            "RUSTFLAGS=-C link-arg=-s".to_string(),
+        ]);
+
+        assert_eq!(
+            vec![
+                "OPENSSL_STATIC=1".to_string(),
+                "RUSTFLAGS=-C link-arg=-s -C relocation-model=static -C target-feature=+crt-static"
+                    .to_string(),
+            ],
+            test.env_vars(debian_rust())
+        );
+    }
+
+    #[test]
+    fn rustflags_can_be_scoped_to_the_target() {
+        let mut test = hello_world();
+        test.package_metadata.rustflags_mode = RustflagsMode::TargetEnv;
+        test.package_metadata.env = EnvVarsMetadata::Common(vec!["OPENSSL_STATIC=1".to_string()]);
+
+        assert_eq!(
+            vec![
+                "OPENSSL_STATIC=1".to_string(),
+                "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_RUSTFLAGS=-C relocation-model=static -C target-feature=+crt-static"
+                    .to_string(),
+            ],
+            test.env_vars(debian_rust())
+        );
+    }
+
+    #[test]
+    fn variant_linker_is_scoped_to_the_target() {
+        let mut test = hello_world();
+        test.package_metadata.linker = Linker::MuslGcc;
+        test.package_metadata.variants.insert(
+            "lld".to_string(),
+            VariantMetadata {
+                linker: Some(Linker::ClangLld),
+                ..Default::default()
+            },
+        );
+        let tests = test.with_variants();
+
+        assert_eq!(
+            vec![
+                "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_LINKER=musl-gcc".to_string(),
+                "RUSTFLAGS=-C relocation-model=static -C target-feature=+crt-static".to_string(),
+            ],
+            tests[0].env_vars(debian_rust())
+        );
+        assert_eq!(
+            vec![
+                "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_LINKER=clang".to_string(),
+                "RUSTFLAGS=-C link-arg=-fuse-ld=lld -C relocation-model=static -C target-feature=+crt-static".to_string(),
+            ],
+            tests[1].env_vars(debian_rust())
+        );
+    }
+
+    #[tokio::test]
+    async fn rustflags_can_go_in_a_cargo_config_file() {
+        let daemon = daemon_with_readelf_output(STATIC_X86_64);
+        let mut test = hello_world();
+        test.package_metadata.variants.insert(
+            "config".to_string(),
+            VariantMetadata {
+                rustflags: vec!["-C link-arg=-s".to_string()],
+                rustflags_mode: Some(RustflagsMode::CargoConfig),
+                ..Default::default()
+            },
+        );
+        let variant = test.with_variants().pop().unwrap();
+
+        let (_temp_dir, log) = run_log();
+        TestRun::new(&daemon, &log).run(&variant).await.unwrap();
+
+        let calls = daemon.calls();
+        let spec = &calls.launched[0].1;
+        assert!(spec.env.is_empty());
+
+        let config_path = log
+            .cell_dir(&CellId::new("hello-world/config", "debian-rust/musl/debug"))
+            .join("cargo-config.toml");
+        assert_eq!(
+            &format!("{}:/.cargo/config.toml:ro", config_path.display()),
+            spec.volumes.last().unwrap()
+        );
+        assert_eq!(
+            "[target.x86_64-unknown-linux-musl]\nrustflags = [\"-C\", \"link-arg=-s\", \"-C\", \"relocation-model=static\", \"-C\", \"target-feature=+crt-static\"]\n",
+            std::fs::read_to_string(config_path).unwrap()
+        );
+    }
+
+    #[test]
+    fn toml_strings_are_escaped() {
+        assert_eq!(r#""-C link-arg=-s""#, toml_string("-C link-arg=-s"));
+        assert_eq!(
+            r#""say \"hi\"\\ \n \u001B \u007F é""#,
+            toml_string("say \"hi\"\\ \n \u{1b} \u{7f} é")
+        );
+    }
+
+    #[test]
+    fn each_kind_of_target_has_its_own_rustflags() {
+        let test = hello_world();
+        let rustflags = |kind| {
+            test.rustflags(Platform::find("debian-static-libs", kind).unwrap())
+                .join(" ")
+        };
+
+        assert_eq!(
+            "-W linker-messages -C target-feature=+crt-static",
+            rustflags(TargetKind::GnuStatic)
+        );
+        assert_eq!(
+            "-C relocation-model=static -C target-feature=+crt-static",
+            rustflags(TargetKind::Musl)
+        );
+        assert_ne!(
+            rustflags(TargetKind::Musl),
+            rustflags(TargetKind::MuslStaticPie)
+        );
+        assert_eq!(
+            "-C relocation-model=pie -C target-feature=+crt-static",
+            rustflags(TargetKind::MuslStaticPie)
+        );
+        assert!(Platform::find("alpine-official-rust", TargetKind::GnuStatic).is_none());
+    }
+
+    #[test]
+    fn env_vars_can_be_per_environment() {
+        let env: EnvVarsMetadata = serde_json::from_value(serde_json::json!({
+            "common": ["OPENSSL_STATIC=1", "PKG_CONFIG_ALLOW_CROSS=1"],
+            "alpine-official-rust": ["OPENSSL_DIR=/usr", "OPENSSL_STATIC=0"],
+        }))
+        .unwrap();
+        let mut test = hello_world();
+        test.package_metadata.env = env;
+
+        assert_eq!(
+            vec![
+                "PKG_CONFIG_ALLOW_CROSS=1".to_string(),
+                "OPENSSL_DIR=/usr".to_string(),
+                "OPENSSL_STATIC=0".to_string(),
+                "RUSTFLAGS=-C relocation-model=static -C target-feature=+crt-static".to_string(),
+            ],
+            test.env_vars(Platform::find("alpine-official-rust", TargetKind::Musl).unwrap())
+        );
+        assert_eq!(
+            vec![
+                "OPENSSL_STATIC=1".to_string(),
+                "PKG_CONFIG_ALLOW_CROSS=1".to_string(),
+                "RUSTFLAGS=-C relocation-model=static -C target-feature=+crt-static".to_string(),
+            ],
+            test.env_vars(debian_rust())
+        );
+    }
+
+    #[test]
+    fn env_vars_can_be_a_plain_list() {
+        let env: EnvVarsMetadata =
+            serde_json::from_value(serde_json::json!(["OPENSSL_STATIC=1"])).unwrap();
+
+        assert_eq!(
+            EnvVarsMetadata::Common(vec!["OPENSSL_STATIC=1".to_string()]),
+            env
+        );
+    }
+
+    /// Load a test crate with `metadata` as the contents of its `[package.metadata.test-crate]` table
+    fn load_with_metadata(metadata: &str) -> (TempDir, Result<TestCrate>) {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("with-metadata");
+        std::fs::create_dir_all(path.join("src")).unwrap();
+        std::fs::write(
+            path.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"with-metadata\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+                 [package.metadata.test-crate]\n{}\n",
+                metadata
+            ),
+        )
+        .unwrap();
+        std::fs::write(path.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let test = TestCrate::load(path);
+        (temp_dir, test)
+    }
+
+    #[test]
+    fn invalid_metadata_errors_point_at_the_line() {
+        let (_temp_dir, test) = load_with_metadata("envs = [\"OPENSSL_STATIC=1\"]");
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:7: "), "{}", error);
+        assert!(error.contains("unknown field `envs`"), "{}", error);
+
+        let (_temp_dir, test) = load_with_metadata(
+            "env = []\n\n[package.metadata.test-crate.variants.fast]\nenv = [\"OPENSSL_STATIC\"]",
+        );
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:10: "), "{}", error);
+        assert!(error.contains("NAME=VALUE"), "{}", error);
+
+        let (_temp_dir, test) =
+            load_with_metadata("[package.metadata.test-crate.env]\ndebian-rusty = [\"A=1\"]");
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:8: "), "{}", error);
+
+        let (_temp_dir, test) = load_with_metadata(
+            "env = []\n\n[package.metadata.test-crate.variants.fast.env]\ncommon = [\"A=1\"]\n\
+             debian-rust = [\"B=2\", 3]",
+        );
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:11: "), "{}", error);
+        assert!(
+            error.contains("invalid env vars for `debian-rust`: invalid type: integer `3`"),
+            "{}",
+            error
+        );
+
+        let (_temp_dir, test) = load_with_metadata("env = \"OPENSSL_STATIC=1\"");
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:7: "), "{}", error);
+        assert!(error.contains(ENV_EXPECTED), "{}", error);
+
+        let (_temp_dir, test) = load_with_metadata("schema-version = 99\nenv = []");
+        assert!(test.is_err());
+
+        let (_temp_dir, test) = load_with_metadata("schema-version = 0\nenv = []");
+        let error = test.unwrap_err().to_string();
+        assert!(error.contains("Cargo.toml:7: "), "{}", error);
+        assert!(error.contains("there is no schema version 0"), "{}", error);
+
+        let (_temp_dir, test) =
+            load_with_metadata("schema-version = 1\nenv = [\"OPENSSL_STATIC=1\"]");
+        assert!(test.is_ok());
+    }
+}
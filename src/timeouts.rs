@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// The steps that make up a test, each of which has its own timeout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Step {
+    /// `cargo clean`
+    Clean,
+
+    /// `cargo build`
+    Build,
+
+    /// `cargo run`
+    Run,
+
+    /// Finding the binary and checking it for dynamic dependencies
+    Analyze,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Clean => "clean",
+            Step::Build => "build",
+            Step::Run => "run",
+            Step::Analyze => "analyze",
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How long each step of a test, and each test as a whole, is allowed to take.
+///
+/// These are the global timeouts, set on the command line.  Each test crate can override any of them in its
+/// `[package.metadata.test-crate.timeouts]` table; see [`TimeoutOverrides`]
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub(crate) struct Timeouts {
+    /// Timeout in seconds for `cargo clean`
+    #[structopt(long = "clean-timeout", default_value = "300", parse(try_from_str = parse_secs))]
+    pub clean: Duration,
+
+    /// Timeout in seconds for `cargo build`
+    #[structopt(long = "build-timeout", default_value = "3600", parse(try_from_str = parse_secs))]
+    pub build: Duration,
+
+    /// Timeout in seconds for `cargo run`
+    #[structopt(long = "run-timeout", default_value = "300", parse(try_from_str = parse_secs))]
+    pub run: Duration,
+
+    /// Timeout in seconds for each command that analyzes the binary
+    #[structopt(long = "analyze-timeout", default_value = "300", parse(try_from_str = parse_secs))]
+    pub analyze: Duration,
+
+    /// Timeout in seconds for all steps of a single test in a single environment.
+    ///
+    /// Default is no limit other than the per-step timeouts
+    #[structopt(long = "cell-timeout", parse(try_from_str = parse_secs))]
+    pub cell: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn for_step(&self, step: Step) -> Duration {
+        match step {
+            Step::Clean => self.clean,
+            Step::Build => self.build,
+            Step::Run => self.run,
+            Step::Analyze => self.analyze,
+        }
+    }
+
+    /// Apply a test crate's overrides on top of these timeouts
+    pub fn with_overrides(&self, overrides: &TimeoutOverrides) -> Timeouts {
+        let secs = |value: Option<u64>, default: Duration| {
+            value.map(Duration::from_secs).unwrap_or(default)
+        };
+
+        Timeouts {
+            clean: secs(overrides.clean, self.clean),
+            build: secs(overrides.build, self.build),
+            run: secs(overrides.run, self.run),
+            analyze: secs(overrides.analyze, self.analyze),
+            cell: overrides.cell.map(Duration::from_secs).or(self.cell),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::from_iter(std::iter::once("timeouts"))
+    }
+}
+
+/// Timeouts in seconds which a test crate overrides in `[package.metadata.test-crate.timeouts]`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TimeoutOverrides {
+    pub clean: Option<u64>,
+    pub build: Option<u64>,
+    pub run: Option<u64>,
+    pub analyze: Option<u64>,
+    pub cell: Option<u64>,
+}
+
+fn parse_secs(secs: &str) -> Result<Duration, std::num::ParseIntError> {
+    secs.parse().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overrides_replace_only_what_they_set() {
+        let global = Timeouts::default();
+        let overrides = TimeoutOverrides {
+            run: Some(5),
+            cell: Some(600),
+            ..Default::default()
+        };
+
+        let timeouts = global.with_overrides(&overrides);
+
+        assert_eq!(global.clean, timeouts.clean);
+        assert_eq!(global.build, timeouts.build);
+        assert_eq!(Duration::from_secs(5), timeouts.for_step(Step::Run));
+        assert_eq!(Some(Duration::from_secs(600)), timeouts.cell);
+    }
+}
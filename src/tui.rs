@@ -0,0 +1,658 @@
+use crate::progress::{Cancel, CellId, CellState, Progress, Verdict};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal::{self, ClearType},
+};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How much of each cell's output is kept for display.  Only the tail of a log is ever shown, so there's no point
+/// keeping all of a very chatty build in memory
+const MAX_LOG_BYTES: usize = 256 * 1024;
+
+/// A live terminal UI showing the test matrix as a grid of tests by environments.
+///
+/// The UI runs on its own thread, redrawing the grid periodically and handling keys to move between cells and to
+/// show the log of the selected cell.  When all tests are finished the final grid is left on the terminal
+pub(crate) struct TuiProgress {
+    state: Arc<Mutex<Matrix>>,
+    stop: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl TuiProgress {
+    /// Take over the terminal and start showing a grid of these tests and environments.  Quitting the UI cancels the
+    /// run with `cancel`
+    pub fn start(tests: Vec<String>, envs: Vec<String>, cancel: Cancel) -> io::Result<Self> {
+        let state = Arc::new(Mutex::new(Matrix::new(tests, envs)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        let thread = {
+            let state = state.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || ui_thread(state, stop, cancel))
+        };
+
+        Ok(Self {
+            state,
+            stop,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    fn update<F: FnOnce(&mut Matrix)>(&self, f: F) {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+
+impl Progress for TuiProgress {
+    fn state(&self, cell: &CellId, state: CellState) {
+        self.update(|matrix| {
+            if let Some(view) = matrix.cell_mut(cell) {
+                view.state = state;
+            }
+        })
+    }
+
+    fn stdout(&self, cell: &CellId, chunk: &[u8]) {
+        self.update(|matrix| {
+            if let Some(view) = matrix.cell_mut(cell) {
+                view.append(chunk);
+            }
+        })
+    }
+
+    fn stderr(&self, cell: &CellId, chunk: &[u8]) {
+        self.stdout(cell, chunk)
+    }
+
+    fn finish(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+
+        // Leave the final grid on the normal screen, without the selection highlight
+        let matrix = self.state.lock().unwrap();
+        for line in matrix.grid() {
+            println!(
+                "{}",
+                line.iter().map(|s| s.text.as_str()).collect::<String>()
+            );
+        }
+    }
+}
+
+impl Drop for TuiProgress {
+    fn drop(&mut self) {
+        // Normally `finish` has already stopped the UI; this makes sure the terminal is usable again if it wasn't
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What the UI knows about each cell
+#[derive(Clone, Debug)]
+struct CellView {
+    state: CellState,
+    log: Vec<u8>,
+}
+
+impl CellView {
+    fn append(&mut self, chunk: &[u8]) {
+        self.log.extend_from_slice(chunk);
+        if self.log.len() > MAX_LOG_BYTES {
+            let excess = self.log.len() - MAX_LOG_BYTES;
+            self.log.drain(..excess);
+        }
+    }
+
+    /// The last `count` lines of the log, as they would appear on a terminal
+    fn tail(&self, count: usize) -> Vec<String> {
+        let log = String::from_utf8_lossy(&self.log);
+        let lines = log
+            .split('\n')
+            // Progress bars redraw the line with `\r`; only the last version is visible
+            .map(|line| line.rsplit('\r').next().unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        let lines = match lines.last() {
+            Some(last) if last.is_empty() => &lines[..lines.len() - 1],
+            _ => &lines[..],
+        };
+
+        lines[lines.len().saturating_sub(count)..].to_vec()
+    }
+}
+
+/// A piece of a line of the UI
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    text: String,
+    color: Option<Color>,
+    selected: bool,
+}
+
+impl Segment {
+    fn plain(text: String) -> Self {
+        Self {
+            text,
+            color: None,
+            selected: false,
+        }
+    }
+}
+
+/// The state of the whole test matrix, as shown by the UI
+struct Matrix {
+    tests: Vec<String>,
+    envs: Vec<String>,
+
+    /// Indexed by test then environment
+    cells: Vec<Vec<CellView>>,
+
+    /// Test and environment index of the selected cell
+    selected: (usize, usize),
+
+    /// Show the log of the selected cell under the grid
+    show_log: bool,
+
+    /// The user quit, and the run is stopping
+    aborting: bool,
+}
+
+impl Matrix {
+    fn new(tests: Vec<String>, envs: Vec<String>) -> Self {
+        let cells = tests
+            .iter()
+            .map(|_| {
+                envs.iter()
+                    .map(|_| CellView {
+                        state: CellState::Queued,
+                        log: Vec::new(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            tests,
+            envs,
+            cells,
+            selected: (0, 0),
+            show_log: false,
+            aborting: false,
+        }
+    }
+
+    fn cell_mut(&mut self, cell: &CellId) -> Option<&mut CellView> {
+        let test = self.tests.iter().position(|t| t == &cell.test)?;
+        let env = self.envs.iter().position(|e| e == &cell.env)?;
+
+        Some(&mut self.cells[test][env])
+    }
+
+    /// The selected cell, unless the matrix is empty
+    fn selected_cell(&self) -> Option<&CellView> {
+        self.cells.get(self.selected.0)?.get(self.selected.1)
+    }
+
+    /// The test and environment names of the selected cell, unless the matrix is empty
+    fn selected_names(&self) -> Option<(&str, &str)> {
+        let (test, env) = self.selected;
+        Some((self.tests.get(test)?, self.envs.get(env)?))
+    }
+
+    /// Handle a key press.  Returns `false` if the user wants to abort the run
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let (test, env) = &mut self.selected;
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char('q') => return false,
+            KeyCode::Up | KeyCode::Char('k') => *test = test.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                *test = (*test + 1).min(self.tests.len().saturating_sub(1))
+            }
+            KeyCode::Left | KeyCode::Char('h') => *env = env.saturating_sub(1),
+            KeyCode::Right | KeyCode::Char('l') => {
+                *env = (*env + 1).min(self.envs.len().saturating_sub(1))
+            }
+            KeyCode::Enter | KeyCode::Char(' ') => self.show_log = !self.show_log,
+            _ => {}
+        }
+
+        true
+    }
+
+    /// The width of the column of test names
+    fn test_width(&self) -> usize {
+        self.tests
+            .iter()
+            .map(|t| t.len())
+            .chain(std::iter::once(4))
+            .max()
+            .unwrap_or_default()
+            + 2
+    }
+
+    /// The width of each environment's column
+    fn env_widths(&self) -> Vec<usize> {
+        self.envs.iter().map(|e| e.len().max(10) + 2).collect()
+    }
+
+    /// The environment columns that fit in `width` next to the test names.  The columns scroll so the selected cell
+    /// is always on the screen
+    fn visible_envs(&self, width: usize) -> Range<usize> {
+        let room = width.saturating_sub(self.test_width());
+        let widths = self.env_widths();
+        let selected = self.selected.1;
+
+        let mut start = 0;
+        while start < selected && widths[start..=selected].iter().sum::<usize>() > room {
+            start += 1;
+        }
+        let mut end = selected + 1;
+        while end < widths.len() && widths[start..=end].iter().sum::<usize>() <= room {
+            end += 1;
+        }
+
+        start..end.min(widths.len())
+    }
+
+    /// The test rows that fit in `rows` lines.  Like the columns, the rows scroll so the selected cell is always on
+    /// the screen
+    fn visible_tests(&self, rows: usize) -> Range<usize> {
+        let rows = rows.max(1);
+        let start = (self.selected.0 + 1).saturating_sub(rows);
+
+        start..(start + rows).min(self.tests.len())
+    }
+
+    /// The whole grid of tests and environments, one line per test plus a header
+    fn grid(&self) -> Vec<Vec<Segment>> {
+        self.grid_of(0..self.tests.len(), 0..self.envs.len())
+    }
+
+    /// The grid with just some of the test rows and environment columns
+    fn grid_of(&self, tests: Range<usize>, envs: Range<usize>) -> Vec<Vec<Segment>> {
+        let test_width = self.test_width();
+        let env_widths = self.env_widths();
+
+        let mut header = vec![Segment::plain(format!(
+            "{:<width$}",
+            "test",
+            width = test_width
+        ))];
+        for env_index in envs.clone() {
+            header.push(Segment::plain(format!(
+                "{:<width$}",
+                self.envs[env_index],
+                width = env_widths[env_index]
+            )));
+        }
+
+        let mut lines = vec![header];
+        for test_index in tests {
+            let test = &self.tests[test_index];
+            let mut line = vec![Segment::plain(format!(
+                "{:<width$}",
+                test,
+                width = test_width
+            ))];
+            for env_index in envs.clone() {
+                let state = self.cells[test_index][env_index].state;
+                line.push(Segment {
+                    text: format!("{:<width$}", state.label(), width = env_widths[env_index]),
+                    color: state_color(state),
+                    selected: self.selected == (test_index, env_index),
+                });
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    /// Everything on the screen, given its size
+    fn screen(&self, width: usize, height: usize) -> Vec<Vec<Segment>> {
+        let envs = self.visible_envs(width);
+        let (first, last) = (envs.start, envs.end);
+
+        // Without the log the rows get everything but the header, the status and the help line.  With it they get a
+        // third of the screen, and the log the rest
+        let rows = if self.show_log {
+            height / 3
+        } else {
+            height.saturating_sub(5)
+        };
+        let tests = self.visible_tests(rows);
+        let (first_test, last_test) = (tests.start, tests.end);
+        let mut lines = self.grid_of(tests, envs);
+
+        lines.push(Vec::new());
+
+        // The column headers can be cut off, so the selected cell is spelled out in full, along with whether there
+        // are more rows or columns to scroll to.  The log's own header does the same
+        match self.selected_names() {
+            None => {
+                lines.push(vec![Segment::plain("nothing to run".to_string())]);
+                lines.push(Vec::new());
+            }
+            Some((test, env)) if !self.show_log => {
+                let mut status = String::new();
+                if first_test > 0 || last_test < self.tests.len() {
+                    status.push_str(&format!(
+                        "[rows {}-{} of {}] ",
+                        first_test + 1,
+                        last_test,
+                        self.tests.len()
+                    ));
+                }
+                if first > 0 || last < self.envs.len() {
+                    status.push_str(&format!(
+                        "[columns {}-{} of {}] ",
+                        first + 1,
+                        last,
+                        self.envs.len()
+                    ));
+                }
+                status.push_str(&format!("{} in {}", test, env));
+                lines.push(vec![Segment::plain(status)]);
+                lines.push(Vec::new());
+            }
+            Some((test, env)) => {
+                lines.push(vec![Segment::plain(format!(
+                    "--- log of {} in {} ---",
+                    test, env
+                ))]);
+
+                // Leave room for the help line at the bottom
+                let room = height.saturating_sub(lines.len() + 2);
+                for line in self
+                    .selected_cell()
+                    .map(|cell| cell.tail(room))
+                    .unwrap_or_default()
+                {
+                    lines.push(vec![Segment::plain(line)]);
+                }
+                lines.push(Vec::new());
+            }
+        }
+
+        let help = if self.aborting {
+            "aborting: cleaning up the running cell..."
+        } else {
+            "arrows: select cell   enter: show/hide log   q: abort"
+        };
+        lines.push(vec![Segment::plain(help.to_string())]);
+
+        // Nothing may wrap, or the layout falls apart
+        for line in &mut lines {
+            let mut remaining = width;
+            for segment in line.iter_mut() {
+                if segment.text.chars().count() > remaining {
+                    segment.text = segment.text.chars().take(remaining).collect();
+                }
+                remaining -= segment.text.chars().count();
+            }
+        }
+
+        lines
+    }
+}
+
+fn state_color(state: CellState) -> Option<Color> {
+    match state {
+        CellState::Queued => Some(Color::DarkGrey),
+        CellState::Starting | CellState::InStep(_) => Some(Color::Cyan),
+        CellState::Done(Verdict::Static) => Some(Color::Green),
+        CellState::Done(Verdict::StaticWithCaveats) => Some(Color::DarkGreen),
+        CellState::Done(Verdict::NonStatic) => Some(Color::Yellow),
+        CellState::Done(Verdict::Misbehaves) => Some(Color::Magenta),
+        CellState::Done(_) => Some(Color::Red),
+    }
+}
+
+fn ui_thread(state: Arc<Mutex<Matrix>>, stop: Arc<AtomicBool>, cancel: Cancel) {
+    let mut stdout = io::stdout();
+
+    while !stop.load(Ordering::SeqCst) {
+        if let Ok(true) = event::poll(Duration::from_millis(100)) {
+            if let Ok(Event::Key(key)) = event::read() {
+                let mut matrix = state.lock().unwrap();
+                if key.kind != KeyEventKind::Release && !matrix.handle_key(key) {
+                    // Keep drawing until the run has cleaned up and finishes
+                    matrix.aborting = true;
+                    cancel.cancel();
+                }
+            }
+        }
+
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        let screen = state
+            .lock()
+            .unwrap()
+            .screen(width as usize, height as usize);
+        let _ = draw(&mut stdout, &screen);
+    }
+
+    restore_terminal();
+}
+
+fn draw(stdout: &mut impl Write, screen: &[Vec<Segment>]) -> io::Result<()> {
+    queue!(
+        stdout,
+        cursor::MoveTo(0, 0),
+        terminal::Clear(ClearType::All)
+    )?;
+
+    for (row, line) in screen.iter().enumerate() {
+        queue!(stdout, cursor::MoveTo(0, row as u16))?;
+        for segment in line {
+            if let Some(color) = segment.color {
+                queue!(stdout, SetForegroundColor(color))?;
+            }
+            if segment.selected {
+                queue!(stdout, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(
+                stdout,
+                Print(&segment.text),
+                SetAttribute(Attribute::Reset),
+                ResetColor
+            )?;
+        }
+    }
+
+    stdout.flush()
+}
+
+fn restore_terminal() {
+    let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timeouts::Step;
+
+    fn text(lines: &[Vec<Segment>]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.iter().map(|s| s.text.as_str()).collect())
+            .collect()
+    }
+
+    fn matrix() -> Matrix {
+        Matrix::new(
+            vec!["hello-world".to_string(), "with-libudev".to_string()],
+            vec!["debian-rust".to_string(), "host".to_string()],
+        )
+    }
+
+    #[test]
+    fn grid_shows_cell_states() {
+        let mut matrix = matrix();
+        matrix
+            .cell_mut(&CellId::new("hello-world", "debian-rust"))
+            .unwrap()
+            .state = CellState::Done(Verdict::Static);
+        matrix
+            .cell_mut(&CellId::new("with-libudev", "host"))
+            .unwrap()
+            .state = CellState::InStep(Step::Build);
+
+        assert_eq!(
+            vec![
+                "test          debian-rust  host        ",
+                "hello-world   static       queued      ",
+                "with-libudev  queued       building    ",
+            ],
+            text(&matrix.grid())
+        );
+    }
+
+    #[test]
+    fn log_shows_tail_of_selected_cell() {
+        let mut matrix = matrix();
+        matrix.handle_key(KeyEvent::from(KeyCode::Down));
+        matrix.handle_key(KeyEvent::from(KeyCode::Enter));
+        matrix
+            .cell_mut(&CellId::new("with-libudev", "debian-rust"))
+            .unwrap()
+            .append(b"one\ntwo\nBuilding [=> ]\rBuilding [==>]\nthree\n");
+
+        let screen = text(&matrix.screen(80, 10));
+
+        assert_eq!(
+            vec![
+                "--- log of with-libudev in debian-rust ---",
+                "two",
+                "Building [==>]",
+                "three",
+                "",
+                "arrows: select cell   enter: show/hide log   q: abort",
+            ],
+            screen[4..]
+        );
+    }
+
+    #[test]
+    fn selection_stays_in_the_grid() {
+        let mut matrix = matrix();
+
+        matrix.handle_key(KeyEvent::from(KeyCode::Up));
+        matrix.handle_key(KeyEvent::from(KeyCode::Left));
+        assert_eq!((0, 0), matrix.selected);
+
+        for _ in 0..5 {
+            matrix.handle_key(KeyEvent::from(KeyCode::Down));
+            matrix.handle_key(KeyEvent::from(KeyCode::Right));
+        }
+        assert_eq!((1, 1), matrix.selected);
+
+        assert!(!matrix.handle_key(KeyEvent::from(KeyCode::Char('q'))));
+    }
+
+    #[test]
+    fn columns_scroll_to_the_selection() {
+        let mut matrix = Matrix::new(
+            vec!["hello-world".to_string()],
+            vec![
+                "debian-rust/gnu-static/debug".to_string(),
+                "debian-rust/musl/debug".to_string(),
+                "debian-rust/musl-static-pie/debug".to_string(),
+            ],
+        );
+
+        let screen = text(&matrix.screen(80, 10));
+        assert_eq!(
+            "test         debian-rust/gnu-static/debug  debian-rust/musl/debug  ",
+            screen[0]
+        );
+        assert_eq!(
+            "[columns 1-2 of 3] hello-world in debian-rust/gnu-static/debug",
+            screen[3]
+        );
+
+        matrix.handle_key(KeyEvent::from(KeyCode::Right));
+        matrix.handle_key(KeyEvent::from(KeyCode::Right));
+        let screen = text(&matrix.screen(80, 10));
+        assert_eq!(
+            "test         debian-rust/musl/debug  debian-rust/musl-static-pie/debug  ",
+            screen[0]
+        );
+        assert_eq!(
+            "[columns 2-3 of 3] hello-world in debian-rust/musl-static-pie/debug",
+            screen[3]
+        );
+    }
+
+    #[test]
+    fn rows_scroll_to_the_selection() {
+        let mut matrix = Matrix::new(
+            (1..=6).map(|i| format!("test-{}", i)).collect(),
+            vec!["host".to_string()],
+        );
+
+        let screen = text(&matrix.screen(80, 8));
+        assert_eq!(
+            vec![
+                "test    host        ",
+                "test-1  queued      ",
+                "test-2  queued      ",
+                "test-3  queued      ",
+                "",
+                "[rows 1-3 of 6] test-1 in host",
+            ],
+            screen[..6]
+        );
+
+        for _ in 0..4 {
+            matrix.handle_key(KeyEvent::from(KeyCode::Down));
+        }
+        let screen = text(&matrix.screen(80, 8));
+        assert_eq!(
+            vec![
+                "test    host        ",
+                "test-3  queued      ",
+                "test-4  queued      ",
+                "test-5  queued      ",
+                "",
+                "[rows 3-5 of 6] test-5 in host",
+            ],
+            screen[..6]
+        );
+    }
+
+    #[test]
+    fn empty_matrix_has_no_selection() {
+        let mut matrix = Matrix::new(Vec::new(), vec!["host".to_string()]);
+        matrix.handle_key(KeyEvent::from(KeyCode::Down));
+        matrix.handle_key(KeyEvent::from(KeyCode::Enter));
+
+        let screen = text(&matrix.screen(80, 10));
+        assert_eq!(
+            vec![
+                "test  host        ",
+                "",
+                "nothing to run",
+                "",
+                "arrows: select cell   enter: show/hide log   q: abort",
+            ],
+            screen
+        );
+    }
+}